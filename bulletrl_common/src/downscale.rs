@@ -0,0 +1,202 @@
+use crate::{Renderer, PLANE_COUNT};
+
+// Area-averaged resize of the rendered field into u8 planes, like OpenCV's INTER_AREA
+// Every output pixel is the average of the source pixels it covers, weighted by how much of each one it covers,
+// so small bullets fade out instead of flickering in and out like they would with point or bilinear sampling
+//
+// Planes are stored one after the other (CHW)
+// Colors are in the byte order of Renderer::buffer (blue, green, red) so that they line up with what trainers used
+// to get by resizing the raw buffer themselves, and a single channel is luminance instead
+// Planes come out in the order of Plane, same as Renderer::write_planes
+#[derive(Clone)]
+pub struct Downscaler {
+    width: usize,
+    height: usize,
+    channels: usize,
+    planes: bool,
+    // Size of the renderer the taps are for, they're worked out on the first downscale
+    source: (usize, usize),
+    x_taps: Vec<Vec<(usize, f32)>>,
+    y_taps: Vec<Vec<(usize, f32)>>,
+    // Horizontally resized rows, source height * width * source channels
+    scratch: Vec<f32>,
+}
+
+// Enough room for either source
+const MAX_CHANNELS: usize = if PLANE_COUNT > 3 { PLANE_COUNT } else { 3 };
+
+impl Downscaler {
+    // Resizes Renderer::buffer into 1 or 3 channels
+    pub fn new(width: usize, height: usize, channels: usize) -> Self {
+        Downscaler {
+            width,
+            height,
+            channels,
+            planes: false,
+            source: (0, 0),
+            x_taps: Vec::new(),
+            y_taps: Vec::new(),
+            scratch: Vec::new(),
+        }
+    }
+
+    // Resizes every plane separately
+    pub fn planes(width: usize, height: usize) -> Self {
+        Downscaler {
+            width,
+            height,
+            channels: PLANE_COUNT,
+            planes: true,
+            source: (0, 0),
+            x_taps: Vec::new(),
+            y_taps: Vec::new(),
+            scratch: Vec::new(),
+        }
+    }
+
+    pub fn output_len(&self) -> usize {
+        self.width * self.height * self.channels
+    }
+
+    pub fn downscale(&mut self, renderer: &Renderer, out: &mut Vec<u8>) {
+        let src_channels = if self.planes { PLANE_COUNT } else { 3 };
+        let source = (renderer.width(), renderer.height());
+        if self.source != source {
+            self.source = source;
+            self.x_taps = taps(source.0, self.width);
+            self.y_taps = taps(source.1, self.height);
+            self.scratch = vec![0.0; source.1 * self.width * src_channels];
+        }
+
+        // Rows first, then columns, since the weights are separable
+        for y in 0..source.1 {
+            let row = y * source.0;
+            for (x, taps) in self.x_taps.iter().enumerate() {
+                let mut sum = [0.0f32; MAX_CHANNELS];
+                for &(src, weight) in taps {
+                    if self.planes {
+                        let classes = renderer.classes[row + src];
+                        for (c, sum) in sum[..PLANE_COUNT].iter_mut().enumerate() {
+                            if classes & (1 << c) != 0 {
+                                *sum += 255.0 * weight;
+                            }
+                        }
+                    } else {
+                        let [b, g, r, _] = renderer.buffer[row + src].to_le_bytes();
+                        sum[0] += b as f32 * weight;
+                        sum[1] += g as f32 * weight;
+                        sum[2] += r as f32 * weight;
+                    }
+                }
+                self.scratch[(y * self.width + x) * src_channels..][..src_channels]
+                    .copy_from_slice(&sum[..src_channels]);
+            }
+        }
+
+        out.clear();
+        out.resize(self.output_len(), 0);
+        let plane_len = self.width * self.height;
+        for (y, taps) in self.y_taps.iter().enumerate() {
+            for x in 0..self.width {
+                let mut sum = [0.0f32; MAX_CHANNELS];
+                for &(src, weight) in taps {
+                    let pixel =
+                        &self.scratch[(src * self.width + x) * src_channels..][..src_channels];
+                    for (sum, value) in sum.iter_mut().zip(pixel) {
+                        *sum += value * weight;
+                    }
+                }
+
+                let i = y * self.width + x;
+                if self.channels == 1 {
+                    out[i] = to_u8(0.114 * sum[0] + 0.587 * sum[1] + 0.299 * sum[2]);
+                } else {
+                    for (c, value) in sum[..self.channels].iter().enumerate() {
+                        out[c * plane_len + i] = to_u8(*value);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// For every output pixel along an axis, the source pixels it covers and how much of the output pixel each one makes up
+fn taps(src_len: usize, dst_len: usize) -> Vec<Vec<(usize, f32)>> {
+    let scale = src_len as f64 / dst_len as f64;
+    (0..dst_len)
+        .map(|i| {
+            let start = i as f64 * scale;
+            let end = (i + 1) as f64 * scale;
+            let mut taps = Vec::new();
+            let mut src = start.floor() as usize;
+            while (src as f64) < end && src < src_len {
+                let covered = (end.min(src as f64 + 1.0) - start.max(src as f64)) / scale;
+                if covered > 1e-6 {
+                    taps.push((src, covered as f32));
+                }
+                src += 1;
+            }
+            taps
+        })
+        .collect()
+}
+
+fn to_u8(x: f32) -> u8 {
+    (x + 0.5).clamp(0.0, 255.0) as u8
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn renderer(width: usize, height: usize, pixels: &[u32]) -> Renderer {
+        let mut renderer = Renderer::new(width as f32, height as f32, width, height);
+        renderer.buffer.copy_from_slice(pixels);
+        renderer
+    }
+
+    fn downscale(downscaler: &mut Downscaler, renderer: &Renderer) -> Vec<u8> {
+        let mut out = Vec::new();
+        downscaler.downscale(renderer, &mut out);
+        assert_eq!(out.len(), downscaler.output_len());
+        out
+    }
+
+    #[test]
+    fn integer_factor() {
+        // Blue goes up by 16 per pixel, every output pixel is a 2x2 block
+        let pixels = (0..16).map(|i| i * 16).collect::<Vec<_>>();
+        let out = downscale(&mut Downscaler::new(2, 2, 3), &renderer(4, 4, &pixels));
+        assert_eq!(out, [40, 72, 168, 200, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn non_integer_ratio() {
+        // Each output pixel covers one and a half source pixels
+        let out = downscale(
+            &mut Downscaler::new(2, 1, 1),
+            &renderer(3, 1, &[0, 0x5a5a5a, 0xb4b4b4]),
+        );
+        assert_eq!(out, [30, 150]);
+
+        // Upscaling isn't what it's for, but still just copies pixels
+        let out = downscale(
+            &mut Downscaler::new(4, 2, 1),
+            &renderer(2, 1, &[0, 0x646464]),
+        );
+        assert_eq!(out, [0, 0, 100, 100, 0, 0, 100, 100]);
+    }
+
+    #[test]
+    fn channels() {
+        let renderer = renderer(3, 1, &[0x0000ff, 0x00ff00, 0xff0000]);
+        // Same weights as OpenCV's BGR2GRAY
+        let out = downscale(&mut Downscaler::new(3, 1, 1), &renderer);
+        assert_eq!(out, [29, 150, 76]);
+        // Blue, green and red planes, in the byte order of the buffer
+        let out = downscale(&mut Downscaler::new(3, 1, 3), &renderer);
+        assert_eq!(out, [255, 0, 0, 0, 255, 0, 0, 0, 255]);
+        let out = downscale(&mut Downscaler::new(1, 1, 3), &renderer);
+        assert_eq!(out, [85, 85, 85]);
+    }
+}
@@ -0,0 +1,66 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    io::{self, ErrorKind},
+};
+
+// Everything that can go wrong between an environment and the trainer
+#[derive(Debug)]
+pub enum ProtocolError {
+    // A sentinel wasn't where it should be, so one side read or wrote the wrong number of bytes somewhere
+    Desync { got: u32 },
+    VersionMismatch { env: u32, trainer: u32 },
+    // The other side went away, e.g. it crashed or exited after an eval run
+    Disconnected,
+    TimedOut,
+    // Lined up fine but made no sense, e.g. an unknown command or an env id that doesn't exist
+    Malformed(String),
+    // The trainer asked for something the environment can't do, e.g. an encoding it doesn't support
+    Rejected(String),
+    // Sent by the other side right before it gave up, see EnvClient::send_error
+    Remote(String),
+    Io(io::Error),
+}
+
+impl Display for ProtocolError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Desync { got } => {
+                write!(f, "desync: expected a sentinel, got {:#x}", got)
+            }
+            ProtocolError::VersionMismatch { env, trainer } => write!(
+                f,
+                "protocol version mismatch: environment speaks v{}, trainer speaks v{}",
+                env, trainer
+            ),
+            ProtocolError::Disconnected => write!(f, "the other side closed the connection"),
+            ProtocolError::TimedOut => write!(f, "timed out"),
+            ProtocolError::Malformed(x) => write!(f, "malformed message: {}", x),
+            ProtocolError::Rejected(x) => write!(f, "rejected: {}", x),
+            ProtocolError::Remote(x) => write!(f, "the other side gave up: {}", x),
+            ProtocolError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            ErrorKind::UnexpectedEof
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe => ProtocolError::Disconnected,
+            ErrorKind::TimedOut | ErrorKind::WouldBlock => ProtocolError::TimedOut,
+            ErrorKind::InvalidData => ProtocolError::Malformed(e.to_string()),
+            _ => ProtocolError::Io(e),
+        }
+    }
+}
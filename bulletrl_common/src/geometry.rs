@@ -0,0 +1,388 @@
+use std::{
+    io::{Read, Write},
+    ops::{Add, AddAssign, Div, Mul, MulAssign, Neg, Sub, SubAssign},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+// Field coordinates, so y points down and positive angles go clockwise
+// repr(C) since games map it straight out of their own memory
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Vector2 {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Vector2 {
+    pub fn new(x: f32, y: f32) -> Self {
+        Self { x, y }
+    }
+
+    pub fn dot(self, other: Vector2) -> f32 {
+        self.x * other.x + self.y * other.y
+    }
+
+    // z of the 3D cross product, positive if other is clockwise from self
+    pub fn cross(self, other: Vector2) -> f32 {
+        self.x * other.y - self.y * other.x
+    }
+
+    pub fn length_squared(self) -> f32 {
+        self.dot(self)
+    }
+
+    pub fn length(self) -> f32 {
+        self.length_squared().sqrt()
+    }
+
+    pub fn distance(self, other: Vector2) -> f32 {
+        (other - self).length()
+    }
+
+    // Zero stays zero instead of turning into NaNs
+    pub fn normalize(self) -> Vector2 {
+        let length = self.length();
+        if length > 0.0 {
+            self / length
+        } else {
+            self
+        }
+    }
+
+    // In radians
+    pub fn rotate(self, angle: f32) -> Vector2 {
+        let (sin, cos) = angle.sin_cos();
+        Vector2::new(self.x * cos - self.y * sin, self.x * sin + self.y * cos)
+    }
+
+    pub fn rotate_around(self, origin: Vector2, angle: f32) -> Vector2 {
+        (self - origin).rotate(angle) + origin
+    }
+
+    // Direction from self to other in radians, 0 is towards +x
+    pub fn angle_to(self, other: Vector2) -> f32 {
+        let delta = other - self;
+        delta.y.atan2(delta.x)
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> std::io::Result<()> {
+        w.write_f32::<LittleEndian>(self.x)?;
+        w.write_f32::<LittleEndian>(self.y)
+    }
+
+    pub fn read<R: Read>(r: &mut R) -> std::io::Result<Self> {
+        Ok(Vector2::new(
+            r.read_f32::<LittleEndian>()?,
+            r.read_f32::<LittleEndian>()?,
+        ))
+    }
+}
+
+impl Add for Vector2 {
+    type Output = Self;
+    fn add(self, rhs: Self) -> Self::Output {
+        Vector2::new(self.x + rhs.x, self.y + rhs.y)
+    }
+}
+
+impl AddAssign for Vector2 {
+    fn add_assign(&mut self, rhs: Vector2) {
+        self.x += rhs.x;
+        self.y += rhs.y;
+    }
+}
+
+impl Sub for Vector2 {
+    type Output = Self;
+    fn sub(self, rhs: Self) -> Self::Output {
+        Vector2::new(self.x - rhs.x, self.y - rhs.y)
+    }
+}
+
+impl SubAssign for Vector2 {
+    fn sub_assign(&mut self, rhs: Vector2) {
+        self.x -= rhs.x;
+        self.y -= rhs.y;
+    }
+}
+
+impl Mul<f32> for Vector2 {
+    type Output = Self;
+    fn mul(self, rhs: f32) -> Self::Output {
+        Vector2::new(self.x * rhs, self.y * rhs)
+    }
+}
+
+impl MulAssign<f32> for Vector2 {
+    fn mul_assign(&mut self, rhs: f32) {
+        self.x *= rhs;
+        self.y *= rhs;
+    }
+}
+
+impl Div<f32> for Vector2 {
+    type Output = Self;
+    fn div(self, rhs: f32) -> Self::Output {
+        Vector2::new(self.x / rhs, self.y / rhs)
+    }
+}
+
+impl Neg for Vector2 {
+    type Output = Self;
+    fn neg(self) -> Self::Output {
+        Vector2::new(-self.x, -self.y)
+    }
+}
+
+// Hitboxes, sizes are full widths and heights like everywhere else
+// Shapes that only touch don't intersect
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shape {
+    Aabb {
+        center: Vector2,
+        size: Vector2,
+    },
+    Circle {
+        center: Vector2,
+        radius: f32,
+    },
+    // size is before rotating, angle is in radians
+    OrientedRect {
+        center: Vector2,
+        size: Vector2,
+        angle: f32,
+    },
+    // Everything within radius of the segment from start to end, e.g. a laser with rounded ends
+    Capsule {
+        start: Vector2,
+        end: Vector2,
+        radius: f32,
+    },
+}
+
+impl Shape {
+    pub fn intersects(&self, other: &Shape) -> bool {
+        match (self.rect(), other.rect()) {
+            (Some(_), Some(_)) => {
+                if let (
+                    Shape::Aabb {
+                        center: p1,
+                        size: s1,
+                    },
+                    Shape::Aabb {
+                        center: p2,
+                        size: s2,
+                    },
+                ) = (self, other)
+                {
+                    return ((p1.x - p2.x).abs() * 2.0 < (s1.x + s2.x))
+                        && ((p1.y - p2.y).abs() * 2.0 < (s1.y + s2.y));
+                }
+                let (a, b) = (self.corners(), other.corners());
+                !separated(&a, &b) && !separated(&b, &a)
+            }
+            (Some(rect), None) => {
+                let (start, end, radius) = other.segment();
+                rect_distance(rect, start, end) < radius
+            }
+            (None, Some(rect)) => {
+                let (start, end, radius) = self.segment();
+                rect_distance(rect, start, end) < radius
+            }
+            (None, None) => {
+                let (a1, a2, r1) = self.segment();
+                let (b1, b2, r2) = other.segment();
+                segment_distance(a1, a2, b1, b2) < r1 + r2
+            }
+        }
+    }
+
+    // Center, half size and angle of the rectangular shapes
+    fn rect(&self) -> Option<(Vector2, Vector2, f32)> {
+        match *self {
+            Shape::Aabb { center, size } => Some((center, size / 2.0, 0.0)),
+            Shape::OrientedRect {
+                center,
+                size,
+                angle,
+            } => Some((center, size / 2.0, angle)),
+            _ => None,
+        }
+    }
+
+    // Round shapes as a segment and how far around it they reach, a circle is a segment that doesn't go anywhere
+    fn segment(&self) -> (Vector2, Vector2, f32) {
+        match *self {
+            Shape::Circle { center, radius } => (center, center, radius),
+            Shape::Capsule { start, end, radius } => (start, end, radius),
+            _ => unreachable!("only round shapes are segments"),
+        }
+    }
+
+    fn corners(&self) -> [Vector2; 4] {
+        let (center, half, angle) = self.rect().expect("only rectangles have corners");
+        [
+            Vector2::new(-half.x, -half.y),
+            Vector2::new(half.x, -half.y),
+            Vector2::new(half.x, half.y),
+            Vector2::new(-half.x, half.y),
+        ]
+        .map(|x| x.rotate(angle) + center)
+    }
+}
+
+// Whether one of a's edges has all of b on its outside, a has to be clockwise (which corners() is since y points down)
+fn separated(a: &[Vector2; 4], b: &[Vector2; 4]) -> bool {
+    (0..4).any(|i| {
+        let edge = a[(i + 1) % 4] - a[i];
+        b.iter().all(|&x| edge.cross(x - a[i]) <= 0.0)
+    })
+}
+
+// Between the segment and a rectangle given as center, half size and angle, 0 if they overlap
+fn rect_distance(rect: (Vector2, Vector2, f32), start: Vector2, end: Vector2) -> f32 {
+    let (center, half, angle) = rect;
+    // In the rectangle's own frame, where it's axis-aligned around the origin
+    let start = (start - center).rotate(-angle);
+    let end = (end - center).rotate(-angle);
+    if clips_box(start, end, half) {
+        return 0.0;
+    }
+
+    let corners = [
+        Vector2::new(-half.x, -half.y),
+        Vector2::new(half.x, -half.y),
+        Vector2::new(half.x, half.y),
+        Vector2::new(-half.x, half.y),
+    ];
+    let to_box = |x: Vector2| {
+        let outside = Vector2::new((x.x.abs() - half.x).max(0.0), (x.y.abs() - half.y).max(0.0));
+        outside.length()
+    };
+    corners
+        .iter()
+        .map(|&x| point_segment_distance(x, start, end))
+        .fold(to_box(start).min(to_box(end)), f32::min)
+}
+
+// Liang-Barsky against [-half, half]
+fn clips_box(start: Vector2, end: Vector2, half: Vector2) -> bool {
+    let delta = end - start;
+    let (mut t0, mut t1) = (0.0f32, 1.0f32);
+    for (p, q) in [
+        (-delta.x, start.x + half.x),
+        (delta.x, half.x - start.x),
+        (-delta.y, start.y + half.y),
+        (delta.y, half.y - start.y),
+    ] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return false;
+            }
+        } else {
+            let t = q / p;
+            if p < 0.0 {
+                t0 = t0.max(t);
+            } else {
+                t1 = t1.min(t);
+            }
+        }
+    }
+    t0 <= t1
+}
+
+fn point_segment_distance(point: Vector2, start: Vector2, end: Vector2) -> f32 {
+    let delta = end - start;
+    let length = delta.length_squared();
+    let t = if length > 0.0 {
+        ((point - start).dot(delta) / length).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    point.distance(start + delta * t)
+}
+
+fn segment_distance(a1: Vector2, a2: Vector2, b1: Vector2, b2: Vector2) -> f32 {
+    // Properly crossing, anything else is covered by the endpoints
+    let (da, db) = (a2 - a1, b2 - b1);
+    let (s1, s2) = (da.cross(b1 - a1), da.cross(b2 - a1));
+    let (s3, s4) = (db.cross(a1 - b1), db.cross(a2 - b1));
+    if ((s1 < 0.0 && s2 > 0.0) || (s1 > 0.0 && s2 < 0.0))
+        && ((s3 < 0.0 && s4 > 0.0) || (s3 > 0.0 && s4 < 0.0))
+    {
+        return 0.0;
+    }
+    point_segment_distance(a1, b1, b2)
+        .min(point_segment_distance(a2, b1, b2))
+        .min(point_segment_distance(b1, a1, a2))
+        .min(point_segment_distance(b2, a1, a2))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotate_quarter_turn() {
+        let rotated = Vector2::new(2.0, 1.0)
+            .rotate_around(Vector2::new(1.0, 1.0), std::f32::consts::FRAC_PI_2);
+        assert!(rotated.distance(Vector2::new(1.0, 2.0)) < 1e-6);
+        assert_eq!(Vector2::default().normalize(), Vector2::default());
+    }
+
+    #[test]
+    fn touching_is_not_intersecting() {
+        let rect = Shape::Aabb {
+            center: Vector2::new(0.0, 0.0),
+            size: Vector2::new(2.0, 2.0),
+        };
+        let circle = |x| Shape::Circle {
+            center: Vector2::new(x, 0.0),
+            radius: 1.0,
+        };
+        assert!(rect.intersects(&circle(1.5)));
+        assert!(!rect.intersects(&circle(2.0)));
+        assert!(circle(0.0).intersects(&circle(1.9)));
+        assert!(!circle(0.0).intersects(&circle(2.0)));
+    }
+
+    #[test]
+    fn rotated_rect_misses_corner() {
+        // A diamond's corner sticks out further than its sides
+        let diamond = Shape::OrientedRect {
+            center: Vector2::new(0.0, 0.0),
+            size: Vector2::new(2.0, 2.0),
+            angle: std::f32::consts::FRAC_PI_4,
+        };
+        let square = |x: f32, y: f32| Shape::Aabb {
+            center: Vector2::new(x, y),
+            size: Vector2::new(0.2, 0.2),
+        };
+        assert!(diamond.intersects(&square(1.45, 0.0)));
+        assert!(!diamond.intersects(&square(0.85, 0.85)));
+    }
+
+    #[test]
+    fn capsule_along_laser() {
+        let laser = Shape::Capsule {
+            start: Vector2::new(0.0, 0.0),
+            end: Vector2::new(10.0, 10.0),
+            radius: 0.5,
+        };
+        let rect = |x: f32, y: f32| Shape::Aabb {
+            center: Vector2::new(x, y),
+            size: Vector2::new(1.0, 1.0),
+        };
+        assert!(laser.intersects(&rect(5.0, 5.0)));
+        assert!(rect(5.5, 4.5).intersects(&laser));
+        assert!(!laser.intersects(&rect(7.0, 3.0)));
+        assert!(!laser.intersects(&rect(11.5, 11.5)));
+        let crossing = Shape::Capsule {
+            start: Vector2::new(0.0, 10.0),
+            end: Vector2::new(10.0, 0.0),
+            radius: 0.0,
+        };
+        assert!(laser.intersects(&crossing));
+    }
+}
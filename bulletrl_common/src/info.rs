@@ -0,0 +1,221 @@
+use std::io::{Error, Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+
+use crate::{read_len, truncate, ProtocolError, MAX_MESSAGE_LEN};
+
+// Extra values sent along with every observation, which end up in the trainer's info dict
+// Keys are free-form, but environments should stick to the same ones where they can, e.g. seed, frame, end
+#[derive(Clone, Debug, Default)]
+pub struct Info {
+    entries: Vec<(String, InfoValue)>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum InfoValue {
+    Int(i64),
+    // Seeds use the full range of a u64
+    UInt(u64),
+    Float(f64),
+    Bool(bool),
+    Str(String),
+}
+
+const INFO_INT: u8 = 0;
+const INFO_FLOAT: u8 = 1;
+const INFO_BOOL: u8 = 2;
+const INFO_STR: u8 = 3;
+const INFO_UINT: u8 = 4;
+
+impl Info {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    // Replaces the value if the key is already there
+    pub fn set<V: Into<InfoValue>>(&mut self, key: &str, value: V) -> &mut Self {
+        let value = value.into();
+        match self.entries.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value,
+            None => self.entries.push((key.to_string(), value)),
+        }
+        self
+    }
+
+    pub fn get(&self, key: &str) -> Option<&InfoValue> {
+        self.entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    // Wire format: entry count (u16), then for every entry:
+    //     key length (u8), key, type (u8), value (i64, f64, u8, u32 length + UTF-8 or u64 depending on the type)
+    pub(crate) fn write<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        let count = self.entries.len().min(u16::MAX as usize);
+        w.write_u16::<LittleEndian>(count as u16)?;
+        for (key, value) in &self.entries[..count] {
+            let key = truncate(key, u8::MAX as usize);
+            w.write_u8(key.len() as u8)?;
+            w.write_all(key.as_bytes())?;
+            match value {
+                InfoValue::Int(x) => {
+                    w.write_u8(INFO_INT)?;
+                    w.write_i64::<LittleEndian>(*x)?;
+                }
+                InfoValue::UInt(x) => {
+                    w.write_u8(INFO_UINT)?;
+                    w.write_u64::<LittleEndian>(*x)?;
+                }
+                InfoValue::Float(x) => {
+                    w.write_u8(INFO_FLOAT)?;
+                    w.write_f64::<LittleEndian>(*x)?;
+                }
+                InfoValue::Bool(x) => {
+                    w.write_u8(INFO_BOOL)?;
+                    w.write_u8(*x as u8)?;
+                }
+                InfoValue::Str(x) => {
+                    let x = truncate(x, MAX_MESSAGE_LEN);
+                    w.write_u8(INFO_STR)?;
+                    w.write_u32::<LittleEndian>(x.len() as u32)?;
+                    w.write_all(x.as_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn read<R: Read>(r: &mut R) -> Result<Self, ProtocolError> {
+        let mut info = Info::new();
+        for _ in 0..r.read_u16::<LittleEndian>()? {
+            let key_len = r.read_u8()? as usize;
+            let key = read_string(r, key_len)?;
+            let value = match r.read_u8()? {
+                INFO_INT => InfoValue::Int(r.read_i64::<LittleEndian>()?),
+                INFO_UINT => InfoValue::UInt(r.read_u64::<LittleEndian>()?),
+                INFO_FLOAT => InfoValue::Float(r.read_f64::<LittleEndian>()?),
+                INFO_BOOL => InfoValue::Bool(r.read_u8()? != 0),
+                INFO_STR => {
+                    let len = read_len(r, MAX_MESSAGE_LEN, "info string")?;
+                    InfoValue::Str(read_string(r, len)?)
+                }
+                x => {
+                    return Err(ProtocolError::Malformed(format!(
+                        "unknown info type {} for {}",
+                        x, key
+                    )))
+                }
+            };
+            info.entries.push((key, value));
+        }
+        Ok(info)
+    }
+}
+
+fn read_string<R: Read>(r: &mut R, len: usize) -> Result<String, ProtocolError> {
+    let mut data = vec![0u8; len];
+    r.read_exact(&mut data)?;
+    String::from_utf8(data).map_err(|_| ProtocolError::Malformed("info isn't UTF-8".to_string()))
+}
+
+macro_rules! impl_from_int {
+    ($variant:ident, $as:ty, $($t:ty),*) => {
+        $(impl From<$t> for InfoValue {
+            fn from(x: $t) -> Self {
+                InfoValue::$variant(x as $as)
+            }
+        })*
+    };
+}
+
+impl_from_int!(Int, i64, i32, i64);
+impl_from_int!(UInt, u64, u8, u32, u64, usize);
+
+impl From<f32> for InfoValue {
+    fn from(x: f32) -> Self {
+        InfoValue::Float(x as f64)
+    }
+}
+
+impl From<f64> for InfoValue {
+    fn from(x: f64) -> Self {
+        InfoValue::Float(x)
+    }
+}
+
+impl From<bool> for InfoValue {
+    fn from(x: bool) -> Self {
+        InfoValue::Bool(x)
+    }
+}
+
+impl From<&str> for InfoValue {
+    fn from(x: &str) -> Self {
+        InfoValue::Str(x.to_string())
+    }
+}
+
+impl From<String> for InfoValue {
+    fn from(x: String) -> Self {
+        InfoValue::Str(x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Writes info followed by a marker, which has to come out right after it
+    fn round_trip(info: &Info) -> Info {
+        let mut data = Vec::new();
+        info.write(&mut data).unwrap();
+        data.write_u32::<LittleEndian>(0xdeadbeef).unwrap();
+        let mut r = data.as_slice();
+        let info = Info::read(&mut r).unwrap();
+        assert_eq!(r.read_u32::<LittleEndian>().unwrap(), 0xdeadbeef);
+        assert!(r.is_empty());
+        info
+    }
+
+    #[test]
+    fn every_type() {
+        let mut info = Info::new();
+        info.set("int", i64::MIN)
+            .set("uint", u64::MAX)
+            .set("float", -0.25f64)
+            .set("inf", f64::INFINITY)
+            .set("true", true)
+            .set("false", false)
+            .set("str", "ピチューン")
+            .set("empty", "")
+            .set("", 1i32);
+        let read = round_trip(&info);
+        assert_eq!(read.entries, info.entries);
+
+        // Replacing keeps the order
+        info.set("int", 5u8);
+        assert_eq!(info.entries[0], ("int".to_string(), InfoValue::UInt(5)));
+    }
+
+    #[test]
+    fn caps() {
+        // Filled directly since set looks through every entry
+        let info = Info {
+            entries: (0..u16::MAX as u64 + 10)
+                .map(|i| (i.to_string(), InfoValue::UInt(i)))
+                .collect(),
+        };
+        let read = round_trip(&info);
+        assert_eq!(read.entries[..], info.entries[..u16::MAX as usize]);
+
+        // Cut at character boundaries, so the rest of the stream still lines up
+        let mut info = Info::new();
+        info.set(&"é".repeat(200), 1u8)
+            .set("long", "ü".repeat(MAX_MESSAGE_LEN));
+        let read = round_trip(&info);
+        assert_eq!(read.entries[0].0, "é".repeat(127));
+        assert_eq!(read.entries[0].1, InfoValue::UInt(1));
+        assert_eq!(
+            read.get("long"),
+            Some(&InfoValue::Str("ü".repeat(MAX_MESSAGE_LEN / 2)))
+        );
+    }
+}
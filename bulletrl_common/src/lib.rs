@@ -1,18 +1,52 @@
 use std::{
-    io::Write,
-    net::{SocketAddr, TcpStream},
+    io::{Read, Write},
+    sync::OnceLock,
 };
 
 use bitflags::bitflags;
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use log::info;
+use log::{error, info};
 
+mod downscale;
+mod error;
+mod geometry;
+mod info;
+mod objects;
+mod recorder;
+mod server;
+mod style;
+mod transport;
+pub use downscale::Downscaler;
+pub use error::ProtocolError;
+pub use geometry::{Shape, Vector2};
+pub use info::{Info, InfoValue};
+pub use objects::{Object, OBJECT_HAS_VELOCITY, OBJECT_PRESENT, OBJECT_RECORD_SIZE};
+pub use recorder::Recorder;
+pub use server::{EnvConnection, EnvServer, Observation, ObservationRequest};
+pub use style::{Body, RenderMode, RenderStyle};
+pub use transport::{connect, ShmTransport, Transport};
+
+// The Touhou playfield, which both games use as their world size and render at by default
 pub const FIELD_WIDTH: usize = 384;
 pub const FIELD_HEIGHT: usize = 448;
 
 // Since I'm doing manual TCP communication, it's possible that it might desync due to a programming error
 // This should catch that
 const TCP_SENTINEL: u32 = 0x1337BEEF;
+// Sent instead of TCP_SENTINEL by a side that's about to give up, followed by why (u32 length + UTF-8)
+const ERROR_SENTINEL: u32 = 0xDEADBEEF;
+
+// th6 alone can have over a thousand things on screen, this is just a sanity check
+const MAX_OBJECTS: u32 = 65536;
+
+// Caps on lengths read off the wire, so that a desync or a broken peer is an error instead of a huge allocation
+const MAX_OPTIONS_LEN: usize = 1 << 20;
+const MAX_STATE_LEN: usize = 64 << 20;
+// Error messages and info strings
+const MAX_MESSAGE_LEN: usize = 1 << 16;
+
+// Has to be bumped whenever the wire format changes, otherwise old binaries will silently desync
+pub const PROTOCOL_VERSION: u32 = 11;
 
 bitflags! {
     pub struct Input: u8 {
@@ -24,154 +58,1347 @@ bitflags! {
     }
 }
 
-#[repr(C)]
-#[derive(Clone, Copy)]
-pub struct Vector2 {
-    pub x: f32,
-    pub y: f32,
+bitflags! {
+    // The trainer picks exactly one of RGBA32, PLANES and OBJECTS, optionally along with DOWNSCALED for the first two
+    pub struct ObservationEncoding: u32 {
+        // Raw Renderer::buffer, width * height little-endian 0x00RRGGBB pixels
+        const RGBA32 = 0b00000001;
+        // Area-averaged down to a size and channel count picked by the trainer, see Downscaler
+        const DOWNSCALED = 0b00000010;
+        // Renderer::write_planes, PLANE_COUNT planes of width * height bytes
+        const PLANES = 0b00000100;
+        // Renderer::objects as fixed-size records, see Object
+        const OBJECTS = 0b00001000;
+    }
+}
+
+bitflags! {
+    // Optional commands an environment knows how to handle
+    pub struct EnvFeatures: u32 {
+        // SaveState and LoadState
+        const SNAPSHOTS = 0b00000001;
+    }
+}
+
+// Flags after the reward in every observation
+// Terminated means the episode ended on its own (e.g. the player died), truncated means it was cut short
+// (e.g. a time limit) and could have gone on, which matters to trainers that bootstrap value estimates
+const STEP_TERMINATED: u8 = 0b00000001;
+const STEP_TRUNCATED: u8 = 0b00000010;
+
+// Commands sent by the trainer, each one is answered with exactly one observation unless noted otherwise
+// A connection can carry several environments (EnvInfo::env_count), which are told apart by their index, the env id
+#[derive(Clone, Debug)]
+pub enum Command {
+    // Advance every environment on the connection, one input each in env id order
+    // Answered with one observation per environment, all in the same reply
+    Step(Vec<Input>),
+    // Throw away the current episode and start a fresh one
+    // Options are free-form text that each environment interprets on its own
+    Reset {
+        env: u32,
+        seed: Option<u64>,
+        options: Option<String>,
+    },
+    // The trainer is done with every environment on the connection, no observation is sent back
+    Close,
+    // Answered with an opaque blob of the full environment state instead of an observation
+    SaveState {
+        env: u32,
+    },
+    // Roll back to a blob from SaveState, answered with an observation of the restored state
+    LoadState {
+        env: u32,
+        data: Vec<u8>,
+    },
+}
+
+const COMMAND_STEP: u8 = 0;
+const COMMAND_RESET: u8 = 1;
+const COMMAND_CLOSE: u8 = 2;
+const COMMAND_SAVE_STATE: u8 = 3;
+const COMMAND_LOAD_STATE: u8 = 4;
+
+// Everything the trainer needs to know about the environment before the first step
+#[derive(Clone, Debug)]
+pub struct EnvInfo {
+    pub game: String,
+    // Size of Renderer::buffer, which isn't necessarily the size of the game's world
+    pub field_width: u32,
+    pub field_height: u32,
+    pub encodings: ObservationEncoding,
+    pub inputs: Input,
+    pub features: EnvFeatures,
+    // Environments on this connection, at least 1
+    pub env_count: u32,
+}
+
+// Everything that can be drawn, each one gets its own plane so that overlapping objects don't hide each other
+// Also the kind of each Object
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Plane {
+    Player,
+    EnemyBullets,
+    Lasers,
+    Enemies,
+    Items,
 }
 
-impl Vector2 {
-    pub fn new(x: f32, y: f32) -> Self {
-        Self { x, y }
+pub const PLANE_COUNT: usize = 5;
+
+impl Plane {
+    // Color in Renderer::buffer, where later draws still overwrite earlier ones
+    pub fn color(self) -> u32 {
+        match self {
+            Plane::Player => 0x00FF0000,
+            Plane::EnemyBullets | Plane::Lasers => 0x000000FF,
+            Plane::Enemies => 0x0000FF00,
+            Plane::Items => 0x00FFFF00,
+        }
     }
 }
 
+// Draws in world units, e.g. the game's own coordinates, onto a buffer of any size
+#[derive(Clone)]
 pub struct Renderer {
     pub buffer: Box<[u32]>,
-    contours: Box<[i32]>,
+    // One byte per pixel with bit (1 << plane) set wherever something of that plane was drawn
+    // Way cheaper to clear every frame than full planes, see write_planes for those
+    pub classes: Box<[u8]>,
+    // Filled alongside drawing by every environment
+    pub objects: Vec<Object>,
+    // Circles and ellipses blend into buffer by how much of each pixel they cover instead of all or nothing
+    // classes is unaffected, a pixel still belongs to a plane only if its center is inside
+    pub antialias: bool,
+    // How draw_object sizes things compared to their hitboxes
+    pub style: RenderStyle,
+    width: usize,
+    height: usize,
+    // Pixels per world unit
+    scale: Vector2,
+    // Fraction of the plane's color that's drawn, only below 1 for Dual mode's hitbox cores
+    intensity: f32,
+    // Scratch space for fill_polygon
+    crossings: Vec<f32>,
+    pixels: Vec<Vector2>,
 }
 
 impl Default for Renderer {
     fn default() -> Self {
-        Renderer {
-            buffer: (vec![0; FIELD_WIDTH * FIELD_HEIGHT]).into_boxed_slice(),
-            contours: (vec![-1; FIELD_HEIGHT * 2]).into_boxed_slice(),
-        }
+        Renderer::new(
+            FIELD_WIDTH as f32,
+            FIELD_HEIGHT as f32,
+            FIELD_WIDTH,
+            FIELD_HEIGHT,
+        )
     }
 }
 
 impl Renderer {
+    // Maps a field_width * field_height world onto width * height pixels
+    pub fn new(field_width: f32, field_height: f32, width: usize, height: usize) -> Self {
+        assert!(
+            width > 0 && height > 0 && field_width > 0.0 && field_height > 0.0,
+            "can't render {}x{} to {}x{}",
+            field_width,
+            field_height,
+            width,
+            height
+        );
+        Renderer {
+            buffer: (vec![0; width * height]).into_boxed_slice(),
+            classes: (vec![0; width * height]).into_boxed_slice(),
+            objects: Vec::new(),
+            antialias: false,
+            style: RenderStyle::default(),
+            width,
+            height,
+            scale: Vector2::new(width as f32 / field_width, height as f32 / field_height),
+            intensity: 1.0,
+            crossings: Vec::new(),
+            pixels: Vec::new(),
+        }
+    }
+
+    // Renders at the size in the BULLETRL_RENDER_SIZE environment variable (e.g. 96x112) if there is one,
+    // otherwise one pixel per world unit
+    pub fn from_env(field_width: usize, field_height: usize) -> Self {
+        let (width, height) = render_size_from_env().unwrap_or((field_width, field_height));
+        Renderer::new(field_width as f32, field_height as f32, width, height)
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    // World to pixel coordinates
+    pub fn to_pixels(&self, point: Vector2) -> Vector2 {
+        Vector2::new(point.x * self.scale.x, point.y * self.scale.y)
+    }
+
     pub fn clear(&mut self) {
         self.buffer.fill(0u32);
+        self.classes.fill(0u8);
+        self.objects.clear();
     }
 
-    pub fn draw_rect(&mut self, color: u32, x: i32, y: i32, w: i32, h: i32) {
-        let left = (x - w / 2).clamp(0, FIELD_WIDTH as i32 - 1) as usize;
-        let right = (x + w / 2).clamp(0, FIELD_WIDTH as i32 - 1) as usize;
-        let top = (y - h / 2).clamp(0, FIELD_HEIGHT as i32 - 1) as usize;
-        let bottom = (y + h / 2).clamp(0, FIELD_HEIGHT as i32 - 1) as usize;
+    // PLANE_COUNT planes of width * height bytes in the order of Plane, 0xFF wherever it was drawn
+    pub fn write_planes(&self, out: &mut Vec<u8>) {
+        out.clear();
+        for plane in 0..PLANE_COUNT {
+            out.extend(
+                self.classes
+                    .iter()
+                    .map(|x| 0u8.wrapping_sub((x >> plane) & 1)),
+            );
+        }
+    }
 
-        for y in top..=bottom {
-            for x in left..=right {
-                self.buffer[y * FIELD_WIDTH + x] = color;
-            }
+    fn color(&self, plane: Plane) -> u32 {
+        if self.intensity >= 1.0 {
+            return plane.color();
         }
+        let dimmed = plane
+            .color()
+            .to_le_bytes()
+            .map(|x| (x as f32 * self.intensity + 0.5) as u8);
+        u32::from_le_bytes(dimmed)
     }
 
-    fn bresenham_update_contours(&mut self, p1: Vector2, p2: Vector2) {
-        // http://rosettacode.org/wiki/Bitmap/Bresenham%27s_line_algorithm#C
-        let mut x0 = p1.x as i32;
-        let mut y0 = p1.y as i32;
-        let x1 = p2.x as i32;
-        let y1 = p2.y as i32;
+    fn put(&mut self, plane: Plane, i: usize) {
+        self.buffer[i] = self.color(plane);
+        self.classes[i] |= 1 << plane as u8;
+    }
 
-        let dx = (x1 - x0).abs();
-        let sx = if x0 < x1 { 1 } else { -1 };
-        let dy = (y1 - y0).abs();
-        let sy = if y0 < y1 { 1 } else { -1 };
-        let mut err = (if dx > dy { dx } else { -dy }) / 2;
+    // Covers the world units from x - w / 2 to x + w / 2 inclusive, and every pixel any of them touch
+    // so that small things never disappear at low resolutions
+    pub fn draw_rect(&mut self, plane: Plane, x: i32, y: i32, w: i32, h: i32) {
+        let touched = |start: i32, end: i32, scale: f32, len: usize| {
+            let first = (start as f32 * scale).floor().clamp(0.0, len as f32) as usize;
+            let last = ((end + 1) as f32 * scale).ceil().clamp(0.0, len as f32) as usize;
+            first..last.max(first)
+        };
+        let columns = touched(x - w / 2, x + w / 2, self.scale.x, self.width);
+        let rows = touched(y - h / 2, y + h / 2, self.scale.y, self.height);
+        if columns.is_empty() {
+            return;
+        }
 
-        loop {
-            if y0 >= 0 && y0 < FIELD_HEIGHT as i32 {
-                if x0 < self.contours[y0 as usize * 2] || self.contours[y0 as usize * 2] == -1 {
-                    self.contours[y0 as usize * 2] = x0;
-                }
-                if x0 > self.contours[y0 as usize * 2 + 1]
-                    || self.contours[y0 as usize * 2 + 1] == -1
-                {
-                    self.contours[y0 as usize * 2 + 1] = x0;
-                }
+        let color = self.color(plane);
+        for y in rows {
+            let row = y * self.width;
+            let (left, right) = (row + columns.start, row + columns.end);
+            self.buffer[left..right].fill(color);
+            for x in &mut self.classes[left..right] {
+                *x |= 1 << plane as u8;
             }
-            if x0 == x1 && y0 == y1 {
-                break;
+        }
+    }
+
+    // Fills every pixel whose center is inside the polygon, edges on the right and bottom are left out so that
+    // polygons sharing an edge don't overlap
+    // Meant for convex polygons, but anything that doesn't cross itself fills correctly
+    pub fn fill_polygon(&mut self, plane: Plane, points: &[Vector2]) {
+        let mut pixels = std::mem::take(&mut self.pixels);
+        pixels.clear();
+        pixels.extend(points.iter().map(|&x| self.to_pixels(x)));
+        self.fill_pixel_polygon(plane, &pixels);
+        self.pixels = pixels;
+    }
+
+    fn fill_pixel_polygon(&mut self, plane: Plane, points: &[Vector2]) {
+        if points.len() < 3 {
+            return;
+        }
+        let (top, bottom) = points
+            .iter()
+            .fold((f32::INFINITY, f32::NEG_INFINITY), |(top, bottom), p| {
+                (top.min(p.y), bottom.max(p.y))
+            });
+        if !top.is_finite() || !bottom.is_finite() {
+            return;
+        }
+        for y in span(top, bottom, self.height) {
+            let center = y as f32 + 0.5;
+            self.crossings.clear();
+            for (i, a) in points.iter().enumerate() {
+                let b = points[(i + 1) % points.len()];
+                if (a.y <= center) != (b.y <= center) {
+                    self.crossings
+                        .push(a.x + (center - a.y) / (b.y - a.y) * (b.x - a.x));
+                }
             }
-            let e2 = err;
-            if e2 > -dx {
-                err -= dy;
-                x0 += sx;
+            self.crossings.sort_by(f32::total_cmp);
+
+            let row = y * self.width;
+            for i in (0..self.crossings.len() / 2).map(|x| x * 2) {
+                for x in span(self.crossings[i], self.crossings[i + 1], self.width) {
+                    self.put(plane, row + x);
+                }
             }
-            if e2 < dy {
-                err += dx;
-                y0 += sy;
+        }
+    }
+
+    // size is the full width and height before rotating, angle is in radians clockwise (y points down)
+    pub fn draw_rotated_rect(&mut self, plane: Plane, center: Vector2, size: Vector2, angle: f32) {
+        let (sin, cos) = angle.sin_cos();
+        let corner = |x: f32, y: f32| {
+            Vector2::new(center.x + x * cos - y * sin, center.y + x * sin + y * cos)
+        };
+        let (w, h) = (size.x / 2.0, size.y / 2.0);
+        self.fill_polygon(
+            plane,
+            &[corner(-w, -h), corner(w, -h), corner(w, h), corner(-w, h)],
+        );
+    }
+
+    // radii are half the width and height
+    pub fn draw_ellipse(&mut self, plane: Plane, center: Vector2, radii: Vector2) {
+        if !(radii.x > 0.0 && radii.y > 0.0 && center.x.is_finite() && center.y.is_finite()) {
+            return;
+        }
+        let center = self.to_pixels(center);
+        let radii = self.to_pixels(radii);
+        // A pixel can be partially covered up to half a pixel outside
+        let pad = if self.antialias { 0.5 } else { 0.0 };
+        let columns = span(
+            center.x - radii.x - pad,
+            center.x + radii.x + pad,
+            self.width,
+        );
+        let rows = span(
+            center.y - radii.y - pad,
+            center.y + radii.y + pad,
+            self.height,
+        );
+        // Caps coverage for ellipses smaller than a pixel
+        let area = (std::f32::consts::PI * radii.x * radii.y).min(1.0);
+
+        for y in rows {
+            let dy = y as f32 + 0.5 - center.y;
+            for x in columns.clone() {
+                let dx = x as f32 + 0.5 - center.x;
+                let (nx, ny) = (dx / radii.x, dy / radii.y);
+                let f = nx * nx + ny * ny - 1.0;
+                let i = y * self.width + x;
+                if !self.antialias {
+                    if f < 0.0 {
+                        self.put(plane, i);
+                    }
+                    continue;
+                }
+
+                // Distance to the edge, from the implicit function and its gradient
+                let gradient =
+                    2.0 * (nx * nx / (radii.x * radii.x) + ny * ny / (radii.y * radii.y)).sqrt();
+                let distance = if gradient > 0.0 {
+                    -f / gradient
+                } else {
+                    radii.x.min(radii.y)
+                };
+                let coverage = (distance + 0.5).clamp(0.0, 1.0).min(area);
+                if coverage > 0.0 {
+                    self.blend(plane, i, coverage);
+                }
+                if f < 0.0 {
+                    self.classes[i] |= 1 << plane as u8;
+                }
             }
         }
     }
 
-    pub fn draw_line(&mut self, color: u32, p1: Vector2, p2: Vector2, size: f32) {
-        let perpendicular = (-((p2.x - p1.x) / (p2.y - p1.x))).atan();
-        let psin = perpendicular.sin();
-        let pcos = perpendicular.cos();
+    pub fn draw_circle(&mut self, plane: Plane, center: Vector2, radius: f32) {
+        self.draw_ellipse(plane, center, Vector2::new(radius, radius));
+    }
 
-        let lp1 = Vector2::new(pcos * (-size / 2.0) + p1.x, psin * (-size / 2.0) + p1.y);
-        let lp2 = Vector2::new(pcos * (size / 2.0) + p1.x, psin * (size / 2.0) + p1.y);
-        let lp3 = Vector2::new(pcos * (size / 2.0) + p2.x, psin * (size / 2.0) + p2.y);
-        let lp4 = Vector2::new(pcos * (-size / 2.0) + p2.x, psin * (-size / 2.0) + p2.y);
+    fn blend(&mut self, plane: Plane, i: usize, coverage: f32) {
+        let old = self.buffer[i].to_le_bytes();
+        let new = self.color(plane).to_le_bytes();
+        let mut out = [0u8; 4];
+        for c in 0..3 {
+            out[c] = (old[c] as f32 + (new[c] as f32 - old[c] as f32) * coverage + 0.5) as u8;
+        }
+        self.buffer[i] = u32::from_le_bytes(out);
+    }
 
-        self.contours.fill(-1);
+    // Something with a size.x * size.y hitbox turned by angle, enlarged or not depending on style
+    pub fn draw_object(
+        &mut self,
+        plane: Plane,
+        center: Vector2,
+        size: Vector2,
+        angle: f32,
+        body: Body,
+    ) {
+        let style = self.style;
+        if style.mode == RenderMode::Hitbox {
+            self.draw_box(plane, center, size, angle);
+            return;
+        }
 
-        self.bresenham_update_contours(lp1, lp2);
-        self.bresenham_update_contours(lp2, lp3);
-        self.bresenham_update_contours(lp3, lp4);
-        self.bresenham_update_contours(lp4, lp1);
+        let enlarged = size * style.scale[plane as usize];
+        match body {
+            Body::Rect => self.draw_box(plane, center, enlarged, angle),
+            // Round enough that the angle doesn't matter
+            Body::Ellipse => self.draw_ellipse(plane, center, enlarged / 2.0),
+        }
+        if style.mode == RenderMode::Dual {
+            self.intensity = style.core_intensity;
+            self.draw_box(plane, center, size, angle);
+            self.intensity = 1.0;
+        }
+    }
 
-        for y in 0..FIELD_HEIGHT {
-            if self.contours[y * 2] != -1 && self.contours[y * 2 + 1] != -1 {
-                for x in self.contours[y * 2]..=self.contours[y * 2 + 1] {
-                    if x >= 0 && x < FIELD_WIDTH as i32 {
-                        self.buffer[y * FIELD_WIDTH + x as usize] = color;
-                    }
-                }
+    // Unrotated ones go through draw_rect so that tiny hitboxes still show up
+    fn draw_box(&mut self, plane: Plane, center: Vector2, size: Vector2, angle: f32) {
+        if angle == 0.0 {
+            self.draw_rect(
+                plane,
+                center.x as i32,
+                center.y as i32,
+                size.x.round() as i32,
+                size.y.round() as i32,
+            );
+        } else {
+            self.draw_rotated_rect(plane, center, size, angle);
+        }
+    }
+
+    // A rectangle from p1 to p2, size wide
+    pub fn draw_line(&mut self, plane: Plane, p1: Vector2, p2: Vector2, size: f32) {
+        let (dx, dy) = (p2.x - p1.x, p2.y - p1.y);
+        self.draw_rotated_rect(
+            plane,
+            Vector2::new((p1.x + p2.x) / 2.0, (p1.y + p2.y) / 2.0),
+            Vector2::new((dx * dx + dy * dy).sqrt(), size),
+            dy.atan2(dx),
+        );
+    }
+}
+
+// Parsed once, every Renderer::from_env in the process gets the same answer
+fn render_size_from_env() -> Option<(usize, usize)> {
+    static RENDER_SIZE: OnceLock<Option<(usize, usize)>> = OnceLock::new();
+    *RENDER_SIZE.get_or_init(|| {
+        let size = std::env::var("BULLETRL_RENDER_SIZE").ok()?;
+        let parsed = size
+            .split_once('x')
+            .and_then(|(w, h)| Some((w.parse().ok()?, h.parse().ok()?)))
+            .filter(|&(w, h)| w > 0 && h > 0);
+        if parsed.is_none() {
+            error!(
+                "Ignoring BULLETRL_RENDER_SIZE={}, expected something like 96x112",
+                size
+            );
+        }
+        parsed
+    })
+}
+
+// Pixels whose centers are in [start, end), clipped to [0, len)
+fn span(start: f32, end: f32, len: usize) -> std::ops::Range<usize> {
+    let first = (start - 0.5).ceil().clamp(0.0, len as f32) as usize;
+    let last = (end - 0.5).ceil().clamp(0.0, len as f32) as usize;
+    first..last.max(first)
+}
+
+// Turns what an environment drew into its part of a reply, cloned by environments that encode from several threads
+#[derive(Clone)]
+pub struct ObvEncoder {
+    encoding: ObservationEncoding,
+    // Only set with ObservationEncoding::DOWNSCALED
+    downscaler: Option<Downscaler>,
+    // Only set with ObservationEncoding::OBJECTS
+    max_objects: usize,
+    sorted_objects: Vec<Object>,
+    scratch: Vec<u8>,
+}
+
+impl ObvEncoder {
+    pub fn encoding(&self) -> ObservationEncoding {
+        self.encoding
+    }
+
+    // Writes one observation to out: payload, reward, flags (STEP_*), info
+    // EnvClient::send_obv writes straight into the transport, there's no copy of the whole observation in between
+    // Info should at least have the seed the current episode was started with, so the trainer can reproduce it later
+    pub fn encode<W: Write>(
+        &mut self,
+        renderer: &Renderer,
+        reward: f32,
+        terminated: bool,
+        truncated: bool,
+        info: &Info,
+        out: &mut W,
+    ) -> Result<(), ProtocolError> {
+        match &mut self.downscaler {
+            Some(downscaler) => {
+                downscaler.downscale(renderer, &mut self.scratch);
+                out.write_all(&self.scratch)?;
+            }
+            None if self.encoding.contains(ObservationEncoding::OBJECTS) => {
+                objects::write_objects(
+                    &renderer.objects,
+                    self.max_objects,
+                    &mut self.sorted_objects,
+                    &mut self.scratch,
+                );
+                out.write_all(&self.scratch)?;
             }
+            None if self.encoding.contains(ObservationEncoding::PLANES) => {
+                renderer.write_planes(&mut self.scratch);
+                out.write_all(&self.scratch)?;
+            }
+            None => out.write_all(bytemuck::cast_slice(&renderer.buffer))?,
+        }
+        out.write_f32::<LittleEndian>(reward)?;
+        let mut flags = 0;
+        if terminated {
+            flags |= STEP_TERMINATED;
         }
+        if truncated {
+            flags |= STEP_TRUNCATED;
+        }
+        out.write_u8(flags)?;
+        info.write(out)?;
+        Ok(())
     }
 }
 
 pub struct EnvClient {
-    stream: TcpStream,
+    stream: Box<dyn Transport>,
+    encoder: ObvEncoder,
+    env_count: u32,
 }
 
 impl EnvClient {
-    pub fn new(port: u16) -> Result<Self, std::io::Error> {
-        info!("Connecting to port {}", port);
-        let stream = TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port)))?;
+    pub fn new(port: u16, info: &EnvInfo) -> Result<Self, ProtocolError> {
+        EnvClient::connect(&port.to_string(), info)
+    }
+
+    // See transport::connect for what the uri can be
+    pub fn connect(uri: &str, info: &EnvInfo) -> Result<Self, ProtocolError> {
+        info!("Connecting to {}", uri);
+        let stream = transport::connect(uri)?;
         info!("Successfully connected!");
 
-        Ok(EnvClient { stream })
+        let mut client = EnvClient {
+            stream,
+            encoder: ObvEncoder {
+                encoding: ObservationEncoding::empty(),
+                downscaler: None,
+                max_objects: 0,
+                sorted_objects: Vec::new(),
+                scratch: Vec::new(),
+            },
+            env_count: info.env_count,
+        };
+        if let Err(e) = client.handshake(info) {
+            // The trainer might still be listening, e.g. after asking for something invalid
+            let _ = client.send_error(&e.to_string());
+            return Err(e);
+        }
+
+        Ok(client)
     }
 
-    pub fn recv_input(&mut self) -> Result<Input, std::io::Error> {
-        assert_eq!(
-            self.stream.read_u32::<LittleEndian>()?,
-            TCP_SENTINEL,
-            "TCP desync check failed!"
+    // Handshake order:
+    // trainer -> env: sentinel, protocol version
+    // env -> trainer: sentinel, protocol version, game id, field size, observation encodings, input bits, features,
+    //                 environment count
+    // trainer -> env: sentinel, chosen observation encoding (empty if the environment was rejected)
+    // trainer -> env: only with DOWNSCALED, width, height, channels (1 for grayscale or 3 for color, PLANE_COUNT for PLANES)
+    // trainer -> env: only with OBJECTS, max object count
+    // The trainer speaks first so that an outdated environment which is still waiting for an input
+    // replies with an observation instead of a version, which the trainer can then reject
+    fn handshake(&mut self, info: &EnvInfo) -> Result<(), ProtocolError> {
+        if info.env_count == 0 {
+            return Err(ProtocolError::Malformed(
+                "a connection needs at least one environment".to_string(),
+            ));
+        }
+        self.read_sentinel()?;
+        let trainer_version = self.stream.read_u32::<LittleEndian>()?;
+
+        // Always send our side, even on a version mismatch, so the trainer can report it too
+        self.stream.write_u32::<LittleEndian>(TCP_SENTINEL)?;
+        self.stream.write_u32::<LittleEndian>(PROTOCOL_VERSION)?;
+        let game = &info.game.as_bytes()[..info.game.len().min(u8::MAX as usize)];
+        self.stream.write_u8(game.len() as u8)?;
+        self.stream.write_all(game)?;
+        self.stream.write_u32::<LittleEndian>(info.field_width)?;
+        self.stream.write_u32::<LittleEndian>(info.field_height)?;
+        self.stream
+            .write_u32::<LittleEndian>(info.encodings.bits())?;
+        self.stream.write_u8(info.inputs.bits())?;
+        self.stream
+            .write_u32::<LittleEndian>(info.features.bits())?;
+        self.stream.write_u32::<LittleEndian>(info.env_count)?;
+        self.stream.flush()?;
+
+        if trainer_version != PROTOCOL_VERSION {
+            return Err(ProtocolError::VersionMismatch {
+                env: PROTOCOL_VERSION,
+                trainer: trainer_version,
+            });
+        }
+
+        self.read_sentinel()?;
+        let encoding =
+            ObservationEncoding::from_bits_truncate(self.stream.read_u32::<LittleEndian>()?);
+        let base = encoding - ObservationEncoding::DOWNSCALED;
+        if base.bits().count_ones() != 1
+            || !info.encodings.contains(encoding)
+            || encoding == ObservationEncoding::OBJECTS | ObservationEncoding::DOWNSCALED
+        {
+            return Err(ProtocolError::Rejected(format!(
+                "trainer chose encoding {:?}, but the environment supports {:?}",
+                encoding, info.encodings
+            )));
+        }
+        self.encoder.encoding = encoding;
+
+        if encoding.contains(ObservationEncoding::DOWNSCALED) {
+            let planes = base == ObservationEncoding::PLANES;
+            let width = self.stream.read_u32::<LittleEndian>()?;
+            let height = self.stream.read_u32::<LittleEndian>()?;
+            let channels = self.stream.read_u8()?;
+            if !(1..=info.field_width).contains(&width)
+                || !(1..=info.field_height).contains(&height)
+                || (planes && channels as usize != PLANE_COUNT)
+                || (!planes && channels != 1 && channels != 3)
+            {
+                return Err(ProtocolError::Rejected(format!(
+                    "trainer asked for an invalid observation size ({}x{}, {} channels)",
+                    width, height, channels
+                )));
+            }
+            self.encoder.downscaler = Some(if planes {
+                Downscaler::planes(width as usize, height as usize)
+            } else {
+                Downscaler::new(width as usize, height as usize, channels as usize)
+            });
+            info!(
+                "Downscaling observations to {}x{}x{}",
+                width, height, channels
+            );
+        }
+
+        if encoding.contains(ObservationEncoding::OBJECTS) {
+            let max_objects = self.stream.read_u32::<LittleEndian>()?;
+            if !(1..=MAX_OBJECTS).contains(&max_objects) {
+                return Err(ProtocolError::Rejected(format!(
+                    "trainer asked for {} objects, which isn't between 1 and {}",
+                    max_objects, MAX_OBJECTS
+                )));
+            }
+            self.encoder.max_objects = max_objects as usize;
+        }
+
+        info!(
+            "Handshake complete: {} v{}, {:?}, {} environment(s)",
+            info.game, PROTOCOL_VERSION, encoding, info.env_count
         );
+        Ok(())
+    }
+
+    fn read_sentinel(&mut self) -> Result<(), ProtocolError> {
+        read_sentinel(self.stream.as_mut())
+    }
+
+    pub fn encoding(&self) -> ObservationEncoding {
+        self.encoder.encoding
+    }
+
+    pub fn env_count(&self) -> u32 {
+        self.env_count
+    }
+
+    // For encoding observations somewhere else, e.g. on the thread that ran the environment, see send_obvs
+    pub fn encoder(&self) -> ObvEncoder {
+        self.encoder.clone()
+    }
 
-        self.stream.read_u8().map(Input::from_bits_truncate)
+    // Step: command, one input (u8) per environment
+    // Reset: command, env id (u32), has seed (u8), seed (u64), options length (u32), options
+    // Close and SaveState: command, plus an env id (u32) for SaveState
+    // LoadState: command, env id (u32), length (u32), data
+    pub fn recv_command(&mut self) -> Result<Command, ProtocolError> {
+        self.read_sentinel()?;
+
+        match self.stream.read_u8()? {
+            COMMAND_STEP => {
+                let mut inputs = vec![0u8; self.env_count as usize];
+                self.stream.read_exact(&mut inputs)?;
+                Ok(Command::Step(
+                    inputs.into_iter().map(Input::from_bits_truncate).collect(),
+                ))
+            }
+            COMMAND_RESET => {
+                let env = self.read_env()?;
+                let has_seed = self.stream.read_u8()? != 0;
+                let seed = self.stream.read_u64::<LittleEndian>()?;
+                let options_len = read_len(self.stream.as_mut(), MAX_OPTIONS_LEN, "reset options")?;
+                let mut options = vec![0u8; options_len];
+                self.stream.read_exact(&mut options)?;
+                let options = String::from_utf8(options).map_err(|_| {
+                    ProtocolError::Malformed("reset options aren't UTF-8".to_string())
+                })?;
+
+                Ok(Command::Reset {
+                    env,
+                    seed: has_seed.then_some(seed),
+                    options: (!options.is_empty()).then_some(options),
+                })
+            }
+            COMMAND_CLOSE => Ok(Command::Close),
+            COMMAND_SAVE_STATE => Ok(Command::SaveState {
+                env: self.read_env()?,
+            }),
+            COMMAND_LOAD_STATE => {
+                let env = self.read_env()?;
+                let len = read_len(self.stream.as_mut(), MAX_STATE_LEN, "state")?;
+                let mut data = vec![0u8; len];
+                self.stream.read_exact(&mut data)?;
+                Ok(Command::LoadState { env, data })
+            }
+            x => Err(ProtocolError::Malformed(format!("unknown command {}", x))),
+        }
+    }
+
+    fn read_env(&mut self) -> Result<u32, ProtocolError> {
+        let env = self.stream.read_u32::<LittleEndian>()?;
+        if env >= self.env_count {
+            return Err(ProtocolError::Malformed(format!(
+                "trainer asked for environment {}, but there are only {}",
+                env, self.env_count
+            )));
+        }
+        Ok(env)
     }
 
+    // Reply with a single observation, i.e. to Reset, LoadState or a Step on a connection with one environment
     pub fn send_obv(
         &mut self,
         renderer: &Renderer,
         reward: f32,
-        done: bool,
-    ) -> Result<(), std::io::Error> {
+        terminated: bool,
+        truncated: bool,
+        info: &Info,
+    ) -> Result<(), ProtocolError> {
         self.stream.write_u32::<LittleEndian>(TCP_SENTINEL)?;
-        self.stream
-            .write_all(bytemuck::cast_slice(&renderer.buffer))?;
-        self.stream.write_f32::<LittleEndian>(reward)?;
-        self.stream.write_u8(done as u8)?;
+        self.encoder.encode(
+            renderer,
+            reward,
+            terminated,
+            truncated,
+            info,
+            &mut self.stream,
+        )?;
+        self.stream.flush()?;
+
+        Ok(())
+    }
+
+    // Reply to a Step with observations from ObvEncoder::encode, one per environment in env id order
+    pub fn send_obvs<'a>(
+        &mut self,
+        obvs: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result<(), ProtocolError> {
+        self.stream.write_u32::<LittleEndian>(TCP_SENTINEL)?;
+        for obv in obvs {
+            self.stream.write_all(obv)?;
+        }
+        self.stream.flush()?;
 
         Ok(())
     }
+
+    pub fn send_state(&mut self, data: &[u8]) -> Result<(), ProtocolError> {
+        check_len(data.len(), MAX_STATE_LEN, "state")?;
+        self.stream.write_u32::<LittleEndian>(TCP_SENTINEL)?;
+        self.stream.write_u32::<LittleEndian>(data.len() as u32)?;
+        self.stream.write_all(data)?;
+        self.stream.flush()?;
+
+        Ok(())
+    }
+
+    // Lets the trainer know why the environment is about to exit, it sees this instead of the next reply
+    pub fn send_error(&mut self, message: &str) -> Result<(), ProtocolError> {
+        write_error(self.stream.as_mut(), message)
+    }
+}
+
+// The trainer and environment both start every message with this
+fn read_sentinel(stream: &mut dyn Transport) -> Result<(), ProtocolError> {
+    match stream.read_u32::<LittleEndian>()? {
+        TCP_SENTINEL => Ok(()),
+        ERROR_SENTINEL => {
+            let len = read_len(stream, MAX_MESSAGE_LEN, "error message")?;
+            let mut message = vec![0u8; len];
+            stream.read_exact(&mut message)?;
+            Err(ProtocolError::Remote(
+                String::from_utf8_lossy(&message).into_owned(),
+            ))
+        }
+        got => Err(ProtocolError::Desync { got }),
+    }
+}
+
+// A u32 length, checked before anything gets allocated for it
+pub(crate) fn read_len<R: Read + ?Sized>(
+    r: &mut R,
+    max: usize,
+    what: &str,
+) -> Result<usize, ProtocolError> {
+    let len = r.read_u32::<LittleEndian>()? as usize;
+    check_len(len, max, what)?;
+    Ok(len)
+}
+
+pub(crate) fn check_len(len: usize, max: usize, what: &str) -> Result<(), ProtocolError> {
+    if len > max {
+        return Err(ProtocolError::Malformed(format!(
+            "{} is {} bytes, but the limit is {}",
+            what, len, max
+        )));
+    }
+    Ok(())
+}
+
+// Cut at a character boundary so that it's still valid UTF-8
+pub(crate) fn truncate(text: &str, max: usize) -> &str {
+    let mut len = text.len().min(max);
+    while !text.is_char_boundary(len) {
+        len -= 1;
+    }
+    &text[..len]
+}
+
+fn write_error(stream: &mut dyn Transport, message: &str) -> Result<(), ProtocolError> {
+    let message = truncate(message, MAX_MESSAGE_LEN);
+    stream.write_u32::<LittleEndian>(ERROR_SENTINEL)?;
+    stream.write_u32::<LittleEndian>(message.len() as u32)?;
+    stream.write_all(message.as_bytes())?;
+    stream.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filled(renderer: &Renderer, plane: Plane) -> Vec<(usize, usize)> {
+        (0..FIELD_WIDTH * FIELD_HEIGHT)
+            .filter(|&i| renderer.classes[i] & (1 << plane as u8) != 0)
+            .map(|i| (i % FIELD_WIDTH, i / FIELD_WIDTH))
+            .collect()
+    }
+
+    fn block(x: std::ops::Range<usize>, y: std::ops::Range<usize>) -> Vec<(usize, usize)> {
+        y.flat_map(|y| x.clone().map(move |x| (x, y))).collect()
+    }
+
+    // Runs an environment's side of the handshake against a trainer that sends these bytes and then hangs up
+    // Returns how the environment's side went and everything it sent back
+    fn handshake(trainer: &[u8]) -> (Option<ProtocolError>, Vec<u8>) {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let info = EnvInfo {
+                game: "test".to_string(),
+                field_width: 2,
+                field_height: 1,
+                encodings: ObservationEncoding::RGBA32,
+                inputs: Input::all(),
+                features: EnvFeatures::empty(),
+                env_count: 1,
+            };
+            let _ = tx.send(EnvClient::connect(&port.to_string(), &info).err());
+        });
+
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        stream.write_all(trainer).unwrap();
+        stream.shutdown(std::net::Shutdown::Write).unwrap();
+        let result = rx
+            .recv_timeout(std::time::Duration::from_secs(5))
+            .expect("the handshake hung");
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).unwrap();
+        (result, reply)
+    }
+
+    #[test]
+    fn bad_handshakes() {
+        let mut trainer = Vec::new();
+        trainer.write_u32::<LittleEndian>(TCP_SENTINEL).unwrap();
+        trainer
+            .write_u32::<LittleEndian>(PROTOCOL_VERSION - 1)
+            .unwrap();
+        let (result, reply) = handshake(&trainer);
+        assert!(matches!(
+            result,
+            Some(ProtocolError::VersionMismatch { env, trainer })
+                if env == PROTOCOL_VERSION && trainer == PROTOCOL_VERSION - 1
+        ));
+        // The environment's side still gets sent so the trainer can report the mismatch too, then why it gave up
+        let mut r = reply.as_slice();
+        assert_eq!(r.read_u32::<LittleEndian>().unwrap(), TCP_SENTINEL);
+        assert_eq!(r.read_u32::<LittleEndian>().unwrap(), PROTOCOL_VERSION);
+        let error = ERROR_SENTINEL.to_le_bytes();
+        assert!(reply.windows(4).any(|x| x == error));
+
+        let (result, reply) = handshake(&0x12345678u32.to_le_bytes());
+        assert!(matches!(
+            result,
+            Some(ProtocolError::Desync { got: 0x12345678 })
+        ));
+        assert!(matches!(
+            read_sentinel(&mut std::io::Cursor::new(reply)),
+            Err(ProtocolError::Remote(_))
+        ));
+
+        // A trainer that hangs up halfway through
+        let (result, _) = handshake(&TCP_SENTINEL.to_le_bytes());
+        assert!(matches!(result, Some(ProtocolError::Disconnected)));
+    }
+
+    #[test]
+    fn oversized_lengths() {
+        // An error message claiming to be 4 GB long
+        let mut data = Vec::new();
+        data.write_u32::<LittleEndian>(ERROR_SENTINEL).unwrap();
+        data.write_u32::<LittleEndian>(u32::MAX).unwrap();
+        let mut stream = std::io::Cursor::new(data);
+        assert!(matches!(
+            read_sentinel(&mut stream),
+            Err(ProtocolError::Malformed(_))
+        ));
+
+        // Long messages are cut short instead, without splitting a character
+        let mut stream = std::io::Cursor::new(Vec::new());
+        write_error(&mut stream, &"é".repeat(MAX_MESSAGE_LEN)).unwrap();
+        stream.set_position(0);
+        match read_sentinel(&mut stream) {
+            Err(ProtocolError::Remote(x)) => assert_eq!(x, "é".repeat(MAX_MESSAGE_LEN / 2)),
+            x => panic!("{:?}", x),
+        }
+
+        let mut info = Vec::new();
+        info.write_u16::<LittleEndian>(1).unwrap();
+        info.extend_from_slice(&[1, b'x', 3]);
+        info.write_u32::<LittleEndian>(u32::MAX).unwrap();
+        assert!(matches!(
+            Info::read(&mut info.as_slice()),
+            Err(ProtocolError::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn step_flags() {
+        let mut encoder = ObvEncoder {
+            encoding: ObservationEncoding::OBJECTS,
+            downscaler: None,
+            max_objects: 1,
+            sorted_objects: Vec::new(),
+            scratch: Vec::new(),
+        };
+        let renderer = Renderer::default();
+        let mut info = Info::new();
+        info.set("seed", 3u64);
+        for (terminated, truncated, flags) in [
+            (false, false, 0),
+            (true, false, STEP_TERMINATED),
+            (false, true, STEP_TRUNCATED),
+            (true, true, STEP_TERMINATED | STEP_TRUNCATED),
+        ] {
+            let mut out = Vec::new();
+            encoder
+                .encode(&renderer, -1.0, terminated, truncated, &info, &mut out)
+                .unwrap();
+            let mut r = &out[OBJECT_RECORD_SIZE..];
+            assert_eq!(r.read_f32::<LittleEndian>().unwrap(), -1.0);
+            assert_eq!(r.read_u8().unwrap(), flags);
+            assert_eq!(
+                Info::read(&mut r).unwrap().get("seed"),
+                Some(&InfoValue::UInt(3))
+            );
+            assert!(r.is_empty());
+        }
+    }
+
+    #[test]
+    fn planes() {
+        let square = |renderer: &mut Renderer, plane: Plane, x: f32| {
+            let corners = [(0.0, 0.0), (8.0, 0.0), (8.0, 8.0), (0.0, 8.0)];
+            renderer.fill_polygon(plane, &corners.map(|(cx, cy)| Vector2::new(x + cx, cy)));
+        };
+        let mut renderer = Renderer::default();
+        for (i, plane) in [
+            Plane::Player,
+            Plane::EnemyBullets,
+            Plane::Lasers,
+            Plane::Enemies,
+            Plane::Items,
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            square(&mut renderer, plane, i as f32 * 16.0);
+        }
+        // Overlaps the player, which doesn't take it out of the player's plane
+        square(&mut renderer, Plane::Items, 4.0);
+
+        let mut out = Vec::new();
+        renderer.write_planes(&mut out);
+        let len = FIELD_WIDTH * FIELD_HEIGHT;
+        assert_eq!(out.len(), PLANE_COUNT * len);
+        for (i, plane) in out.chunks(len).enumerate() {
+            assert!(plane.iter().all(|&x| x == 0 || x == 0xFF));
+            let drawn = (0..len)
+                .filter(|&j| plane[j] == 0xFF)
+                .map(|j| (j % FIELD_WIDTH, j / FIELD_WIDTH))
+                .collect::<Vec<_>>();
+            let mut expected = block(i * 16..i * 16 + 8, 0..8);
+            if i == Plane::Items as usize {
+                expected = block(4..12, 0..8)
+                    .into_iter()
+                    .chain(expected)
+                    .collect::<Vec<_>>();
+                expected.sort_by_key(|&(x, y)| (y, x));
+            }
+            assert_eq!(drawn, expected, "plane {}", i);
+        }
+
+        // Every 8x8 square becomes a single pixel, and the overlapping one covers half of two
+        let mut downscaler = Downscaler::planes(FIELD_WIDTH / 8, FIELD_HEIGHT / 8);
+        downscaler.downscale(&renderer, &mut out);
+        let len = downscaler.output_len() / PLANE_COUNT;
+        for (i, plane) in out.chunks(len).enumerate() {
+            let mut expected = vec![0u8; len];
+            expected[i * 2] = 255;
+            if i == Plane::Items as usize {
+                expected[0] = 128;
+                expected[1] = 128;
+            }
+            assert_eq!(plane, expected, "plane {}", i);
+        }
+    }
+
+    #[test]
+    fn polygon_on_pixel_edges() {
+        let mut renderer = Renderer::default();
+        renderer.fill_polygon(
+            Plane::Enemies,
+            &[
+                Vector2::new(10.0, 10.0),
+                Vector2::new(14.0, 10.0),
+                Vector2::new(14.0, 13.0),
+                Vector2::new(10.0, 13.0),
+            ],
+        );
+        assert_eq!(filled(&renderer, Plane::Enemies), block(10..14, 10..13));
+        assert_eq!(
+            renderer.buffer[11 * FIELD_WIDTH + 12],
+            Plane::Enemies.color()
+        );
+    }
+
+    #[test]
+    fn triangle() {
+        let mut renderer = Renderer::default();
+        renderer.fill_polygon(
+            Plane::Items,
+            &[
+                Vector2::new(0.0, 0.0),
+                Vector2::new(4.0, 0.0),
+                Vector2::new(0.0, 4.0),
+            ],
+        );
+        // Centers on the hypotenuse are on its right side, so they're left out
+        assert_eq!(
+            filled(&renderer, Plane::Items),
+            vec![(0, 0), (1, 0), (2, 0), (0, 1), (1, 1), (0, 2)]
+        );
+    }
+
+    #[test]
+    fn shared_edges_dont_overlap() {
+        let mut renderer = Renderer::default();
+        let (a, b, c, d) = (
+            Vector2::new(20.0, 20.0),
+            Vector2::new(37.0, 23.0),
+            Vector2::new(31.0, 41.0),
+            Vector2::new(18.0, 35.0),
+        );
+        renderer.fill_polygon(Plane::Player, &[a, b, c]);
+        renderer.fill_polygon(Plane::Enemies, &[a, c, d]);
+        let mut whole = Renderer::default();
+        whole.fill_polygon(Plane::Player, &[a, b, c, d]);
+
+        let both = 1 << Plane::Player as u8 | 1 << Plane::Enemies as u8;
+        assert!(renderer.classes.iter().all(|x| x & both != both));
+        let union = renderer
+            .classes
+            .iter()
+            .map(|x| (x & both != 0) as u8)
+            .collect::<Vec<_>>();
+        assert_eq!(union, whole.classes.to_vec());
+    }
+
+    #[test]
+    fn rotated_rect_quarter_turn() {
+        let mut renderer = Renderer::default();
+        renderer.draw_rotated_rect(
+            Plane::EnemyBullets,
+            Vector2::new(20.0, 20.0),
+            Vector2::new(6.0, 2.0),
+            std::f32::consts::FRAC_PI_2,
+        );
+        assert_eq!(
+            filled(&renderer, Plane::EnemyBullets),
+            block(19..21, 17..23)
+        );
+    }
+
+    #[test]
+    fn vertical_and_horizontal_lines() {
+        // Used to divide by (p2.y - p1.x) and come out sideways
+        let mut renderer = Renderer::default();
+        renderer.draw_line(
+            Plane::Lasers,
+            Vector2::new(50.0, 10.0),
+            Vector2::new(50.0, 30.0),
+            4.0,
+        );
+        assert_eq!(filled(&renderer, Plane::Lasers), block(48..52, 10..30));
+
+        let mut renderer = Renderer::default();
+        renderer.draw_line(
+            Plane::Lasers,
+            Vector2::new(30.0, 50.0),
+            Vector2::new(10.0, 50.0),
+            4.0,
+        );
+        assert_eq!(filled(&renderer, Plane::Lasers), block(10..30, 48..52));
+    }
+
+    #[test]
+    fn lines_at_any_angle() {
+        let p1 = Vector2::new(190.3, 221.7);
+        let (length, size) = (150.0f32, 7.0f32);
+        for step in 0..64 {
+            let angle = step as f32 / 64.0 * std::f32::consts::TAU;
+            let (sin, cos) = angle.sin_cos();
+            let p2 = Vector2::new(p1.x + cos * length, p1.y + sin * length);
+            let mut renderer = Renderer::default();
+            renderer.draw_line(Plane::Lasers, p1, p2, size);
+
+            // Every pixel center, in coordinates along and across the line
+            for y in 0..FIELD_HEIGHT {
+                for x in 0..FIELD_WIDTH {
+                    let (px, py) = (x as f32 + 0.5 - p1.x, y as f32 + 0.5 - p1.y);
+                    let along = px * cos + py * sin;
+                    let across = -px * sin + py * cos;
+                    let margin = along.min(length - along).min(size / 2.0 - across.abs());
+                    // Too close to call with float rounding
+                    if margin.abs() < 1e-3 {
+                        continue;
+                    }
+                    assert_eq!(
+                        renderer.classes[y * FIELD_WIDTH + x] != 0,
+                        margin > 0.0,
+                        "pixel ({}, {}) at {} radians",
+                        x,
+                        y,
+                        angle
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn circle() {
+        let mut renderer = Renderer::default();
+        renderer.draw_circle(Plane::EnemyBullets, Vector2::new(10.0, 10.0), 2.0);
+        // Every pixel center within 2 of (10, 10)
+        assert_eq!(
+            filled(&renderer, Plane::EnemyBullets),
+            vec![
+                (9, 8),
+                (10, 8),
+                (8, 9),
+                (9, 9),
+                (10, 9),
+                (11, 9),
+                (8, 10),
+                (9, 10),
+                (10, 10),
+                (11, 10),
+                (9, 11),
+                (10, 11)
+            ]
+        );
+    }
+
+    #[test]
+    fn ellipse_matches_its_equation() {
+        let mut renderer = Renderer::default();
+        let (center, radii) = (Vector2::new(100.3, 200.6), Vector2::new(17.2, 5.1));
+        renderer.draw_ellipse(Plane::Items, center, radii);
+        for y in 0..FIELD_HEIGHT {
+            for x in 0..FIELD_WIDTH {
+                let nx = (x as f32 + 0.5 - center.x) / radii.x;
+                let ny = (y as f32 + 0.5 - center.y) / radii.y;
+                assert_eq!(
+                    renderer.classes[y * FIELD_WIDTH + x] != 0,
+                    nx * nx + ny * ny < 1.0,
+                    "pixel ({}, {})",
+                    x,
+                    y
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn antialiased_circle() {
+        let (center, radius) = (Vector2::new(40.0, 40.0), 6.0);
+        let mut aliased = Renderer::default();
+        aliased.draw_circle(Plane::Enemies, center, radius);
+        let mut renderer = Renderer {
+            antialias: true,
+            ..Default::default()
+        };
+        renderer.draw_circle(Plane::Enemies, center, radius);
+
+        // Same planes, but the edge fades out in the buffer
+        assert_eq!(renderer.classes, aliased.classes);
+        let green = |renderer: &Renderer, x: usize, y: usize| {
+            renderer.buffer[y * FIELD_WIDTH + x] >> 8 & 0xFF
+        };
+        assert_eq!(green(&renderer, 40, 40), 0xFF);
+        assert!((1..0xFF).contains(&green(&renderer, 45, 40)));
+        assert!((1..0xFF).contains(&green(&renderer, 34, 40)));
+        assert_eq!(green(&renderer, 47, 40), 0);
+
+        // Total intensity is about the area of the circle
+        let total = renderer
+            .buffer
+            .iter()
+            .map(|x| (x >> 8 & 0xFF) as f32 / 255.0)
+            .sum::<f32>();
+        let area = std::f32::consts::PI * radius * radius;
+        assert!((total - area).abs() < 0.01 * area, "{} vs {}", total, area);
+
+        // Smaller than a pixel, but still there
+        renderer.clear();
+        renderer.draw_circle(Plane::Enemies, Vector2::new(40.5, 40.5), 0.2);
+        assert!((1..0x40).contains(&green(&renderer, 40, 40)));
+    }
+
+    #[test]
+    fn rect_at_a_quarter_of_the_size() {
+        let mut renderer = Renderer::new(FIELD_WIDTH as f32, FIELD_HEIGHT as f32, 96, 112);
+        renderer.draw_rect(Plane::Player, 100, 200, 25, 5);
+        let filled = (0..96 * 112)
+            .filter(|&i| renderer.classes[i] != 0)
+            .map(|i| (i % 96, i / 96))
+            .collect::<Vec<_>>();
+        // World units 88 to 112 and 198 to 202, any pixel they touch
+        assert_eq!(filled, block(22..29, 49..51));
+    }
+
+    #[test]
+    fn scaled_shapes_match_drawing_in_pixels() {
+        let mut scaled = Renderer::new(FIELD_WIDTH as f32, FIELD_HEIGHT as f32, 96, 112);
+        let mut pixels = Renderer::new(96.0, 112.0, 96, 112);
+        // Scaling by a power of two is exact, so these come out the same down to the last bit
+        let quarter = |x: f32, y: f32| Vector2::new(x / 4.0, y / 4.0);
+
+        scaled.draw_ellipse(
+            Plane::EnemyBullets,
+            Vector2::new(150.0, 301.0),
+            Vector2::new(9.0, 13.0),
+        );
+        pixels.draw_ellipse(
+            Plane::EnemyBullets,
+            quarter(150.0, 301.0),
+            quarter(9.0, 13.0),
+        );
+        scaled.draw_line(
+            Plane::Lasers,
+            Vector2::new(20.0, 40.0),
+            Vector2::new(300.0, 390.0),
+            12.0,
+        );
+        pixels.draw_line(
+            Plane::Lasers,
+            quarter(20.0, 40.0),
+            quarter(300.0, 390.0),
+            3.0,
+        );
+        assert_eq!(scaled.classes, pixels.classes);
+    }
+
+    #[test]
+    fn clipped_to_the_field() {
+        let mut renderer = Renderer::default();
+        renderer.draw_rotated_rect(
+            Plane::Enemies,
+            Vector2::new(-2.0, FIELD_HEIGHT as f32),
+            Vector2::new(8.0, 8.0),
+            0.0,
+        );
+        assert_eq!(
+            filled(&renderer, Plane::Enemies),
+            block(0..2, FIELD_HEIGHT - 4..FIELD_HEIGHT)
+        );
+
+        renderer.clear();
+        renderer.draw_rotated_rect(
+            Plane::Enemies,
+            Vector2::new(-100.0, -100.0),
+            Vector2::new(8.0, 8.0),
+            1.0,
+        );
+        renderer.fill_polygon(Plane::Enemies, &[Vector2::new(f32::NAN, 0.0); 3]);
+        assert!(filled(&renderer, Plane::Enemies).is_empty());
+    }
+
+    #[test]
+    fn render_styles() {
+        let mut renderer = Renderer::default();
+        let (center, size) = (Vector2::new(100.0, 100.0), Vector2::new(4.0, 4.0));
+        let style = RenderStyle::default()
+            .with_overrides("dual, player=5, core=0.25")
+            .unwrap();
+        assert_eq!(style.mode, RenderMode::Dual);
+        assert!(style.with_overrides("scaled,bullets=2").is_err());
+        assert!(style.with_overrides("dual,core=2").is_err());
+
+        renderer.draw_object(Plane::Player, center, size, 0.0, Body::Rect);
+        let hitbox = filled(&renderer, Plane::Player);
+        assert_eq!(hitbox, block(98..103, 98..103));
+
+        // Same hitbox pixels but dimmer, inside the enlarged body
+        renderer.clear();
+        renderer.style = style;
+        renderer.draw_object(Plane::Player, center, size, 0.0, Body::Rect);
+        assert_eq!(filled(&renderer, Plane::Player), block(90..111, 90..111));
+        let red = |x: usize, y: usize| renderer.buffer[y * FIELD_WIDTH + x] >> 16;
+        assert_eq!(red(100, 100), 0x40);
+        assert_eq!(red(102, 102), 0x40);
+        assert_eq!(red(103, 103), 0xFF);
+    }
 }
@@ -0,0 +1,201 @@
+use byteorder::{LittleEndian, WriteBytesExt};
+
+use crate::{Plane, Vector2};
+
+// Exact state of one thing on the field, for trainers that would rather not learn from pixels
+// Unlike what gets drawn, the size is the actual hitbox
+#[derive(Clone, Copy, Debug)]
+pub struct Object {
+    pub kind: Plane,
+    // Center
+    pub pos: Vector2,
+    pub size: Vector2,
+    // Not every environment knows this for everything
+    pub velocity: Option<Vector2>,
+    // In radians, only lasers are rotated
+    pub angle: f32,
+}
+
+impl Object {
+    pub fn new(kind: Plane, pos: Vector2, size: Vector2) -> Self {
+        Object {
+            kind,
+            pos,
+            size,
+            velocity: None,
+            angle: 0.0,
+        }
+    }
+
+    pub fn with_velocity(mut self, velocity: Vector2) -> Self {
+        self.velocity = Some(velocity);
+        self
+    }
+
+    pub fn with_angle(mut self, angle: f32) -> Self {
+        self.angle = angle;
+        self
+    }
+}
+
+pub const OBJECT_PRESENT: u8 = 0b00000001;
+pub const OBJECT_HAS_VELOCITY: u8 = 0b00000010;
+
+// Every observation is exactly max_objects of these, padded with zeroes and cut off if there are more:
+//     kind (u8, index into Plane), flags (u8, OBJECT_*), x, y, width, height, velocity x, velocity y, angle (f32s)
+// The player comes first, then everything else from nearest to farthest so that the cutoff drops what matters least
+pub const OBJECT_RECORD_SIZE: usize = 30;
+
+pub(crate) fn write_objects(
+    objects: &[Object],
+    max_objects: usize,
+    sorted: &mut Vec<Object>,
+    out: &mut Vec<u8>,
+) {
+    sorted.clear();
+    sorted.extend_from_slice(objects);
+    let player = objects
+        .iter()
+        .find(|x| x.kind == Plane::Player)
+        .map(|x| x.pos);
+    sorted.sort_by(|a, b| match player {
+        Some(player) => (a.kind != Plane::Player)
+            .cmp(&(b.kind != Plane::Player))
+            .then_with(|| distance2(a.pos, player).total_cmp(&distance2(b.pos, player))),
+        None => std::cmp::Ordering::Equal,
+    });
+    sorted.truncate(max_objects);
+
+    out.clear();
+    for object in sorted.iter() {
+        let velocity = object.velocity.unwrap_or(Vector2::new(0.0, 0.0));
+        let mut flags = OBJECT_PRESENT;
+        if object.velocity.is_some() {
+            flags |= OBJECT_HAS_VELOCITY;
+        }
+
+        out.push(object.kind as u8);
+        out.push(flags);
+        for x in [
+            object.pos.x,
+            object.pos.y,
+            object.size.x,
+            object.size.y,
+            velocity.x,
+            velocity.y,
+            object.angle,
+        ] {
+            out.write_f32::<LittleEndian>(x).unwrap();
+        }
+    }
+    out.resize(max_objects * OBJECT_RECORD_SIZE, 0);
+}
+
+fn distance2(a: Vector2, b: Vector2) -> f32 {
+    (a.x - b.x).powi(2) + (a.y - b.y).powi(2)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::ReadBytesExt;
+
+    // Same fields as OBJECT_DTYPE in bulletrl_env.py
+    #[derive(Debug, PartialEq)]
+    struct Record {
+        kind: u8,
+        flags: u8,
+        values: [f32; 7],
+    }
+
+    fn decode(objects: &[Object], max_objects: usize) -> Vec<Record> {
+        let mut out = Vec::new();
+        write_objects(objects, max_objects, &mut Vec::new(), &mut out);
+        assert_eq!(out.len(), max_objects * OBJECT_RECORD_SIZE);
+        out.chunks(OBJECT_RECORD_SIZE)
+            .map(|mut r| Record {
+                kind: r.read_u8().unwrap(),
+                flags: r.read_u8().unwrap(),
+                values: [(); 7].map(|_| r.read_f32::<LittleEndian>().unwrap()),
+            })
+            .collect()
+    }
+
+    fn at(kind: Plane, x: f32, y: f32) -> Object {
+        Object::new(kind, Vector2::new(x, y), Vector2::new(2.0, 3.0))
+    }
+
+    #[test]
+    fn order_and_padding() {
+        let objects = [
+            at(Plane::Enemies, 100.0, 0.0),
+            at(Plane::EnemyBullets, 10.0, 10.0).with_velocity(Vector2::new(1.0, -2.0)),
+            at(Plane::Lasers, 50.0, 50.0).with_angle(0.5),
+            at(Plane::Player, 10.0, 20.0),
+            at(Plane::Items, 10.0, 40.0),
+        ];
+
+        let records = decode(&objects, 7);
+        assert_eq!(
+            records[0],
+            Record {
+                kind: Plane::Player as u8,
+                flags: OBJECT_PRESENT,
+                values: [10.0, 20.0, 2.0, 3.0, 0.0, 0.0, 0.0],
+            }
+        );
+        assert_eq!(
+            records[1],
+            Record {
+                kind: Plane::EnemyBullets as u8,
+                flags: OBJECT_PRESENT | OBJECT_HAS_VELOCITY,
+                values: [10.0, 10.0, 2.0, 3.0, 1.0, -2.0, 0.0],
+            }
+        );
+        let kinds = records.iter().map(|x| x.kind).collect::<Vec<_>>();
+        assert_eq!(
+            kinds[..5],
+            [
+                Plane::Player as u8,
+                Plane::EnemyBullets as u8,
+                Plane::Items as u8,
+                Plane::Lasers as u8,
+                Plane::Enemies as u8
+            ]
+        );
+        assert_eq!(records[3].values[6], 0.5);
+        // Padding has the present bit cleared, and everything else zeroed too
+        for record in &records[5..] {
+            assert_eq!(
+                *record,
+                Record {
+                    kind: 0,
+                    flags: 0,
+                    values: [0.0; 7]
+                }
+            );
+        }
+
+        // The farthest ones are dropped, but never the player
+        let kinds = decode(&objects, 2)
+            .iter()
+            .map(|x| x.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, [Plane::Player as u8, Plane::EnemyBullets as u8]);
+    }
+
+    #[test]
+    fn no_player() {
+        // Without a player to measure from, things stay in the order they were drawn
+        let objects = [
+            at(Plane::Enemies, 100.0, 0.0),
+            at(Plane::EnemyBullets, 10.0, 10.0),
+            at(Plane::Items, 10.0, 40.0),
+        ];
+        let kinds = decode(&objects, 2)
+            .iter()
+            .map(|x| x.kind)
+            .collect::<Vec<_>>();
+        assert_eq!(kinds, [Plane::Enemies as u8, Plane::EnemyBullets as u8]);
+    }
+}
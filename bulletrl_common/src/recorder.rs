@@ -0,0 +1,308 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Error, ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use log::{error, info};
+
+use crate::{Input, Renderer};
+
+// Recordings are a directory with an index.bin and any number of chunk_XXXXXX.bin files
+//
+// index.bin: magic, version, width, height, steps per chunk, then one entry per finished chunk:
+//     chunk number (u32), first step (u64), step count (u32), first episode (u32)
+// chunk_XXXXXX.bin: magic, version, width, height, then fixed-size step records:
+//     episode (u32), step in episode (u32), input (u8), reward (f32), flags (u8), observation (width * height u32s)
+//
+// Every record stores the observation *after* its input was applied
+// The first record of an episode has STEP_FIRST set and may be the initial observation with no input
+// Everything is little-endian
+const INDEX_MAGIC: &[u8; 4] = b"BRLI";
+const CHUNK_MAGIC: &[u8; 4] = b"BRLC";
+const RECORDING_VERSION: u16 = 1;
+
+// ~176MB per chunk at 384x448
+const STEPS_PER_CHUNK: u32 = 256;
+
+const STEP_DONE: u8 = 0b00000001;
+const STEP_FIRST: u8 = 0b00000010;
+
+// Recording is best effort, so an I/O error is logged once and the recorder stops instead of taking the game down
+pub struct Recorder {
+    dir: PathBuf,
+    // Of Renderer::buffer, which every recorded step has to match
+    width: usize,
+    height: usize,
+    index: BufWriter<File>,
+    chunk: Option<BufWriter<File>>,
+    chunk_num: u32,
+    chunk_steps: u32,
+    chunk_first_step: u64,
+    chunk_first_episode: u32,
+    total_steps: u64,
+    episode: u32,
+    episode_step: u32,
+    failed: bool,
+}
+
+impl Recorder {
+    pub fn new<P: AsRef<Path>>(dir: P, width: usize, height: usize) -> Result<Self, Error> {
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut index = BufWriter::new(File::create(dir.join("index.bin"))?);
+        index.write_all(INDEX_MAGIC)?;
+        index.write_u16::<LittleEndian>(RECORDING_VERSION)?;
+        index.write_u32::<LittleEndian>(width as u32)?;
+        index.write_u32::<LittleEndian>(height as u32)?;
+        index.write_u32::<LittleEndian>(STEPS_PER_CHUNK)?;
+        index.flush()?;
+
+        info!("Recording to {}", dir.display());
+        Ok(Recorder {
+            dir,
+            width,
+            height,
+            index,
+            chunk: None,
+            chunk_num: 0,
+            chunk_steps: 0,
+            chunk_first_step: 0,
+            chunk_first_episode: 0,
+            total_steps: 0,
+            episode: 0,
+            episode_step: 0,
+            failed: false,
+        })
+    }
+
+    // Recording is opt-in through the BULLETRL_RECORD environment variable so that every backend gets it for free
+    // Steps are recorded at the size of the renderer
+    pub fn from_env(renderer: &Renderer) -> Option<Self> {
+        let dir = std::env::var_os("BULLETRL_RECORD")?;
+        match Recorder::new(&dir, renderer.width(), renderer.height()) {
+            Ok(recorder) => Some(recorder),
+            Err(e) => {
+                error!("Failed to start recording to {:?}: {}", dir, e);
+                None
+            }
+        }
+    }
+
+    // Cuts off the current episode if it didn't finish on its own, e.g. because of a manual reset
+    pub fn end_episode(&mut self) {
+        if self.episode_step != 0 {
+            self.episode += 1;
+            self.episode_step = 0;
+        }
+    }
+
+    // Records the initial observation of a new episode
+    pub fn begin_episode(&mut self, renderer: &Renderer) {
+        self.end_episode();
+        self.record(Input::empty(), 0.0, false, renderer);
+    }
+
+    pub fn record(&mut self, input: Input, reward: f32, done: bool, renderer: &Renderer) {
+        if self.failed {
+            return;
+        }
+        if let Err(e) = self.write_step(input, reward, done, renderer) {
+            error!("Recording failed, no more steps will be recorded: {}", e);
+            self.failed = true;
+        }
+    }
+
+    // Called on drop, but games that exit without unwinding have to call it themselves
+    pub fn finish(&mut self) {
+        if self.failed {
+            return;
+        }
+        if let Err(e) = self.close_chunk() {
+            error!("Failed to finish recording: {}", e);
+            self.failed = true;
+        }
+    }
+
+    fn write_step(
+        &mut self,
+        input: Input,
+        reward: f32,
+        done: bool,
+        renderer: &Renderer,
+    ) -> Result<(), Error> {
+        if (renderer.width(), renderer.height()) != (self.width, self.height) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "recording is {}x{}, but the renderer is {}x{}",
+                    self.width,
+                    self.height,
+                    renderer.width(),
+                    renderer.height()
+                ),
+            ));
+        }
+        if self.chunk.is_none() {
+            self.open_chunk()?;
+        }
+        let chunk = self.chunk.as_mut().unwrap();
+
+        let mut flags = 0;
+        if done {
+            flags |= STEP_DONE;
+        }
+        if self.episode_step == 0 {
+            flags |= STEP_FIRST;
+        }
+
+        chunk.write_u32::<LittleEndian>(self.episode)?;
+        chunk.write_u32::<LittleEndian>(self.episode_step)?;
+        chunk.write_u8(input.bits())?;
+        chunk.write_f32::<LittleEndian>(reward)?;
+        chunk.write_u8(flags)?;
+        chunk.write_all(bytemuck::cast_slice(&renderer.buffer))?;
+
+        self.chunk_steps += 1;
+        self.total_steps += 1;
+        if done {
+            self.episode += 1;
+            self.episode_step = 0;
+        } else {
+            self.episode_step += 1;
+        }
+
+        if self.chunk_steps == STEPS_PER_CHUNK {
+            self.close_chunk()?;
+        }
+        Ok(())
+    }
+
+    fn open_chunk(&mut self) -> Result<(), Error> {
+        let path = self.dir.join(format!("chunk_{:06}.bin", self.chunk_num));
+        let mut chunk = BufWriter::new(File::create(path)?);
+        chunk.write_all(CHUNK_MAGIC)?;
+        chunk.write_u16::<LittleEndian>(RECORDING_VERSION)?;
+        chunk.write_u32::<LittleEndian>(self.width as u32)?;
+        chunk.write_u32::<LittleEndian>(self.height as u32)?;
+
+        self.chunk = Some(chunk);
+        self.chunk_steps = 0;
+        self.chunk_first_step = self.total_steps;
+        self.chunk_first_episode = self.episode;
+        Ok(())
+    }
+
+    // Chunks only show up in the index once they're completely written
+    fn close_chunk(&mut self) -> Result<(), Error> {
+        if let Some(mut chunk) = self.chunk.take() {
+            chunk.flush()?;
+
+            self.index.write_u32::<LittleEndian>(self.chunk_num)?;
+            self.index
+                .write_u64::<LittleEndian>(self.chunk_first_step)?;
+            self.index.write_u32::<LittleEndian>(self.chunk_steps)?;
+            self.index
+                .write_u32::<LittleEndian>(self.chunk_first_episode)?;
+            self.index.flush()?;
+
+            self.chunk_num += 1;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use byteorder::ReadBytesExt;
+    use std::io::Read;
+
+    const WIDTH: usize = 4;
+    const HEIGHT: usize = 2;
+    const RECORD_LEN: usize = 14 + WIDTH * HEIGHT * 4;
+
+    fn header(r: &mut &[u8], magic: &[u8; 4]) {
+        let mut x = [0u8; 4];
+        r.read_exact(&mut x).unwrap();
+        assert_eq!(&x, magic);
+        assert_eq!(r.read_u16::<LittleEndian>().unwrap(), RECORDING_VERSION);
+        assert_eq!(r.read_u32::<LittleEndian>().unwrap(), WIDTH as u32);
+        assert_eq!(r.read_u32::<LittleEndian>().unwrap(), HEIGHT as u32);
+    }
+
+    #[test]
+    fn chunks() {
+        let dir = std::env::temp_dir().join(format!("bulletrl-{}-recording", std::process::id()));
+        let mut renderer = Renderer::new(WIDTH as f32, HEIGHT as f32, WIDTH, HEIGHT);
+        let mut recorder = Recorder::new(&dir, WIDTH, HEIGHT).unwrap();
+
+        // A 100 step episode, then one that's cut off by a reset, then one that runs past the end of the first chunk
+        for i in 0..300 {
+            renderer.buffer.fill(i);
+            if i == 0 || i == 150 {
+                recorder.begin_episode(&renderer);
+            } else {
+                recorder.record(Input::LEFT, i as f32, i == 100, &renderer);
+            }
+        }
+        drop(recorder);
+
+        let index = std::fs::read(dir.join("index.bin")).unwrap();
+        let mut r = &index[..];
+        header(&mut r, INDEX_MAGIC);
+        assert_eq!(r.read_u32::<LittleEndian>().unwrap(), STEPS_PER_CHUNK);
+        for (num, first_step, steps, first_episode) in [(0, 0, 256, 0), (1, 256, 44, 2)] {
+            assert_eq!(r.read_u32::<LittleEndian>().unwrap(), num);
+            assert_eq!(r.read_u64::<LittleEndian>().unwrap(), first_step);
+            assert_eq!(r.read_u32::<LittleEndian>().unwrap(), steps);
+            assert_eq!(r.read_u32::<LittleEndian>().unwrap(), first_episode);
+        }
+        assert!(r.is_empty());
+
+        let mut records = Vec::new();
+        for num in 0..2 {
+            let chunk = std::fs::read(dir.join(format!("chunk_{:06}.bin", num))).unwrap();
+            let mut r = &chunk[..];
+            header(&mut r, CHUNK_MAGIC);
+            assert_eq!(r.len() % RECORD_LEN, 0);
+            records.extend(r.chunks(RECORD_LEN).map(|x| x.to_vec()));
+        }
+        assert_eq!(records.len(), 300);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        for (i, record) in records.iter().enumerate() {
+            let mut r = &record[..];
+            let (episode, episode_step) = match i {
+                0..=100 => (0, i),
+                101..=149 => (1, i - 101),
+                _ => (2, i - 150),
+            };
+            assert_eq!(r.read_u32::<LittleEndian>().unwrap(), episode);
+            assert_eq!(r.read_u32::<LittleEndian>().unwrap() as usize, episode_step);
+            let first = episode_step == 0;
+            let input = r.read_u8().unwrap();
+            let reward = r.read_f32::<LittleEndian>().unwrap();
+            // Episode 1 started right after the previous one was done, without an initial observation
+            if i == 0 || i == 150 {
+                assert_eq!((input, reward), (0, 0.0));
+            } else {
+                assert_eq!((input, reward), (Input::LEFT.bits(), i as f32));
+            }
+            let flags = r.read_u8().unwrap();
+            assert_eq!(flags & STEP_FIRST != 0, first);
+            assert_eq!(flags & STEP_DONE != 0, i == 100);
+            for _ in 0..WIDTH * HEIGHT {
+                assert_eq!(r.read_u32::<LittleEndian>().unwrap(), i as u32);
+            }
+        }
+    }
+}
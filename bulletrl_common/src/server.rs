@@ -0,0 +1,722 @@
+use std::{
+    io::{Error, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    process::{Child, Command as Process},
+    time::{Duration, Instant},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::{info, warn};
+
+use crate::{
+    check_len, read_len, read_sentinel,
+    transport::{Buffered, Transport},
+    Command, EnvFeatures, EnvInfo, Info, Input, ObservationEncoding, ProtocolError, COMMAND_CLOSE,
+    COMMAND_LOAD_STATE, COMMAND_RESET, COMMAND_SAVE_STATE, COMMAND_STEP, MAX_OPTIONS_LEN,
+    MAX_STATE_LEN, OBJECT_RECORD_SIZE, PLANE_COUNT, PROTOCOL_VERSION, STEP_TERMINATED,
+    STEP_TRUNCATED, TCP_SENTINEL,
+};
+
+// What the trainer asks every environment to send, see ObservationEncoding
+#[derive(Clone, Copy, Debug)]
+pub enum ObservationRequest {
+    Rgba32,
+    Planes,
+    // 1 channel for grayscale, 3 for color
+    Downscaled {
+        width: u32,
+        height: u32,
+        channels: u8,
+    },
+    DownscaledPlanes {
+        width: u32,
+        height: u32,
+    },
+    Objects {
+        max_objects: u32,
+    },
+}
+
+impl ObservationRequest {
+    pub fn encoding(self) -> ObservationEncoding {
+        match self {
+            ObservationRequest::Rgba32 => ObservationEncoding::RGBA32,
+            ObservationRequest::Planes => ObservationEncoding::PLANES,
+            ObservationRequest::Downscaled { .. } => {
+                ObservationEncoding::RGBA32 | ObservationEncoding::DOWNSCALED
+            }
+            ObservationRequest::DownscaledPlanes { .. } => {
+                ObservationEncoding::PLANES | ObservationEncoding::DOWNSCALED
+            }
+            ObservationRequest::Objects { .. } => ObservationEncoding::OBJECTS,
+        }
+    }
+
+    // Bytes in every observation from an environment with the given field size
+    pub fn size(self, field_width: u32, field_height: u32) -> usize {
+        let field = field_width as usize * field_height as usize;
+        match self {
+            ObservationRequest::Rgba32 => field * 4,
+            ObservationRequest::Planes => field * PLANE_COUNT,
+            ObservationRequest::Downscaled {
+                width,
+                height,
+                channels,
+            } => width as usize * height as usize * channels as usize,
+            ObservationRequest::DownscaledPlanes { width, height } => {
+                width as usize * height as usize * PLANE_COUNT
+            }
+            ObservationRequest::Objects { max_objects } => {
+                max_objects as usize * OBJECT_RECORD_SIZE
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Observation {
+    // Laid out as described in ObservationEncoding
+    pub data: Vec<u8>,
+    pub reward: f32,
+    pub terminated: bool,
+    pub truncated: bool,
+    pub info: Info,
+}
+
+impl Observation {
+    pub fn done(&self) -> bool {
+        self.terminated || self.truncated
+    }
+}
+
+// The trainer's end of a single EnvClient
+pub struct EnvConnection {
+    stream: Box<dyn Transport>,
+    info: EnvInfo,
+    obv_size: usize,
+}
+
+impl EnvConnection {
+    // Trainer side of EnvClient::handshake
+    pub fn handshake(
+        mut stream: Box<dyn Transport>,
+        request: ObservationRequest,
+    ) -> Result<Self, ProtocolError> {
+        stream.write_u32::<LittleEndian>(TCP_SENTINEL)?;
+        stream.write_u32::<LittleEndian>(PROTOCOL_VERSION)?;
+        stream.flush()?;
+
+        read_sentinel(stream.as_mut())?;
+        let version = stream.read_u32::<LittleEndian>()?;
+        if version != PROTOCOL_VERSION {
+            return Err(ProtocolError::VersionMismatch {
+                env: version,
+                trainer: PROTOCOL_VERSION,
+            });
+        }
+        let game_len = stream.read_u8()? as usize;
+        let mut game = vec![0u8; game_len];
+        stream.read_exact(&mut game)?;
+        let info = EnvInfo {
+            game: String::from_utf8(game)
+                .map_err(|_| ProtocolError::Malformed("game id isn't UTF-8".to_string()))?,
+            field_width: stream.read_u32::<LittleEndian>()?,
+            field_height: stream.read_u32::<LittleEndian>()?,
+            encodings: ObservationEncoding::from_bits_truncate(stream.read_u32::<LittleEndian>()?),
+            inputs: Input::from_bits_truncate(stream.read_u8()?),
+            features: EnvFeatures::from_bits_truncate(stream.read_u32::<LittleEndian>()?),
+            env_count: stream.read_u32::<LittleEndian>()?,
+        };
+
+        let encoding = request.encoding();
+        stream.write_u32::<LittleEndian>(TCP_SENTINEL)?;
+        if !info.encodings.contains(encoding) || info.env_count == 0 {
+            // Lets the environment know it was rejected instead of leaving it waiting
+            stream.write_u32::<LittleEndian>(0)?;
+            stream.flush()?;
+            return Err(ProtocolError::Rejected(format!(
+                "{} doesn't support {:?} (supported {:?})",
+                info.game, encoding, info.encodings
+            )));
+        }
+        stream.write_u32::<LittleEndian>(encoding.bits())?;
+        match request {
+            ObservationRequest::Downscaled {
+                width,
+                height,
+                channels,
+            } => {
+                stream.write_u32::<LittleEndian>(width)?;
+                stream.write_u32::<LittleEndian>(height)?;
+                stream.write_u8(channels)?;
+            }
+            ObservationRequest::DownscaledPlanes { width, height } => {
+                stream.write_u32::<LittleEndian>(width)?;
+                stream.write_u32::<LittleEndian>(height)?;
+                stream.write_u8(PLANE_COUNT as u8)?;
+            }
+            ObservationRequest::Objects { max_objects } => {
+                stream.write_u32::<LittleEndian>(max_objects)?;
+            }
+            ObservationRequest::Rgba32 | ObservationRequest::Planes => {}
+        }
+        stream.flush()?;
+
+        info!(
+            "Connected to {} ({:?}, {} environment(s))",
+            info.game, encoding, info.env_count
+        );
+        Ok(EnvConnection {
+            stream,
+            obv_size: request.size(info.field_width, info.field_height),
+            info,
+        })
+    }
+
+    pub fn info(&self) -> &EnvInfo {
+        &self.info
+    }
+
+    pub fn env_count(&self) -> usize {
+        self.info.env_count as usize
+    }
+
+    // Counterpart to EnvClient::recv_command, nothing is read back
+    pub fn send_command(&mut self, command: &Command) -> Result<(), ProtocolError> {
+        // The environment would turn these down anyway, and then give up on the connection
+        match command {
+            Command::Step(inputs) if inputs.len() != self.env_count() => {
+                return Err(ProtocolError::Malformed(format!(
+                    "{} inputs for {} environments",
+                    inputs.len(),
+                    self.env_count()
+                )));
+            }
+            Command::Reset {
+                options: Some(options),
+                ..
+            } => check_len(options.len(), MAX_OPTIONS_LEN, "reset options")?,
+            Command::LoadState { data, .. } => check_len(data.len(), MAX_STATE_LEN, "state")?,
+            _ => {}
+        }
+
+        self.stream.write_u32::<LittleEndian>(TCP_SENTINEL)?;
+        match command {
+            Command::Step(inputs) => {
+                self.stream.write_u8(COMMAND_STEP)?;
+                for input in inputs {
+                    self.stream.write_u8(input.bits())?;
+                }
+            }
+            Command::Reset { env, seed, options } => {
+                let options = options.as_deref().unwrap_or("");
+                self.stream.write_u8(COMMAND_RESET)?;
+                self.stream.write_u32::<LittleEndian>(*env)?;
+                self.stream.write_u8(seed.is_some() as u8)?;
+                self.stream.write_u64::<LittleEndian>(seed.unwrap_or(0))?;
+                self.stream
+                    .write_u32::<LittleEndian>(options.len() as u32)?;
+                self.stream.write_all(options.as_bytes())?;
+            }
+            Command::Close => self.stream.write_u8(COMMAND_CLOSE)?,
+            Command::SaveState { env } => {
+                self.stream.write_u8(COMMAND_SAVE_STATE)?;
+                self.stream.write_u32::<LittleEndian>(*env)?;
+            }
+            Command::LoadState { env, data } => {
+                self.stream.write_u8(COMMAND_LOAD_STATE)?;
+                self.stream.write_u32::<LittleEndian>(*env)?;
+                self.stream.write_u32::<LittleEndian>(data.len() as u32)?;
+                self.stream.write_all(data)?;
+            }
+        }
+        self.stream.flush()?;
+        Ok(())
+    }
+
+    // Answer to Reset and LoadState
+    pub fn recv_obv(&mut self) -> Result<Observation, ProtocolError> {
+        read_sentinel(self.stream.as_mut())?;
+        self.read_obv()
+    }
+
+    // Answer to Step, one per environment in env id order
+    pub fn recv_obvs(&mut self) -> Result<Vec<Observation>, ProtocolError> {
+        read_sentinel(self.stream.as_mut())?;
+        (0..self.env_count()).map(|_| self.read_obv()).collect()
+    }
+
+    fn read_obv(&mut self) -> Result<Observation, ProtocolError> {
+        let mut data = vec![0u8; self.obv_size];
+        self.stream.read_exact(&mut data)?;
+        let reward = self.stream.read_f32::<LittleEndian>()?;
+        let flags = self.stream.read_u8()?;
+        Ok(Observation {
+            data,
+            reward,
+            terminated: flags & STEP_TERMINATED != 0,
+            truncated: flags & STEP_TRUNCATED != 0,
+            info: Info::read(&mut self.stream)?,
+        })
+    }
+
+    // Answer to Command::SaveState
+    pub fn recv_state(&mut self) -> Result<Vec<u8>, ProtocolError> {
+        read_sentinel(self.stream.as_mut())?;
+        let len = read_len(self.stream.as_mut(), MAX_STATE_LEN, "state")?;
+        let mut data = vec![0u8; len];
+        self.stream.read_exact(&mut data)?;
+        Ok(data)
+    }
+}
+
+// th6 takes a while to get going, but anything longer than this is probably stuck
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(60);
+// How long environments get to exit after Close before they're killed, so one that hangs doesn't hang the trainer too
+const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Runs a pool of environments and steps them all at once, the Rust counterpart to bulletrl_env.py
+// Every step goes out to all of them before any observation is read back, so they run in parallel
+// Environments are numbered across every connection, in the order the connections were made and then by env id
+pub struct EnvServer {
+    connections: Vec<EnvConnection>,
+    children: Vec<Child>,
+}
+
+impl EnvServer {
+    // Starts count copies of cmdline with a port appended, e.g. ["bullettest", "serve", "--port"]
+    // Connections end up in whatever order they were made in
+    pub fn spawn(
+        cmdline: &[String],
+        count: usize,
+        request: ObservationRequest,
+    ) -> Result<Self, ProtocolError> {
+        let (program, args) = cmdline
+            .split_first()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "empty command line"))?;
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let port = listener.local_addr()?.port();
+        let mut server = EnvServer {
+            connections: Vec::with_capacity(count),
+            children: Vec::with_capacity(count),
+        };
+        for _ in 0..count {
+            server.children.push(
+                Process::new(program)
+                    .args(args)
+                    .arg(port.to_string())
+                    .spawn()?,
+            );
+        }
+
+        info!("Waiting for {} environments on port {}", count, port);
+        if let Err(e) = server.accept(&listener, count, request) {
+            // Anything still running would otherwise be waited on forever when the server gets dropped
+            for child in &mut server.children {
+                let _ = child.kill();
+            }
+            return Err(e);
+        }
+
+        Ok(server)
+    }
+
+    fn accept(
+        &mut self,
+        listener: &TcpListener,
+        count: usize,
+        request: ObservationRequest,
+    ) -> Result<(), ProtocolError> {
+        let start = Instant::now();
+        listener.set_nonblocking(true)?;
+        while self.connections.len() < count {
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(false)?;
+                    stream.set_nodelay(true)?;
+                    let stream: Box<dyn Transport> = Box::new(Buffered::<TcpStream>::new(stream));
+                    self.connections
+                        .push(EnvConnection::handshake(stream, request)?);
+                }
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    // Otherwise a crashed environment would leave this waiting forever
+                    for child in &mut self.children {
+                        if let Some(status) = child.try_wait()? {
+                            return Err(ProtocolError::Io(Error::new(
+                                ErrorKind::ConnectionAborted,
+                                format!("environment exited before connecting ({})", status),
+                            )));
+                        }
+                    }
+                    if start.elapsed() > CONNECT_TIMEOUT {
+                        return Err(ProtocolError::TimedOut);
+                    }
+                    std::thread::sleep(Duration::from_millis(10));
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+
+    // For environments that are started some other way, e.g. th6 through an injector
+    pub fn from_connections(connections: Vec<EnvConnection>) -> Self {
+        EnvServer {
+            connections,
+            children: Vec::new(),
+        }
+    }
+
+    // Environments, not connections
+    pub fn len(&self) -> usize {
+        self.connections.iter().map(|x| x.env_count()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn connections(&mut self) -> &mut [EnvConnection] {
+        &mut self.connections
+    }
+
+    // Connection and env id of an environment
+    pub fn locate(&self, index: usize) -> Result<(usize, u32), ProtocolError> {
+        let mut env = index;
+        for (i, connection) in self.connections.iter().enumerate() {
+            if env < connection.env_count() {
+                return Ok((i, env as u32));
+            }
+            env -= connection.env_count();
+        }
+        Err(ProtocolError::Malformed(format!(
+            "environment {} out of range, there are only {}",
+            index,
+            self.len()
+        )))
+    }
+
+    fn check_count(&self, count: usize, what: &str) -> Result<(), ProtocolError> {
+        if count != self.len() {
+            return Err(ProtocolError::Malformed(format!(
+                "{} {} for {} environments",
+                count,
+                what,
+                self.len()
+            )));
+        }
+        Ok(())
+    }
+
+    // One input per environment
+    pub fn step(&mut self, inputs: &[Input]) -> Result<Vec<Observation>, ProtocolError> {
+        self.check_count(inputs.len(), "inputs")?;
+        let mut inputs = inputs;
+        for connection in &mut self.connections {
+            let (batch, rest) = inputs.split_at(connection.env_count());
+            connection.send_command(&Command::Step(batch.to_vec()))?;
+            inputs = rest;
+        }
+
+        let mut obvs = Vec::with_capacity(self.len());
+        for connection in &mut self.connections {
+            obvs.extend(connection.recv_obvs()?);
+        }
+        Ok(obvs)
+    }
+
+    // One seed per environment, None lets the environment pick one
+    pub fn reset(
+        &mut self,
+        seeds: &[Option<u64>],
+        options: Option<&str>,
+    ) -> Result<Vec<Observation>, ProtocolError> {
+        self.check_count(seeds.len(), "seeds")?;
+        let mut seeds = seeds.iter();
+        for connection in &mut self.connections {
+            for env in 0..connection.env_count() as u32 {
+                connection.send_command(&Command::Reset {
+                    env,
+                    seed: *seeds.next().unwrap(),
+                    options: options.map(str::to_string),
+                })?;
+            }
+        }
+
+        let mut obvs = Vec::with_capacity(self.len());
+        for connection in &mut self.connections {
+            for _ in 0..connection.env_count() {
+                obvs.push(connection.recv_obv()?);
+            }
+        }
+        Ok(obvs)
+    }
+
+    // Resets a single environment, e.g. one that just finished an episode
+    pub fn reset_one(
+        &mut self,
+        index: usize,
+        seed: Option<u64>,
+        options: Option<&str>,
+    ) -> Result<Observation, ProtocolError> {
+        let (connection, env) = self.locate(index)?;
+        let connection = &mut self.connections[connection];
+        connection.send_command(&Command::Reset {
+            env,
+            seed,
+            options: options.map(str::to_string),
+        })?;
+        connection.recv_obv()
+    }
+}
+
+impl Drop for EnvServer {
+    fn drop(&mut self) {
+        for connection in &mut self.connections {
+            // It might already be gone, which is fine
+            let _ = connection.send_command(&Command::Close);
+        }
+        let deadline = Instant::now() + EXIT_TIMEOUT;
+        for child in &mut self.children {
+            loop {
+                match child.try_wait() {
+                    Ok(Some(_)) => break,
+                    Ok(None) if Instant::now() < deadline => {
+                        std::thread::sleep(Duration::from_millis(10))
+                    }
+                    Ok(None) => {
+                        warn!("Environment {} didn't exit in time, killing it", child.id());
+                        let _ = child.kill();
+                        let _ = child.wait();
+                        break;
+                    }
+                    Err(e) => {
+                        warn!("Failed to wait for an environment to exit: {}", e);
+                        break;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread::JoinHandle;
+
+    use super::*;
+    use crate::{EnvClient, InfoValue, Renderer};
+
+    // Adds up the inputs it's given, so the trainer can tell which environment got what
+    // Observations are two pixels, the env id and the total, and the total is also the reward
+    fn counter_env(port: u16, env_count: u32) -> Result<(), ProtocolError> {
+        let info = EnvInfo {
+            game: "counter".to_string(),
+            field_width: 2,
+            field_height: 1,
+            encodings: ObservationEncoding::RGBA32,
+            inputs: Input::all(),
+            features: EnvFeatures::SNAPSHOTS,
+            env_count,
+        };
+        let mut client = EnvClient::connect(&port.to_string(), &info)?;
+        let mut encoder = client.encoder();
+        let mut renderer = Renderer::new(2.0, 1.0, 2, 1);
+        let mut totals = vec![0u32; env_count as usize];
+        let mut seeds = vec![0u64; env_count as usize];
+
+        let mut observe = |env: usize, totals: &[u32], seeds: &[u64], out: &mut Vec<u8>| {
+            renderer.buffer[0] = env as u32;
+            renderer.buffer[1] = totals[env];
+            let mut info = Info::new();
+            info.set("seed", seeds[env]);
+            out.clear();
+            encoder.encode(
+                &renderer,
+                totals[env] as f32,
+                totals[env] >= 100,
+                false,
+                &info,
+                out,
+            )
+        };
+        let mut obv = Vec::new();
+        loop {
+            match client.recv_command()? {
+                Command::Step(inputs) => {
+                    let mut obvs = vec![Vec::new(); inputs.len()];
+                    for (env, input) in inputs.iter().enumerate() {
+                        totals[env] += input.bits() as u32;
+                        observe(env, &totals, &seeds, &mut obvs[env])?;
+                    }
+                    client.send_obvs(obvs.iter().map(|x| x.as_slice()))?;
+                }
+                Command::Reset { env, seed, options } => {
+                    let env = env as usize;
+                    seeds[env] = seed.unwrap_or(12345);
+                    totals[env] = options.map(|x| x.len() as u32).unwrap_or(0);
+                    observe(env, &totals, &seeds, &mut obv)?;
+                    client.send_obvs([obv.as_slice()])?;
+                }
+                Command::SaveState { env } => {
+                    client.send_state(&totals[env as usize].to_le_bytes())?;
+                }
+                Command::LoadState { env, data } => {
+                    let env = env as usize;
+                    totals[env] = u32::from_le_bytes(data.try_into().unwrap());
+                    observe(env, &totals, &seeds, &mut obv)?;
+                    client.send_obvs([obv.as_slice()])?;
+                }
+                Command::Close => return Ok(()),
+            }
+        }
+    }
+
+    // One connection per entry, each with that many environments
+    fn loopback(env_counts: &[u32]) -> (EnvServer, Vec<JoinHandle<Result<(), ProtocolError>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let mut connections = Vec::new();
+        let mut threads = Vec::new();
+        for &count in env_counts {
+            threads.push(std::thread::spawn(move || counter_env(port, count)));
+            let (stream, _) = listener.accept().unwrap();
+            let stream: Box<dyn Transport> = Box::new(Buffered::new(stream));
+            connections.push(EnvConnection::handshake(stream, ObservationRequest::Rgba32).unwrap());
+        }
+        (EnvServer::from_connections(connections), threads)
+    }
+
+    fn pixels(obv: &Observation) -> [u32; 2] {
+        let data = &obv.data;
+        [0, 4].map(|i| u32::from_le_bytes(data[i..i + 4].try_into().unwrap()))
+    }
+
+    #[test]
+    fn loopback_server() {
+        let (mut server, threads) = loopback(&[1, 1]);
+        assert_eq!(server.len(), 2);
+        assert_eq!(server.connections()[0].info().game, "counter");
+        assert_eq!(server.connections()[0].info().field_width, 2);
+
+        let obvs = server.reset(&[Some(7), None], None).unwrap();
+        assert_eq!(obvs.len(), 2);
+        assert_eq!(obvs[0].info.get("seed"), Some(&InfoValue::UInt(7)));
+        assert_eq!(obvs[1].info.get("seed"), Some(&InfoValue::UInt(12345)));
+        assert!(obvs.iter().all(|x| x.data.len() == 8 && x.reward == 0.0));
+
+        // Batched across both connections, in order
+        for _ in 0..2 {
+            let obvs = server.step(&[Input::UP, Input::FOCUS]).unwrap();
+            assert_eq!(obvs.len(), 2);
+        }
+        let obvs = server.step(&[Input::empty(), Input::empty()]).unwrap();
+        assert_eq!(pixels(&obvs[0]), [0, 2]);
+        assert_eq!(pixels(&obvs[1]), [0, 32]);
+        assert_eq!(obvs[1].reward, 32.0);
+        assert!(!obvs[0].done());
+
+        let obvs = server.reset(&[None, Some(3)], Some("abcd")).unwrap();
+        assert_eq!(obvs[1].info.get("seed"), Some(&InfoValue::UInt(3)));
+        assert_eq!(obvs[1].reward, 4.0);
+        let obv = server.reset_one(0, Some(1), None).unwrap();
+        assert_eq!(obv.reward, 0.0);
+
+        // Dropping the server closes every environment
+        drop(server);
+        for thread in threads {
+            thread.join().unwrap().unwrap();
+        }
+    }
+
+    #[test]
+    fn multiplexed_connections() {
+        let (mut server, threads) = loopback(&[3, 2]);
+        assert_eq!(server.len(), 5);
+        assert_eq!(server.connections()[0].env_count(), 3);
+        assert_eq!(server.locate(2).unwrap(), (0, 2));
+        assert_eq!(server.locate(3).unwrap(), (1, 0));
+        assert_eq!(server.locate(4).unwrap(), (1, 1));
+
+        let seeds = (0..5).map(|x| Some(x * 10)).collect::<Vec<_>>();
+        let obvs = server.reset(&seeds, None).unwrap();
+        let ids = obvs.iter().map(|x| pixels(x)[0]).collect::<Vec<_>>();
+        assert_eq!(ids, [0, 1, 2, 0, 1]);
+        for (obv, seed) in obvs.iter().zip(&seeds) {
+            assert_eq!(obv.info.get("seed"), Some(&InfoValue::UInt(seed.unwrap())));
+        }
+
+        // Every environment only gets its own input, and replies come back in the same order
+        let inputs = [
+            Input::UP,
+            Input::DOWN,
+            Input::LEFT,
+            Input::RIGHT,
+            Input::FOCUS,
+        ];
+        let obvs = server.step(&inputs).unwrap();
+        let totals = obvs.iter().map(|x| pixels(x)[1]).collect::<Vec<_>>();
+        assert_eq!(totals, [1, 2, 4, 8, 16]);
+        let ids = obvs.iter().map(|x| pixels(x)[0]).collect::<Vec<_>>();
+        assert_eq!(ids, [0, 1, 2, 0, 1]);
+
+        // Snapshots of one environment don't touch the others on the same connection
+        let connection = &mut server.connections()[0];
+        connection
+            .send_command(&Command::SaveState { env: 1 })
+            .unwrap();
+        let state = connection.recv_state().unwrap();
+        server.step(&inputs).unwrap();
+        let connection = &mut server.connections()[0];
+        connection
+            .send_command(&Command::LoadState {
+                env: 1,
+                data: state,
+            })
+            .unwrap();
+        let obv = connection.recv_obv().unwrap();
+        assert_eq!(pixels(&obv), [1, 2]);
+        let obvs = server.step(&[Input::empty(); 5]).unwrap();
+        let totals = obvs.iter().map(|x| pixels(x)[1]).collect::<Vec<_>>();
+        assert_eq!(totals, [2, 2, 8, 16, 32]);
+
+        // Resetting one environment in the middle of the second connection
+        let obv = server.reset_one(4, Some(99), Some("xy")).unwrap();
+        assert_eq!(pixels(&obv), [1, 2]);
+        assert_eq!(obv.info.get("seed"), Some(&InfoValue::UInt(99)));
+        let obvs = server.step(&[Input::empty(); 5]).unwrap();
+        let totals = obvs.iter().map(|x| pixels(x)[1]).collect::<Vec<_>>();
+        assert_eq!(totals, [2, 2, 8, 16, 2]);
+
+        // Bad requests never reach the environment, so the connection is still fine afterwards
+        let connection = &mut server.connections()[1];
+        assert!(connection
+            .send_command(&Command::Step(vec![Input::UP; 3]))
+            .is_err());
+        assert!(matches!(server.locate(5), Err(ProtocolError::Malformed(_))));
+        assert!(server.reset_one(5, None, None).is_err());
+        assert!(server.step(&[Input::UP; 4]).is_err());
+        assert!(server.reset(&[None; 6], None).is_err());
+        assert!(server
+            .reset_one(0, None, Some(&"x".repeat(MAX_OPTIONS_LEN + 1)))
+            .is_err());
+        assert_eq!(server.step(&[Input::empty(); 5]).unwrap().len(), 5);
+
+        drop(server);
+        for thread in threads {
+            thread.join().unwrap().unwrap();
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn hung_environments_are_killed() {
+        let server = EnvServer {
+            connections: Vec::new(),
+            children: vec![Process::new("sleep").arg("60").spawn().unwrap()],
+        };
+        let start = Instant::now();
+        drop(server);
+        assert!(start.elapsed() < EXIT_TIMEOUT + Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,114 @@
+use std::sync::OnceLock;
+
+use log::error;
+
+use crate::PLANE_COUNT;
+
+// How big things are drawn in Renderer::buffer and classes compared to their hitboxes
+// Enlarging keeps small things visible after downscaling, but hides where the hitbox actually is
+// Renderer::objects always has the real hitboxes
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RenderMode {
+    // Exactly the hitbox
+    Hitbox,
+    // Enlarged by the plane's factor in RenderStyle::scale
+    Scaled,
+    // Enlarged like Scaled, with the hitbox drawn inside it at RenderStyle::core_intensity
+    Dual,
+}
+
+// What something looks like once it's enlarged, the hitbox itself is always a rectangle
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Body {
+    Rect,
+    Ellipse,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RenderStyle {
+    pub mode: RenderMode,
+    // Indexed by Plane, hitbox widths and heights are multiplied by this outside of Hitbox mode
+    pub scale: [f32; PLANE_COUNT],
+    // Fraction of the plane's color that Dual mode draws hitboxes with
+    // Only buffer can tell the core apart, classes has the whole enlarged body
+    pub core_intensity: f32,
+}
+
+// Names used by with_overrides, in the order of Plane
+const PLANE_NAMES: [&str; PLANE_COUNT] = ["player", "enemy_bullets", "lasers", "enemies", "items"];
+
+impl Default for RenderStyle {
+    fn default() -> Self {
+        RenderStyle {
+            mode: RenderMode::Hitbox,
+            scale: [1.0; PLANE_COUNT],
+            core_intensity: 0.5,
+        }
+    }
+}
+
+impl RenderStyle {
+    // Applies something like "dual,player=8,enemy_bullets=2,core=0.25" on top of this style
+    // Every part is optional, e.g. "hitbox" only changes the mode
+    pub fn with_overrides(mut self, spec: &str) -> Result<Self, String> {
+        for part in spec.split(',').map(str::trim).filter(|x| !x.is_empty()) {
+            let (key, value) = match part.split_once('=') {
+                Some(x) => x,
+                None => {
+                    self.mode = match part {
+                        "hitbox" => RenderMode::Hitbox,
+                        "scaled" => RenderMode::Scaled,
+                        "dual" => RenderMode::Dual,
+                        _ => return Err(format!("unknown render mode {}", part)),
+                    };
+                    continue;
+                }
+            };
+            let value = value
+                .trim()
+                .parse::<f32>()
+                .map_err(|e| format!("bad value for {}: {}", key, e))?;
+            match key.trim() {
+                "core" if (0.0..=1.0).contains(&value) => self.core_intensity = value,
+                "core" => return Err("core has to be between 0 and 1".to_string()),
+                key => {
+                    let plane = PLANE_NAMES
+                        .iter()
+                        .position(|&x| x == key)
+                        .ok_or_else(|| format!("unknown plane {}", key))?;
+                    if !(value.is_finite() && value > 0.0) {
+                        return Err(format!("{} has to be above 0", key));
+                    }
+                    self.scale[plane] = value;
+                }
+            }
+        }
+        Ok(self)
+    }
+
+    // This style with the BULLETRL_RENDER_STYLE environment variable applied on top, if there is one
+    pub fn with_env_overrides(self) -> Self {
+        match style_from_env() {
+            Some(spec) => self
+                .with_overrides(spec)
+                .expect("already checked by style_from_env"),
+            None => self,
+        }
+    }
+}
+
+// Checked once, every RenderStyle::with_env_overrides in the process gets the same overrides
+fn style_from_env() -> Option<&'static str> {
+    static RENDER_STYLE: OnceLock<Option<String>> = OnceLock::new();
+    RENDER_STYLE
+        .get_or_init(|| {
+            let spec = std::env::var("BULLETRL_RENDER_STYLE").ok()?;
+            // Whether it parses doesn't depend on what it's applied to
+            if let Err(e) = RenderStyle::default().with_overrides(&spec) {
+                error!("Ignoring BULLETRL_RENDER_STYLE={} ({})", spec, e);
+                return None;
+            }
+            Some(spec)
+        })
+        .as_deref()
+}
@@ -0,0 +1,344 @@
+use std::{
+    fs::OpenOptions,
+    io::{Error, ErrorKind, Read, Write},
+    net::TcpStream,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use memmap2::MmapMut;
+
+// Anything EnvClient can talk to the trainer over
+// Writes can be buffered until flush, which EnvClient calls after every message
+pub trait Transport: Read + Write + Send {}
+
+impl<T: Read + Write + Send> Transport for T {}
+
+// Connects to whatever the trainer is listening on:
+//     tcp://host:port, or just a port for 127.0.0.1
+//     unix:///path/to/socket (Unix only)
+//     shm:///path/to/file?control=<uri>, see ShmTransport
+pub fn connect(uri: &str) -> Result<Box<dyn Transport>, Error> {
+    if let Ok(port) = uri.parse::<u16>() {
+        return Ok(Box::new(Buffered::new(tcp(&format!(
+            "127.0.0.1:{}",
+            port
+        ))?)));
+    }
+
+    match uri.split_once("://") {
+        Some(("tcp", addr)) => Ok(Box::new(Buffered::new(tcp(addr)?))),
+        #[cfg(unix)]
+        Some(("unix", path)) => Ok(Box::new(Buffered::new(
+            std::os::unix::net::UnixStream::connect(path)?,
+        ))),
+        #[cfg(not(unix))]
+        Some(("unix", _)) => Err(Error::new(
+            ErrorKind::Unsupported,
+            "Unix domain sockets aren't supported on this platform",
+        )),
+        Some(("shm", rest)) => {
+            let (path, control) = rest.split_once("?control=").ok_or_else(|| {
+                Error::new(ErrorKind::InvalidInput, "shm transport needs a control uri")
+            })?;
+            Ok(Box::new(ShmTransport::open(path, connect(control)?)?))
+        }
+        _ => Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("unknown transport uri {}", uri),
+        )),
+    }
+}
+
+fn tcp(addr: &str) -> Result<TcpStream, Error> {
+    let stream = TcpStream::connect(addr)?;
+    // Messages are written in lots of small pieces, so don't let Nagle hold back the last one
+    stream.set_nodelay(true)?;
+    Ok(stream)
+}
+
+// Holds writes until flush so that a message goes out in one piece instead of one syscall per field
+pub(crate) struct Buffered<T> {
+    inner: T,
+    buf: Vec<u8>,
+}
+
+impl<T> Buffered<T> {
+    pub(crate) fn new(inner: T) -> Self {
+        Buffered {
+            inner,
+            buf: Vec::new(),
+        }
+    }
+}
+
+impl<T: Read> Read for Buffered<T> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        self.inner.read(buf)
+    }
+}
+
+impl<T: Write> Write for Buffered<T> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        self.buf.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        self.inner.write_all(&self.buf)?;
+        self.buf.clear();
+        self.inner.flush()
+    }
+}
+
+// Two single-producer single-consumer byte rings in a file created by the trainer, one for each direction
+// Bytes go through the mapping, and the control connection only carries how many were just written (u32) so that
+// the other side can block on it instead of spinning
+// Whoever reads publishes how far it got in the header so the writer knows how much room is left
+//
+// EnvClient::send_obv encodes observations straight into the ring, so the renderer's buffer (or the downscaled
+// version of it) goes into shared memory once and nothing else, no buffering and no kernel on the way
+// Batched replies from EnvClient::send_obvs are encoded on the environments' own threads first, then copied in
+//
+// Layout, little-endian:
+//     magic (4 bytes), version (u32), capacity of each ring (u64),
+//     bytes read from the trainer -> env ring (u64), bytes read from the env -> trainer ring (u64),
+//     padding up to SHM_HEADER_SIZE, trainer -> env ring, env -> trainer ring
+const SHM_MAGIC: &[u8; 4] = b"BRLS";
+const SHM_VERSION: u32 = 1;
+const SHM_HEADER_SIZE: usize = 64;
+const SHM_TO_ENV_READ: usize = 16;
+const SHM_TO_TRAINER_READ: usize = 24;
+
+pub struct ShmTransport {
+    // Never sliced, since the trainer writes to it at the same time
+    // Everything goes through base instead, and the read counters only through atomics
+    _map: MmapMut,
+    base: *mut u8,
+    control: Box<dyn Transport>,
+    capacity: u64,
+    // Offsets of the ring this side reads and the one it writes, and of their read counters
+    in_ring: usize,
+    out_ring: usize,
+    in_read: usize,
+    out_read: usize,
+    // Totals since the start, which wrap around the ring
+    read: u64,
+    readable: u64,
+    written: u64,
+    unannounced: u64,
+}
+
+// base points into _map, which moves along with it
+unsafe impl Send for ShmTransport {}
+
+impl ShmTransport {
+    pub fn open(path: &str, control: Box<dyn Transport>) -> Result<Self, Error> {
+        Self::open_side(path, control, false)
+    }
+
+    // The trainer side reads what the environment writes and the other way around, only the tests need it
+    fn open_side(path: &str, control: Box<dyn Transport>, trainer: bool) -> Result<Self, Error> {
+        let file = OpenOptions::new().read(true).write(true).open(path)?;
+        // Safe as long as only the trainer and this environment touch the file
+        let mut map = unsafe { MmapMut::map_mut(&file)? };
+        let base = map.as_mut_ptr();
+
+        let mut header = [0u8; 16];
+        if map.len() >= SHM_HEADER_SIZE {
+            // Written once by the trainer before the environment was started
+            unsafe { std::ptr::copy_nonoverlapping(base, header.as_mut_ptr(), header.len()) };
+        }
+        if map.len() < SHM_HEADER_SIZE || &header[..4] != SHM_MAGIC {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{} isn't a bulletrl shared memory file", path),
+            ));
+        }
+        let mut header = &header[4..];
+        let version = header.read_u32::<LittleEndian>()?;
+        let capacity = header.read_u64::<LittleEndian>()?;
+        if version != SHM_VERSION
+            || capacity == 0
+            || (map.len() as u64) < SHM_HEADER_SIZE as u64 + capacity * 2
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is from a different version or truncated", path),
+            ));
+        }
+
+        let to_env = (SHM_HEADER_SIZE, SHM_TO_ENV_READ);
+        let to_trainer = (SHM_HEADER_SIZE + capacity as usize, SHM_TO_TRAINER_READ);
+        let ((in_ring, in_read), (out_ring, out_read)) = if trainer {
+            (to_trainer, to_env)
+        } else {
+            (to_env, to_trainer)
+        };
+        Ok(ShmTransport {
+            _map: map,
+            base,
+            control,
+            capacity,
+            in_ring,
+            out_ring,
+            in_read,
+            out_read,
+            read: 0,
+            readable: 0,
+            written: 0,
+            unannounced: 0,
+        })
+    }
+
+    fn counter(&self, offset: usize) -> &AtomicU64 {
+        // The mapping is page-aligned and the counters are 8-byte aligned within it
+        unsafe { &*(self.base.add(offset) as *const AtomicU64) }
+    }
+}
+
+impl Read for ShmTransport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.read == self.readable {
+            self.readable += self.control.read_u32::<LittleEndian>()? as u64;
+        }
+
+        let start = (self.read % self.capacity) as usize;
+        let len = buf
+            .len()
+            .min((self.readable - self.read) as usize)
+            .min(self.capacity as usize - start);
+        // The writer was done with these bytes before announcing them, and won't touch them until read is published
+        unsafe {
+            std::ptr::copy_nonoverlapping(
+                self.base.add(self.in_ring + start),
+                buf.as_mut_ptr(),
+                len,
+            )
+        };
+        self.read += len as u64;
+        self.counter(self.in_read)
+            .store(self.read, Ordering::Release);
+        Ok(len)
+    }
+}
+
+impl Write for ShmTransport {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Error> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        // Only waits if a single message is bigger than the ring, in which case the trainer is already reading it
+        let mut free;
+        loop {
+            let peer_read = self.counter(self.out_read).load(Ordering::Acquire);
+            free = self.capacity - (self.written - peer_read);
+            if free > 0 {
+                break;
+            }
+            self.flush()?;
+            std::thread::yield_now();
+        }
+
+        let start = (self.written % self.capacity) as usize;
+        let len = buf
+            .len()
+            .min(free as usize)
+            .min(self.capacity as usize - start);
+        // The reader is past these bytes, and won't look at them again until they're announced
+        unsafe {
+            std::ptr::copy_nonoverlapping(buf.as_ptr(), self.base.add(self.out_ring + start), len)
+        };
+        self.written += len as u64;
+        self.unannounced += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        while self.unannounced > 0 {
+            let len = self.unannounced.min(u32::MAX as u64);
+            self.control.write_u32::<LittleEndian>(len as u32)?;
+            self.unannounced -= len;
+        }
+        self.control.flush()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::os::unix::net::{UnixListener, UnixStream};
+
+    use super::*;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("bulletrl-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_file(&path);
+        path.to_string_lossy().to_string()
+    }
+
+    // Sends messages back and forth, including ones much bigger than a shared memory ring
+    fn round_trip(mut env: impl Read + Write + Send + 'static, mut trainer: impl Read + Write) {
+        let echo = std::thread::spawn(move || {
+            for _ in 0..3 {
+                let len = env.read_u32::<LittleEndian>().unwrap();
+                let mut message = vec![0u8; len as usize];
+                env.read_exact(&mut message).unwrap();
+                message.reverse();
+                env.write_u32::<LittleEndian>(len).unwrap();
+                env.write_all(&message).unwrap();
+                env.flush().unwrap();
+            }
+        });
+
+        for len in [5usize, 1000, 4096] {
+            let message = (0..len).map(|x| (x * 7) as u8).collect::<Vec<_>>();
+            trainer.write_u32::<LittleEndian>(len as u32).unwrap();
+            trainer.write_all(&message).unwrap();
+            trainer.flush().unwrap();
+
+            assert_eq!(trainer.read_u32::<LittleEndian>().unwrap() as usize, len);
+            let mut reply = vec![0u8; len];
+            trainer.read_exact(&mut reply).unwrap();
+            reply.reverse();
+            assert_eq!(reply, message);
+        }
+        echo.join().unwrap();
+    }
+
+    #[test]
+    fn unix_round_trip() {
+        let path = temp_path("unix.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        let env = connect(&format!("unix://{}", path)).unwrap();
+        let (trainer, _) = listener.accept().unwrap();
+        round_trip(env, trainer);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn shm_round_trip() {
+        let path = temp_path("ring.shm");
+        let capacity = 256u64;
+        let mut file = Vec::new();
+        file.extend_from_slice(SHM_MAGIC);
+        file.write_u32::<LittleEndian>(SHM_VERSION).unwrap();
+        file.write_u64::<LittleEndian>(capacity).unwrap();
+        file.resize(SHM_HEADER_SIZE + capacity as usize * 2, 0);
+        std::fs::write(&path, &file).unwrap();
+
+        let (env, trainer) = UnixStream::pair().unwrap();
+        let env = ShmTransport::open(&path, Box::new(env)).unwrap();
+        let trainer = ShmTransport::open_side(&path, Box::new(trainer), true).unwrap();
+        round_trip(env, trainer);
+
+        // Anything else is turned away
+        std::fs::write(&path, [0u8; 100]).unwrap();
+        let (control, _) = UnixStream::pair().unwrap();
+        assert!(ShmTransport::open(&path, Box::new(control)).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
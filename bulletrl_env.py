@@ -1,16 +1,44 @@
+import json
+import mmap
 import os
+import shutil
 import socket
 import struct
 import subprocess
+import tempfile
+import time
 import gymnasium as gym
 import numpy as np
 import cv2
+from stable_baselines3.common.vec_env import VecEnv
 
-WIDTH = 384
-HEIGHT = 448
 SCALED_WIDTH = 84
 SCALED_HEIGHT = 84
+# 1 for grayscale, 3 for color
+SCALED_CHANNELS = 3
+# "rgb": colored pixels
+# "planes": one plane per object type (player, enemy bullets, lasers, enemies, items) so overlapping objects stay
+#           visible, SCALED_CHANNELS is ignored
+# "objects": a (MAX_OBJECTS, OBJECT_FEATURES) float32 array of exact positions, see process_objects
+OBSERVATION_MODE = "rgb"
+PLANE_COUNT = 5
+MAX_OBJECTS = 256
+OBJECT_FEATURES = 10
 RENDER_SCALE = 4
+# (width, height) games draw at instead of one pixel per unit of their field, e.g. (96, 112) for a quarter of Touhou's
+# 384x448, None to leave it up to the game
+# Downscaled observations come out at SCALED_WIDTH x SCALED_HEIGHT either way, but are much cheaper from a smaller render
+FIELD_RENDER_SIZE = None
+# How big things are drawn compared to their hitboxes, None to leave it up to the game (which enlarges the player and
+# bullets), otherwise e.g. "hitbox", "scaled,player=8,enemy_bullets=2" or "dual,core=0.25"
+# "dual" draws the hitbox at a fraction of the color inside the enlarged body, so it only shows up in "rgb"
+# See bulletrl_common::RenderStyle::with_overrides
+FIELD_RENDER_STYLE = None
+# "tcp": works everywhere, including a game running on another machine
+# "unix": Unix domain socket, skips the TCP stack (not on Windows)
+# "shm": observations go through a shared memory file and only their sizes go over TCP, see ShmConnection
+TRANSPORT = "tcp"
+SHM_CAPACITY = 4 * 1024 * 1024
 
 INPUT_UP    = 0b00000001
 INPUT_DOWN  = 0b00000010
@@ -21,72 +49,416 @@ INPUT_FOCUS = 0b00010000
 # Since I'm doing manual TCP communication, it's possible that it might desync due to a programming error
 # This should catch that
 TCP_SENTINEL = 0x1337BEEF
+# Sent instead of TCP_SENTINEL by an environment that's about to give up, followed by why
+ERROR_SENTINEL = 0xDEADBEEF
 
+# Has to match bulletrl_common::PROTOCOL_VERSION
+PROTOCOL_VERSION = 11
 
-def process_image(img):
-    img = np.frombuffer(img, dtype=np.uint8).reshape((HEIGHT, WIDTH, 4))  # 1D to 3D
+COMMAND_STEP = 0
+COMMAND_RESET = 1
+COMMAND_CLOSE = 2
+COMMAND_SAVE_STATE = 3
+COMMAND_LOAD_STATE = 4
+
+FEATURE_SNAPSHOTS = 0b00000001
+
+ENCODING_RGBA32 = 0b00000001
+ENCODING_DOWNSCALED = 0b00000010
+ENCODING_PLANES = 0b00000100
+ENCODING_OBJECTS = 0b00001000
+
+STEP_TERMINATED = 0b00000001
+STEP_TRUNCATED = 0b00000010
+
+INFO_INT = 0
+INFO_FLOAT = 1
+INFO_BOOL = 2
+INFO_STR = 3
+INFO_UINT = 4
+
+# Has to match bulletrl_common::ShmTransport
+SHM_MAGIC = b"BRLS"
+SHM_VERSION = 1
+SHM_HEADER_SIZE = 64
+SHM_TO_ENV_READ = 16
+SHM_TO_TRAINER_READ = 24
+
+OBJECT_PRESENT = 0b00000001
+OBJECT_HAS_VELOCITY = 0b00000010
+# Has to match bulletrl_common::Object's wire format
+OBJECT_DTYPE = np.dtype(
+    [("kind", "u1"), ("flags", "u1"), ("pos", "<f4", 2), ("size", "<f4", 2), ("velocity", "<f4", 2), ("angle", "<f4")]
+)
+
+
+def observation_channels():
+    return PLANE_COUNT if OBSERVATION_MODE == "planes" else SCALED_CHANNELS
+
+
+def process_objects(data):
+    # Columns: present, kind (index into bulletrl_common::Plane), has velocity, x, y, width, height,
+    # velocity x, velocity y, angle
+    # The player is always the first row, the rest go from nearest to farthest, and missing rows are all zero
+    objects = np.frombuffer(data, dtype=OBJECT_DTYPE)
+    return np.concatenate(
+        [
+            (objects["flags"] & OBJECT_PRESENT != 0)[:, np.newaxis],
+            objects["kind"][:, np.newaxis],
+            (objects["flags"] & OBJECT_HAS_VELOCITY != 0)[:, np.newaxis],
+            objects["pos"],
+            objects["size"],
+            objects["velocity"],
+            objects["angle"][:, np.newaxis],
+        ],
+        axis=1,
+        dtype=np.float32,
+    )
+
+
+def process_planes(img, width, height):
+    img = np.frombuffer(img, dtype=np.uint8).reshape((PLANE_COUNT, height, width))
+    img = np.transpose(img, (1, 2, 0))  # (C, H, W) to (H, W, C), since that's what OpenCV resizes
+    img = cv2.resize(img, (SCALED_WIDTH, SCALED_HEIGHT), interpolation=cv2.INTER_AREA)
+    img = np.transpose(img, (2, 0, 1))  # (H, W, C) to (C, H, W)
+    return img
+
+
+def process_image(img, width, height):
+    img = np.frombuffer(img, dtype=np.uint8).reshape((height, width, 4))  # 1D to 3D
     img = img[:, :, :3]  # Remove alpha
     img = cv2.resize(
-        img, (SCALED_HEIGHT, SCALED_WIDTH), interpolation=cv2.INTER_LINEAR
-    )  # Scale
+        img, (SCALED_WIDTH, SCALED_HEIGHT), interpolation=cv2.INTER_AREA
+    )  # Scale, the same way the environment does with ENCODING_DOWNSCALED
+    if SCALED_CHANNELS == 1:
+        return cv2.cvtColor(img, cv2.COLOR_BGR2GRAY)[np.newaxis]
     img = np.transpose(img, (2, 0, 1))  # (H, W, C) to (C, H, W)
     return img
 
 
+def read_recording(path):
+    """Yields (episode, step, input, reward, done, first, observation) from a bulletrl_common::Recorder directory"""
+    with open(os.path.join(path, "index.bin"), "rb") as f:
+        magic, _version, width, height, _steps_per_chunk = struct.unpack("<4sHIII", f.read(18))
+        if magic != b"BRLI":
+            raise Exception(f"{path} isn't a bulletrl recording")
+        chunks = []
+        while entry := f.read(20):
+            chunks.append(struct.unpack("<IQII", entry)[0])
+
+    record_size = 14 + width * height * 4
+    for chunk in chunks:
+        with open(os.path.join(path, f"chunk_{chunk:06}.bin"), "rb") as f:
+            f.read(14)  # Header, same as the index
+            while record := f.read(record_size):
+                episode, step, input, reward, flags = struct.unpack("<IIBfB", record[:14])
+                yield (
+                    episode,
+                    step,
+                    input,
+                    reward,
+                    flags & 0b01 != 0,
+                    flags & 0b10 != 0,
+                    process_image(record[14:], width, height),
+                )
+
+
+def flatten_options(options, prefix=""):
+    for k, v in options.items():
+        if isinstance(v, dict):
+            yield from flatten_options(v, f"{prefix}{k}.")
+        else:
+            yield f"{prefix}{k}", v
+
+
+# Anything that went wrong talking to an environment, see bulletrl_common::ProtocolError
+class ProtocolError(Exception):
+    pass
+
+
+# Socket-like wrapper around the two rings in a shared memory file, with conn carrying how many bytes were written
+class ShmConnection:
+    def __init__(self, path, conn):
+        self.file = open(path, "r+b")
+        self.map = mmap.mmap(self.file.fileno(), 0)
+        self.conn = conn
+        self.capacity = struct.unpack_from("<Q", self.map, 8)[0]
+        self.read = 0
+        self.readable = 0
+        self.written = 0
+
+    @staticmethod
+    def create(path):
+        with open(path, "wb") as f:
+            f.write(struct.pack("<4sIQQQ", SHM_MAGIC, SHM_VERSION, SHM_CAPACITY, 0, 0))
+            f.truncate(SHM_HEADER_SIZE + SHM_CAPACITY * 2)
+
+    def sendall(self, data):
+        data = memoryview(data)
+        while len(data) > 0:
+            free = self.capacity - (self.written - struct.unpack_from("<Q", self.map, SHM_TO_ENV_READ)[0])
+            if free == 0:
+                time.sleep(0)
+                continue
+            start = self.written % self.capacity
+            size = min(len(data), free, self.capacity - start)
+            self.map[SHM_HEADER_SIZE + start : SHM_HEADER_SIZE + start + size] = data[:size]
+            self.written += size
+            self.conn.sendall(struct.pack("<I", size))
+            data = data[size:]
+
+    # flags (i.e. MSG_WAITALL) are ignored, recvfull already loops until it has everything
+    def recv(self, size, flags=0):
+        if self.read == self.readable:
+            announced = b""
+            while len(announced) != 4:
+                chunk = self.conn.recv(4 - len(announced))
+                if not chunk:
+                    return b""
+                announced += chunk
+            self.readable += struct.unpack("<I", announced)[0]
+        start = self.read % self.capacity
+        size = min(size, self.readable - self.read, self.capacity - start)
+        ring = SHM_HEADER_SIZE + self.capacity
+        data = self.map[ring + start : ring + start + size]
+        self.read += size
+        struct.pack_into("<Q", self.map, SHM_TO_TRAINER_READ, self.read)
+        return data
+
+    def close(self):
+        self.conn.close()
+        self.map.close()
+        self.file.close()
+
+
 class BulletRLEnv(gym.Env):
     metadata = {"render.modes": ["human"]}
+    # Environments on the connection, anything but 1 is only usable through a VecEnv like BulletTestVecEnv
+    env_count = 1
 
     def __init__(self) -> None:
         if self.cmdline_base is None:
             raise Exception("You shouldn't directly construct a BulletRLEnv")
 
-        self.stepped_once = False
         #self.action_space = gym.spaces.MultiDiscrete(
         #    [2, 2, 2, 2, 2]
         #)  # up down left right focus
         self.action_space = gym.spaces.Discrete(32)
-        self.observation_space = gym.spaces.Box(
-            low=0, high=255, dtype=np.uint8, shape=(3, SCALED_HEIGHT, SCALED_WIDTH)
-        )
+        if OBSERVATION_MODE == "objects":
+            self.observation_space = gym.spaces.Box(
+                low=-np.inf, high=np.inf, dtype=np.float32, shape=(MAX_OBJECTS, OBJECT_FEATURES)
+            )
+        else:
+            self.observation_space = gym.spaces.Box(
+                low=0, high=255, dtype=np.uint8, shape=(observation_channels(), SCALED_HEIGHT, SCALED_WIDTH)
+            )
         self.obv = None
         self.screen = None
 
-        self.socket = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
-        self.socket.bind(("", 0))
-        port = self.socket.getsockname()[1]
+        # Sockets and shared memory files live here
+        self.tempdir = tempfile.mkdtemp(dir="/dev/shm" if os.path.isdir("/dev/shm") else None)
+        uri = self.listen()
 
-        subprocess.Popen(self.cmdline_base + [str(port)])
+        env = os.environ.copy()
+        if FIELD_RENDER_SIZE is not None:
+            # See bulletrl_common::Renderer::from_env
+            env["BULLETRL_RENDER_SIZE"] = "{}x{}".format(*FIELD_RENDER_SIZE)
+        if FIELD_RENDER_STYLE is not None:
+            # See bulletrl_common::RenderStyle::with_env_overrides
+            env["BULLETRL_RENDER_STYLE"] = FIELD_RENDER_STYLE
+        process = subprocess.Popen(self.cmdline_base + [uri], env=env)
         print("Waiting for client...")
-        self.socket.listen(1)
-        (self.conn, _) = self.socket.accept()
+        # Don't wait forever on an environment that crashed before connecting
+        self.socket.settimeout(1.0)
+        while True:
+            try:
+                (self.conn, _) = self.socket.accept()
+                break
+            except socket.timeout:
+                if process.poll() is not None:
+                    raise ProtocolError(f"Environment exited with code {process.returncode} before connecting")
+        self.conn.settimeout(None)
+        if TRANSPORT == "shm":
+            self.conn = ShmConnection(self.shm_path, self.conn)
+        self.handshake()
 
         print("Init done")
 
+    # Returns the uri the environment should connect to, see bulletrl_common::connect
+    def listen(self):
+        if TRANSPORT == "unix":
+            path = os.path.join(self.tempdir, "bulletrl.sock")
+            self.socket = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+            self.socket.bind(path)
+            self.socket.listen(1)
+            return f"unix://{path}"
+
+        self.socket = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+        self.socket.bind(("127.0.0.1", 0))
+        # Before the environment starts so that it can't try connecting too early
+        self.socket.listen(1)
+        uri = f"tcp://127.0.0.1:{self.socket.getsockname()[1]}"
+        if TRANSPORT == "shm":
+            self.shm_path = os.path.join(self.tempdir, "bulletrl.shm")
+            ShmConnection.create(self.shm_path)
+            return f"shm://{self.shm_path}?control={uri}"
+        if TRANSPORT != "tcp":
+            raise Exception(f"Unknown transport {TRANSPORT}")
+        return uri
+
+    def handshake(self):
+        # The trainer speaks first, see EnvClient::handshake
+        self.conn.sendall(struct.pack("<II", TCP_SENTINEL, PROTOCOL_VERSION))
+
+        self.recv_sentinel()
+        version = struct.unpack("<I", self.recvfull(4))[0]
+        if version != PROTOCOL_VERSION:
+            raise ProtocolError(
+                f"Protocol version mismatch: environment speaks v{version}, trainer speaks v{PROTOCOL_VERSION}"
+            )
+
+        game_len = struct.unpack("B", self.recvfull(1))[0]
+        self.game = self.recvfull(game_len).decode("utf-8")
+        self.width, self.height, encodings, inputs, self.features, env_count = struct.unpack(
+            "<IIIBII", self.recvfull(21)
+        )
+
+        base = {"rgb": ENCODING_RGBA32, "planes": ENCODING_PLANES, "objects": ENCODING_OBJECTS}[
+            OBSERVATION_MODE
+        ]
+        if not encodings & base or env_count != self.env_count:
+            encoding = 0
+        elif encodings & ENCODING_DOWNSCALED and base != ENCODING_OBJECTS:
+            # Downscaling on the environment's side is ~100x less data per step
+            encoding = base | ENCODING_DOWNSCALED
+        else:
+            encoding = base
+        self.conn.sendall(struct.pack("<II", TCP_SENTINEL, encoding))
+        if encoding & ENCODING_DOWNSCALED:
+            self.conn.sendall(
+                struct.pack("<IIB", SCALED_WIDTH, SCALED_HEIGHT, observation_channels())
+            )
+        elif encoding == ENCODING_OBJECTS:
+            self.conn.sendall(struct.pack("<I", MAX_OBJECTS))
+        self.encoding = encoding
+        if env_count != self.env_count:
+            raise Exception(f"{self.game} hosts {env_count} environments instead of {self.env_count}")
+        if encoding == 0:
+            raise Exception(
+                f"{self.game} doesn't support any known observation encoding ({encodings:#x})"
+            )
+        if inputs & 0x1F != 0x1F:
+            raise Exception(f"{self.game} doesn't support every input ({inputs:#x})")
+        print(f"Connected to {self.game} ({self.width}x{self.height})")
+
     def recvfull(self, size):
-        read = b""
+        # Full size frames come in a lot of pieces over sockets, so let the OS put them together
+        chunks = []
         left = size
-        while len(read) != size:
-            last_size = len(read)
-            read += self.conn.recv(left)
-            left -= len(read) - last_size
-        return read
+        while left != 0:
+            chunk = self.conn.recv(left, socket.MSG_WAITALL)
+            if not chunk:
+                # e.g. bullettest exiting after an eval run
+                raise ConnectionError("Environment closed the connection")
+            chunks.append(chunk)
+            left -= len(chunk)
+        return b"".join(chunks)
 
     def send_input(self, input):
-        self.conn.sendall(struct.pack("I", TCP_SENTINEL))
-        self.conn.sendall(struct.pack("B", input))
+        self.send_inputs([input])
+
+    # One per environment, in env id order
+    def send_inputs(self, inputs):
+        self.conn.sendall(struct.pack("<IB", TCP_SENTINEL, COMMAND_STEP) + bytes(int(x) for x in inputs))
 
+    def send_reset(self, seed=None, options=None, env=0):
+        if options is None:
+            options = ""
+        elif not isinstance(options, str):
+            # Plain key = value lines, which environments parse as TOML
+            # Nested dicts become dotted keys, e.g. {"pattern": {"spiral": {"weight": 0}}} is pattern.spiral.weight = 0
+            options = "\n".join(f"{k} = {json.dumps(v)}" for k, v in flatten_options(options))
+        options = options.encode("utf-8")
+        self.conn.sendall(
+            struct.pack(
+                "<IBIBQI",
+                TCP_SENTINEL,
+                COMMAND_RESET,
+                env,
+                seed is not None,
+                seed or 0,
+                len(options),
+            )
+            + options
+        )
+
+    def recv_sentinel(self):
+        sentinel = struct.unpack("<I", self.recvfull(4))[0]
+        if sentinel == ERROR_SENTINEL:
+            length = struct.unpack("<I", self.recvfull(4))[0]
+            message = self.recvfull(length).decode("utf-8", "replace")
+            raise ProtocolError(f"Environment gave up: {message}")
+        if sentinel != TCP_SENTINEL:
+            raise ProtocolError(f"TCP desync check failed! (got {sentinel:#x})")
+
+    # Answer to a reset or load_state
     def recv_obv(self):
-        if struct.unpack("I", self.recvfull(4))[0] != TCP_SENTINEL:
-            raise Exception("TCP desync check failed!")
+        self.recv_sentinel()
+        return self.recv_obv_body()
+
+    # Answer to send_inputs, one per environment
+    def recv_obvs(self):
+        self.recv_sentinel()
+        return [self.recv_obv_body() for _ in range(self.env_count)]
 
+    def recv_obv_body(self):
+        channels = observation_channels()
+        if self.encoding == ENCODING_OBJECTS:
+            obv = process_objects(self.recvfull(MAX_OBJECTS * OBJECT_DTYPE.itemsize))
+        elif self.encoding & ENCODING_DOWNSCALED:
+            obv = np.frombuffer(
+                self.recvfull(channels * SCALED_HEIGHT * SCALED_WIDTH), dtype=np.uint8
+            ).reshape((channels, SCALED_HEIGHT, SCALED_WIDTH))
+        elif self.encoding & ENCODING_PLANES:
+            obv = process_planes(
+                self.recvfull(PLANE_COUNT * self.width * self.height), self.width, self.height
+            )
+        else:
+            obv = process_image(
+                self.recvfull(self.width * self.height * 4), self.width, self.height
+            )
+
+        reward = struct.unpack("f", self.recvfull(4))[0]
+        flags = struct.unpack("B", self.recvfull(1))[0]
         return (
-            process_image(self.recvfull(WIDTH * HEIGHT * 4)),
-            struct.unpack("f", self.recvfull(4))[0],
-            struct.unpack("B", self.recvfull(1))[0] == 1,
+            obv,
+            reward,
+            flags & STEP_TERMINATED != 0,
+            flags & STEP_TRUNCATED != 0,
+            self.recv_info(),
         )
 
+    # See bulletrl_common::Info, keys like "seed", "frame" and "end" (why the episode ended) depend on the environment
+    def recv_info(self):
+        info = {}
+        for _ in range(struct.unpack("<H", self.recvfull(2))[0]):
+            key = self.recvfull(struct.unpack("B", self.recvfull(1))[0]).decode("utf-8")
+            kind = struct.unpack("B", self.recvfull(1))[0]
+            if kind == INFO_INT:
+                info[key] = struct.unpack("<q", self.recvfull(8))[0]
+            elif kind == INFO_UINT:
+                info[key] = struct.unpack("<Q", self.recvfull(8))[0]
+            elif kind == INFO_FLOAT:
+                info[key] = struct.unpack("<d", self.recvfull(8))[0]
+            elif kind == INFO_BOOL:
+                info[key] = self.recvfull(1) != b"\x00"
+            elif kind == INFO_STR:
+                info[key] = self.recvfull(struct.unpack("<I", self.recvfull(4))[0]).decode("utf-8")
+            else:
+                raise Exception(f"Unknown info type {kind} for {key}")
+        return info
+
     def step(self, action):
-        self.stepped_once = True
         '''
         packed_input = 0
         if action[0] == 1:
@@ -103,32 +475,63 @@ class BulletRLEnv(gym.Env):
         '''
         self.send_input(action)
 
-        self.obv, reward, done = self.recv_obv()
+        self.obv, reward, terminated, truncated, info = self.recv_obv()
 
         # self.render()
-        return self.obv, reward, done, False, {}
+        return self.obv, reward, terminated, truncated, info
 
-    def reset(self):
-        if self.stepped_once:
-            self.send_input(0)  # Send dummy input
-            obv, _reward, _done = self.recv_obv()
-            return obv, {}
-        else:
-            return np.empty((3, SCALED_HEIGHT, SCALED_WIDTH), dtype=np.uint8), {}
+    def reset(self, *, seed=None, options=None):
+        self.send_reset(seed, options)
+        self.obv, _reward, _terminated, _truncated, info = self.recv_obv()
+        return self.obv, info
+
+    def save_state(self):
+        if not self.features & FEATURE_SNAPSHOTS:
+            raise Exception(f"{self.game} doesn't support saving state")
+        self.conn.sendall(struct.pack("<IBI", TCP_SENTINEL, COMMAND_SAVE_STATE, 0))
+        self.recv_sentinel()
+        size = struct.unpack("<I", self.recvfull(4))[0]
+        return self.recvfull(size)
+
+    def load_state(self, state):
+        if not self.features & FEATURE_SNAPSHOTS:
+            raise Exception(f"{self.game} doesn't support loading state")
+        self.conn.sendall(
+            struct.pack("<IBII", TCP_SENTINEL, COMMAND_LOAD_STATE, 0, len(state)) + state
+        )
+        self.obv, _reward, _terminated, _truncated, info = self.recv_obv()
+        return self.obv, info
+
+    def close(self):
+        try:
+            self.conn.sendall(struct.pack("<IB", TCP_SENTINEL, COMMAND_CLOSE))
+        except OSError:
+            pass
+        self.conn.close()
+        self.socket.close()
+        shutil.rmtree(self.tempdir, ignore_errors=True)
 
     def render(self, mode="human"):
         import pygame
 
+        if OBSERVATION_MODE == "objects":
+            raise Exception("Object observations can't be rendered")
         if self.screen is None:
             pygame.init()
             pygame.display.init()
             self.screen = pygame.display.set_mode(
                 (SCALED_WIDTH * RENDER_SCALE, SCALED_HEIGHT * RENDER_SCALE)
             )
+        if OBSERVATION_MODE == "planes":
+            obv = self.obv[:3]  # Player, enemy bullets and lasers
+        elif SCALED_CHANNELS == 1:
+            obv = np.repeat(self.obv, 3, axis=0)
+        else:
+            obv = self.obv
         self.screen.blit(
             pygame.surfarray.make_surface(
                 cv2.resize(
-                    np.transpose(self.obv, (2, 1, 0)),
+                    np.transpose(obv, (2, 1, 0)),
                     (SCALED_HEIGHT * RENDER_SCALE, SCALED_WIDTH * RENDER_SCALE),
                     interpolation=cv2.INTER_NEAREST,
                 )
@@ -140,12 +543,79 @@ class BulletRLEnv(gym.Env):
 
 
 class BulletTestEnv(BulletRLEnv):
-    def __init__(self) -> None:
+    # config is an optional path to a TOML file like bullettest/bullettest.toml
+    # With eval_episodes, episodes use fixed seeds counting up from eval_seed and bullettest exits after the last one
+    # host_envs and threads are for BulletTestVecEnv
+    def __init__(self, config=None, eval_episodes=None, eval_seed=0, host_envs=None, threads=None) -> None:
         binary = "bullettest.exe" if os.name == "nt" else "bullettest"
         self.cmdline_base = [f"bullettest/target/release/{binary}"]
+        if host_envs is not None:
+            if eval_episodes is not None:
+                raise Exception("Evaluation only runs a single game")
+            self.env_count = host_envs
+            self.cmdline_base += ["host", "--envs", str(host_envs)]
+            if threads is not None:
+                self.cmdline_base += ["--threads", str(threads)]
+        elif eval_episodes is None:
+            self.cmdline_base += ["serve"]
+        else:
+            self.cmdline_base += ["eval", "--episodes", str(eval_episodes), "--seed", str(eval_seed)]
+        if config is not None:
+            self.cmdline_base += ["--config", config]
+        # The uri gets appended last
+        self.cmdline_base += ["--connect"]
         super().__init__()
 
 
+# Lots of bullettest games in a single process and over a single connection, which are all stepped at once
+# Finished games restart on their own, so the observation that comes with a done is already from the next episode
+class BulletTestVecEnv(VecEnv):
+    def __init__(self, num_envs, config=None, threads=None) -> None:
+        self.conn = BulletTestEnv(config=config, host_envs=num_envs, threads=threads)
+        super().__init__(num_envs, self.conn.observation_space, self.conn.action_space)
+
+    def reset(self):
+        for i in range(self.num_envs):
+            self.conn.send_reset(self._seeds[i], self._options[i], env=i)
+        obvs = []
+        for i in range(self.num_envs):
+            obv, _reward, _terminated, _truncated, self.reset_infos[i] = self.conn.recv_obv()
+            obvs.append(obv)
+        self._reset_seeds()
+        self._reset_options()
+        return np.stack(obvs)
+
+    def step_async(self, actions):
+        self.conn.send_inputs(actions)
+
+    def step_wait(self):
+        obvs, rewards, dones, infos = [], [], [], []
+        for obv, reward, terminated, truncated, info in self.conn.recv_obvs():
+            # There's no info["terminal_observation"] to bootstrap from, the observation is already from the next episode
+            info["TimeLimit.truncated"] = truncated and not terminated
+            obvs.append(obv)
+            rewards.append(reward)
+            dones.append(terminated or truncated)
+            infos.append(info)
+        return np.stack(obvs), np.array(rewards, dtype=np.float32), np.array(dones), infos
+
+    def close(self):
+        self.conn.close()
+
+    # Every game shares the same connection, so attributes are the same for all of them
+    def get_attr(self, attr_name, indices=None):
+        return [getattr(self.conn, attr_name) for _ in self._get_indices(indices)]
+
+    def set_attr(self, attr_name, value, indices=None):
+        setattr(self.conn, attr_name, value)
+
+    def env_method(self, method_name, *method_args, indices=None, **method_kwargs):
+        raise NotImplementedError("Games in a BulletTestVecEnv can't be called individually")
+
+    def env_is_wrapped(self, wrapper_class, indices=None):
+        return [False for _ in self._get_indices(indices)]
+
+
 class Touhou6Env(BulletRLEnv):
     def __init__(self) -> None:
         # TODO: Don't hard code paths
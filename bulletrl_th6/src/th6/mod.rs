@@ -1,8 +1,11 @@
 use std::ffi::c_void;
 
-use bulletrl_common::Vector2;
-use log::{info, warn};
-use rand::{rngs::ThreadRng, Rng};
+use bulletrl_common::{
+    Body, Command, EnvClient, EnvFeatures, EnvInfo, Info, Input, Object, ObservationEncoding,
+    Plane, ProtocolError, RenderMode, RenderStyle, Vector2,
+};
+use log::{error, info, warn};
+use rand::{rngs::StdRng, Rng, SeedableRng};
 
 use self::{
     offsets::{ENEMY_BULLETS, ENEMY_MANAGER, ENGINE, GAME, GAME_UI, ITEM_MANAGER, PLAYER},
@@ -18,10 +21,12 @@ struct GlobalState {
     first_tick: bool,
     renderer: bulletrl_common::Renderer,
     client: Option<bulletrl_common::EnvClient>,
+    recorder: Option<bulletrl_common::Recorder>,
     training: bool,
     cur_input: bulletrl_common::Input,
     last_score: u32,
-    rng: ThreadRng,
+    seed: u64,
+    rng: StdRng,
 
     #[cfg(feature = "renderer_debug")]
     window: minifb::Window,
@@ -29,6 +34,15 @@ struct GlobalState {
 
 static mut GLOBAL_STATE: *mut GlobalState = std::ptr::null_mut::<GlobalState>();
 
+// Some objects are drawn much larger than their actual hitbox to remain visible after downscaling
+// The player ends up about 25 pixels wide and bullets twice their size
+// Can be changed with BULLETRL_RENDER_STYLE, see RenderStyle::with_overrides
+const RENDER_STYLE: RenderStyle = RenderStyle {
+    mode: RenderMode::Scaled,
+    scale: [25.0 / 3.0, 2.0, 1.0, 1.0, 1.0],
+    core_intensity: 0.5,
+};
+
 pub unsafe fn modify_game_settings() {
     std::ptr::write_bytes(GAME, 0, 1);
 
@@ -91,9 +105,13 @@ unsafe extern "cdecl" fn custom_calc(state: *mut c_void) -> i32 {
     // Discard first input because input is being polled after sending observation, not the other way around
     // Training loop should be:   recv input -> tick game -> send observation -> repeat
     // Without this, it would be: recv input -> send observation -> tick game -> repeat
-    if state.first_tick && let Some(client) = &mut state.client {
-        client.recv_input().expect("receiving first input");
+    // A reset is still honored though, since the trainer might want a specific seed from the start
+    if state.first_tick && state.client.is_some() {
         state.first_tick = false;
+        if let Some(Command::Reset { seed, .. }) = recv_command(state) {
+            reset_game(state, seed);
+            return 1;
+        }
     }
 
     // Build and send the observation
@@ -105,11 +123,12 @@ unsafe extern "cdecl" fn custom_calc(state: *mut c_void) -> i32 {
         .window
         .update_with_buffer(
             &state.renderer.buffer,
-            bulletrl_common::FIELD_WIDTH,
-            bulletrl_common::FIELD_HEIGHT,
+            state.renderer.width(),
+            state.renderer.height(),
         )
         .expect("updating debug window");
 
+    let mut info = step_info(state);
     if let Some(client) = &mut state.client {
         // TODO: Refine reward shaping, this is lazy
         // 50% ln(score difference) / ln(10000)
@@ -153,98 +172,172 @@ unsafe extern "cdecl" fn custom_calc(state: *mut c_void) -> i32 {
         */
         info!("Reward: {}", reward);
 
-        if client.send_obv(&state.renderer, reward, done).is_err() {
-            handle_broken_socket();
+        if let Some(recorder) = &mut state.recorder {
+            recorder.record(state.cur_input, reward, done, &state.renderer);
+        }
+        info.set("score_delta", score_diff)
+            .set("reward.score", score_reward)
+            .set("reward.distance", distance_reward);
+        // The stage clear results screen ends the episode, but the run could have kept going
+        let terminated = (*GAME).game_over;
+        let truncated = done && !terminated;
+        if terminated {
+            info.set("end", "game_over");
+        } else if truncated {
+            info.set("end", "cleared");
+        }
+        if let Err(e) = client.send_obv(&state.renderer, reward, terminated, truncated, &info) {
+            handle_protocol_error(client, e);
         }
     }
 
-    // Instantly reset the game on game over or level completion
-    if state.training && done {
-        state.cur_input = bulletrl_common::Input::empty();
-        state.last_score = 0;
-        state.frame = 0;
-        (*(*GAME_UI).inner).show_results = 0;
-        offsets::DESTROY_GAME_CHAINS();
-        offsets::INIT_GAME_CHAINS();
-        return 1;
+    // Wait for the next command
+    // Once an episode is over, a step also starts a new one so that trainers which never reset still work
+    match recv_command(state) {
+        Some(Command::Step(inputs)) if !(state.training && done) => state.cur_input = inputs[0],
+        Some(Command::Step(_)) => reset_game(state, None),
+        Some(Command::Reset { seed, .. }) => reset_game(state, seed),
+        _ => {}
     }
 
-    // Wait for an input
-    if let Some(client) = &mut state.client {
-        if let Ok(input) = client.recv_input() {
-            state.cur_input = input;
-        } else {
-            handle_broken_socket();
+    1
+}
+
+// Only returns steps and resets, everything else is dealt with here
+unsafe fn recv_command(state: &mut GlobalState) -> Option<Command> {
+    let client = state.client.as_mut()?;
+    loop {
+        match client.recv_command() {
+            Ok(command @ (Command::Step(_) | Command::Reset { .. })) => return Some(command),
+            Ok(Command::Close) => handle_close(),
+            // Snapshots aren't advertised in the handshake, so a trainer asking for one has lost track of what it's talking to
+            // Answering with an empty state or a fake load would look like it worked
+            Ok(Command::SaveState { .. } | Command::LoadState { .. }) => handle_protocol_error(
+                client,
+                ProtocolError::Rejected("th6 doesn't support saving or loading state".to_string()),
+            ),
+            Err(e) => handle_protocol_error(client, e),
         }
     }
+}
 
-    1
+// What every observation gets, on top of what's only known in custom_calc
+unsafe fn step_info(state: &GlobalState) -> Info {
+    let mut info = Info::new();
+    info.set("seed", state.seed)
+        .set("frame", state.frame)
+        .set("stage", (*GAME).stage)
+        .set("lives", (*GAME).lives)
+        .set("bombs", (*GAME).bombs)
+        .set("score", (*GAME).score)
+        .set(
+            "bullets",
+            (*ENEMY_BULLETS)
+                .bullets
+                .iter()
+                .filter(|x| x.shot_type != 0)
+                .count(),
+        );
+    info
+}
+
+// The next observation is sent a few frames into the new run, which also answers the command that caused the reset
+unsafe fn reset_game(state: &mut GlobalState, seed: Option<u64>) {
+    // The seed only decides the stage for now, the game itself has its own RNG
+    state.seed = seed.unwrap_or_else(rand::random);
+    state.rng = StdRng::seed_from_u64(state.seed);
+    info!("Resetting with seed {}", state.seed);
+    if let Some(recorder) = &mut state.recorder {
+        recorder.end_episode();
+    }
+
+    state.cur_input = bulletrl_common::Input::empty();
+    state.last_score = 0;
+    state.frame = 0;
+    if !(*GAME_UI).inner.is_null() {
+        (*(*GAME_UI).inner).show_results = 0;
+    }
+    offsets::DESTROY_GAME_CHAINS();
+    offsets::INIT_GAME_CHAINS();
+}
+
+fn handle_close() -> ! {
+    info!("Trainer closed the environment, exiting!");
+    finish_recording();
+    std::process::exit(0);
+}
+
+// The global state is never dropped, so the recording has to be finished by hand before exiting
+fn finish_recording() {
+    unsafe {
+        if let Some(recorder) = &mut (*GLOBAL_STATE).recorder {
+            recorder.finish();
+        }
+    }
 }
 
 // This is perfectly normal when exiting the train/eval script, so it's not really an error
-fn handle_broken_socket() -> ! {
-    info!("Socket broke, exiting!");
-    std::process::abort();
+// Tells the trainer why the game is going away, unless the trainer is the one that went away
+fn handle_protocol_error(client: &mut EnvClient, e: ProtocolError) -> ! {
+    error!("Socket broke ({}), exiting!", e);
+    if !matches!(e, ProtocolError::Disconnected | ProtocolError::Remote(_)) {
+        let _ = client.send_error(&e.to_string());
+    }
+    finish_recording();
+    std::process::exit(1);
 }
 
 unsafe fn render_observation(renderer: &mut bulletrl_common::Renderer) {
     // Draw order is important for readability
-    // Also, some objects are drawn much larger than their actual hitbox to remain visible after downscaling
+    // How much larger than their hitboxes things are drawn is up to renderer.style, see RENDER_STYLE
     renderer.clear();
 
     // Player
-    renderer.draw_rect(
-        0x00FF0000,
-        (*PLAYER).pos.x as i32,
-        (*PLAYER).pos.y as i32,
-        25,
-        25,
-    );
+    // The player's hitbox size isn't mapped, but it's about this big
+    let size = Vector2::new(3.0, 3.0);
+    renderer.draw_object(Plane::Player, (*PLAYER).pos, size, 0.0, Body::Rect);
+    renderer
+        .objects
+        .push(Object::new(Plane::Player, (*PLAYER).pos, size));
 
     // Items
     for item in &(*ITEM_MANAGER).items {
         if item.active {
-            renderer.draw_rect(0x00FFFF00, item.pos.x as i32, item.pos.y as i32, 16, 16);
+            let size = Vector2::new(16.0, 16.0);
+            renderer.draw_object(Plane::Items, item.pos, size, 0.0, Body::Rect);
+            renderer
+                .objects
+                .push(Object::new(Plane::Items, item.pos, size));
         }
     }
 
     // Enemies
     for enemy in &(*ENEMY_MANAGER).enemies {
         if (enemy.enemy_type & 0x80) != 0 && (enemy.flags & 8) == 0 {
-            renderer.draw_rect(
-                0x0000FF00,
-                enemy.pos.x as i32,
-                enemy.pos.y as i32,
-                enemy.size.x as i32,
-                enemy.size.y as i32,
-            );
+            renderer.draw_object(Plane::Enemies, enemy.pos, enemy.size, 0.0, Body::Rect);
+            renderer
+                .objects
+                .push(Object::new(Plane::Enemies, enemy.pos, enemy.size));
         }
     }
 
     // Bullets
     for bullet in &(*ENEMY_BULLETS).bullets {
         if bullet.shot_type != 0 {
-            renderer.draw_rect(
-                0x000000FF,
-                bullet.pos.x as i32,
-                bullet.pos.y as i32,
-                bullet.size.x as i32 * 2,
-                bullet.size.y as i32 * 2,
+            renderer.draw_object(
+                Plane::EnemyBullets,
+                bullet.pos,
+                bullet.size,
+                0.0,
+                Body::Ellipse,
             );
+            renderer
+                .objects
+                .push(Object::new(Plane::EnemyBullets, bullet.pos, bullet.size));
         }
     }
 
     // Lasers
-    let rot = |mut point: Vector2, origin: Vector2, angle: f32| {
-        point.x -= origin.x;
-        point.y -= origin.y;
-
-        let temp_x = point.x * angle.cos() - point.y * angle.sin();
-        let temp_y = point.x * angle.sin() + point.y * angle.cos();
-
-        Vector2::new(temp_x + origin.x, temp_y + origin.y)
-    };
-
     for laser in &(*ENEMY_BULLETS).lasers {
         if laser.active != 0 {
             let pos = Vector2::new(
@@ -254,34 +347,45 @@ unsafe fn render_observation(renderer: &mut bulletrl_common::Renderer) {
                 laser.fire_point.y,
             );
             let size = Vector2::new(laser.end_offset - laser.start_offset, laser.width / 2.0);
+            let center = pos.rotate_around(laser.fire_point, laser.angle);
 
-            let p1 = rot(
-                Vector2::new(pos.x - (size.x / 2.0), pos.y),
-                laser.fire_point,
-                laser.angle,
-            );
-            let p2 = rot(
-                Vector2::new(pos.x + (size.x / 2.0), pos.y),
-                laser.fire_point,
-                laser.angle,
-            );
-
-            renderer.draw_line(0x000000FF, p1, p2, size.y);
+            renderer.draw_object(Plane::Lasers, center, size, laser.angle, Body::Rect);
+            renderer
+                .objects
+                .push(Object::new(Plane::Lasers, center, size).with_angle(laser.angle));
         }
     }
 }
 
 unsafe fn connect_to_server() {
     let args = std::env::args().collect::<Vec<String>>();
+    // Either a port or a uri, see bulletrl_common::connect
     if args.len() < 2 {
         warn!("No port specified, running in standalone non-training mode!");
         (*GLOBAL_STATE).training = false;
-    } else if let Ok(port) = str::parse::<u16>(&args[1]) {
-        let client = bulletrl_common::EnvClient::new(port).expect("connecting to server");
+    } else {
+        let info = EnvInfo {
+            game: "th6".to_string(),
+            field_width: (*GLOBAL_STATE).renderer.width() as u32,
+            field_height: (*GLOBAL_STATE).renderer.height() as u32,
+            encodings: ObservationEncoding::RGBA32
+                | ObservationEncoding::PLANES
+                | ObservationEncoding::OBJECTS
+                | ObservationEncoding::DOWNSCALED,
+            inputs: Input::all(),
+            features: EnvFeatures::empty(),
+            // There's only one game per process
+            env_count: 1,
+        };
+        let client = match EnvClient::connect(&args[1], &info) {
+            Ok(client) => client,
+            Err(e) => {
+                error!("Failed to connect to the trainer: {}", e);
+                std::process::exit(1);
+            }
+        };
         (*GLOBAL_STATE).client = Some(client);
         (*GLOBAL_STATE).training = true; // TODO: Allow server to pick between eval and train
-    } else {
-        panic!("Failed to parse port: {}", args[1]);
     }
 }
 
@@ -294,15 +398,23 @@ unsafe fn inject_calc_chain() {
 unsafe fn post_inject_init() {
     info!("Running post-injection initialization...");
 
+    let seed = rand::random();
+    let mut renderer = bulletrl_common::Renderer::from_env(
+        bulletrl_common::FIELD_WIDTH,
+        bulletrl_common::FIELD_HEIGHT,
+    );
+    renderer.style = RENDER_STYLE.with_env_overrides();
     GLOBAL_STATE = Box::leak(Box::new(GlobalState {
         frame: 0,
         first_tick: true,
-        renderer: Default::default(),
+        recorder: bulletrl_common::Recorder::from_env(&renderer),
+        renderer,
         client: None,
         training: false,
         cur_input: bulletrl_common::Input::empty(),
         last_score: 0,
-        rng: rand::thread_rng(),
+        seed,
+        rng: StdRng::seed_from_u64(seed),
 
         #[cfg(feature = "renderer_debug")]
         window: minifb::Window::new(
@@ -1,40 +1,69 @@
-use std::time::{Duration, Instant};
+use std::{
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use bulletrl_common::Input;
-use log::{error, info};
+use bulletrl_common::{
+    Command, EnvClient, EnvFeatures, EnvInfo, Info, Input, ObservationEncoding, ObvEncoder,
+    ProtocolError, Recorder,
+};
+use log::{error, info, warn};
 use minifb::{Key, Window, WindowOptions};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
 
-use crate::game::Game;
+use crate::{
+    config::Config,
+    game::Game,
+    replay::{Replay, ReplayRecorder},
+};
 
 pub trait Backend {
     fn main_loop(&mut self);
 }
 
+// Always the size of the field, smaller renders are stretched to fit
+fn create_window() -> Window {
+    let mut window = Window::new(
+        "bullettest",
+        bulletrl_common::FIELD_WIDTH,
+        bulletrl_common::FIELD_HEIGHT,
+        //WindowOptions::default(),
+        WindowOptions {
+            topmost: true,
+            ..Default::default()
+        },
+    )
+    .expect("failed to create window");
+
+    window.limit_update_rate(Some(Duration::from_secs_f64(1.0 / 60.0)));
+    //window.limit_update_rate(None);
+
+    window
+}
+
 pub struct MinifbBackend {
     game: Game,
     window: Window,
+    recorder: Option<Recorder>,
+    replays: Option<ReplayRecorder>,
 }
 
-impl Default for MinifbBackend {
-    fn default() -> Self {
-        let mut window = Window::new(
-            "bullettest",
-            bulletrl_common::FIELD_WIDTH,
-            bulletrl_common::FIELD_HEIGHT,
-            //WindowOptions::default(),
-            WindowOptions {
-                topmost: true,
-                ..Default::default()
-            },
-        )
-        .expect("failed to create window");
-
-        window.limit_update_rate(Some(Duration::from_secs_f64(1.0 / 60.0)));
-        //window.limit_update_rate(None);
+impl MinifbBackend {
+    pub fn new(config: Config, seed: Option<u64>) -> Self {
+        let game = Game::new(Arc::new(config), seed.unwrap_or_else(rand::random));
+        let mut replays = ReplayRecorder::from_env();
+        if let Some(replays) = &mut replays {
+            replays.begin(game.seed, &game.config);
+        }
 
         MinifbBackend {
-            game: Default::default(),
-            window,
+            recorder: Recorder::from_env(&game.renderer),
+            game,
+            window: create_window(),
+            replays,
         }
     }
 }
@@ -62,15 +91,30 @@ impl Backend for MinifbBackend {
                 input |= Input::FOCUS;
             }
 
-            if self.game.tick(input) || self.window.is_key_down(Key::R) {
-                self.game = Default::default();
+            let died = self.game.tick(input);
+            if let Some(recorder) = &mut self.recorder {
+                recorder.record(input, self.game.reward(died), died, &self.game.renderer);
+            }
+            if let Some(replays) = &mut self.replays {
+                replays.push(input);
+            }
+            if died || self.window.is_key_down(Key::R) {
+                self.game = self.game.restart();
+                self.game.draw();
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.begin_episode(&self.game.renderer);
+                }
+                if let Some(replays) = &mut self.replays {
+                    replays.finish(died);
+                    replays.begin(self.game.seed, &self.game.config);
+                }
             }
 
             self.window
                 .update_with_buffer(
                     &self.game.renderer.buffer,
-                    bulletrl_common::FIELD_WIDTH,
-                    bulletrl_common::FIELD_HEIGHT,
+                    self.game.renderer.width(),
+                    self.game.renderer.height(),
                 )
                 .expect("failed to update window");
 
@@ -87,71 +131,734 @@ impl Backend for MinifbBackend {
 
 pub struct TcpBackend {
     game: Game,
+    // Reset options are applied on top of this
+    config: Arc<Config>,
     client: bulletrl_common::EnvClient,
+    recorder: Option<Recorder>,
+    replays: Option<ReplayRecorder>,
+    eval: Option<Eval>,
+}
+
+// Tracks a fixed run of episodes for `bullettest eval`
+struct Eval {
+    episodes: u32,
+    seed: u64,
+    episode_return: f32,
+    results: Vec<EpisodeResult>,
+}
+
+struct EpisodeResult {
+    episode_return: f32,
+    frames: u64,
+    died: bool,
 }
 
 impl TcpBackend {
-    pub fn new(port: u16) -> Self {
-        let client = bulletrl_common::EnvClient::new(port).expect("connecting to server");
+    // See bulletrl_common::connect for what the uri can be, despite the name this isn't limited to TCP
+    pub fn new(uri: &str, config: Config) -> Self {
+        let config = Arc::new(config);
+        let game = Game::new(config.clone(), rand::random());
+        let info = EnvInfo {
+            game: "bullettest".to_string(),
+            field_width: game.renderer.width() as u32,
+            field_height: game.renderer.height() as u32,
+            encodings: ObservationEncoding::RGBA32
+                | ObservationEncoding::PLANES
+                | ObservationEncoding::OBJECTS
+                | ObservationEncoding::DOWNSCALED,
+            inputs: Input::all(),
+            features: EnvFeatures::SNAPSHOTS,
+            env_count: 1,
+        };
+        let client = connect(uri, &info);
+        let mut replays = ReplayRecorder::from_env();
+        if let Some(replays) = &mut replays {
+            replays.begin(game.seed, &game.config);
+        }
+
         TcpBackend {
-            game: Default::default(),
+            recorder: Recorder::from_env(&game.renderer),
+            game,
+            config,
             client,
+            replays,
+            eval: None,
         }
     }
+
+    // Episodes use the seeds seed, seed + 1, ... no matter what the trainer asks for, and the game exits after the last one
+    // A reset in the middle of an episode restarts it instead of skipping ahead
+    pub fn eval(uri: &str, config: Config, episodes: u32, seed: u64) -> Self {
+        let mut backend = TcpBackend::new(uri, config);
+        backend.game = Game::new(backend.config.clone(), seed);
+        if let Some(replays) = &mut backend.replays {
+            replays.begin(seed, &backend.game.config);
+        }
+        backend.eval = Some(Eval {
+            episodes: episodes.max(1),
+            seed,
+            episode_return: 0.0,
+            results: Vec::new(),
+        });
+        backend
+    }
+}
+
+impl Eval {
+    // Returns whether every episode is done
+    fn finish_episode(&mut self, game: &Game, died: bool) -> bool {
+        self.results.push(EpisodeResult {
+            episode_return: self.episode_return,
+            frames: game.frame,
+            died,
+        });
+        info!(
+            "Episode {}/{} (seed {}): {} after {:.2} seconds with a return of {:.2}",
+            self.results.len(),
+            self.episodes,
+            game.seed,
+            if died { "died" } else { "survived" },
+            game.frame as f64 / 60.0,
+            self.episode_return
+        );
+        self.seed = self.seed.wrapping_add(1);
+        self.episode_return = 0.0;
+
+        if self.results.len() < self.episodes as usize {
+            return false;
+        }
+
+        let count = self.results.len() as f64;
+        info!(
+            "Evaluated {} episodes: mean return {:.2}, mean length {:.2} seconds, {} deaths",
+            self.results.len(),
+            self.results
+                .iter()
+                .map(|x| x.episode_return as f64)
+                .sum::<f64>()
+                / count,
+            self.results
+                .iter()
+                .map(|x| x.frames as f64 / 60.0)
+                .sum::<f64>()
+                / count,
+            self.results.iter().filter(|x| x.died).count()
+        );
+        true
+    }
 }
 
 impl Backend for TcpBackend {
     fn main_loop(&mut self) {
-        let mut input = Input::empty();
-        let mut frame = 0;
-        loop {
-            // Play the game at 15fps to highlight major changes and for better performance
-            let input_frame = frame % 4 == 0;
-            frame += 1;
-
-            // Read the input from the agent
-            if input_frame {
-                if let Ok(input_recv) = self.client.recv_input() {
-                    input = input_recv;
-                } else {
+        let e = loop {
+            // Read the next command from the agent
+            let input = match self.client.recv_command() {
+                // Only ever one environment, so env ids are always 0
+                Ok(Command::Step(inputs)) => inputs[0],
+                Ok(Command::Reset { seed, options, .. }) => {
+                    let config = reset_config(&self.config, options);
+
+                    let seed = match &mut self.eval {
+                        Some(eval) => {
+                            if seed.is_some_and(|x| x != eval.seed) {
+                                warn!(
+                                    "Ignoring reset seed {}, evaluating seed {}",
+                                    seed.unwrap(),
+                                    eval.seed
+                                );
+                            }
+                            eval.episode_return = 0.0;
+                            eval.seed
+                        }
+                        None => seed.unwrap_or_else(rand::random),
+                    };
+
+                    // Answer with the very first frame of the new episode
+                    self.game = Game::new(config, seed);
+                    self.game.draw();
+                    if let Some(recorder) = &mut self.recorder {
+                        recorder.begin_episode(&self.game.renderer);
+                    }
+                    if let Some(replays) = &mut self.replays {
+                        replays.begin(self.game.seed, &self.game.config);
+                    }
+                    if let Err(e) = self.client.send_obv(
+                        &self.game.renderer,
+                        0.0,
+                        false,
+                        false,
+                        &self.game.info(),
+                    ) {
+                        break e;
+                    }
+                    continue;
+                }
+                Ok(Command::Close) => {
+                    info!("Agent closed the environment, exiting!");
+                    return;
+                }
+                Ok(Command::SaveState { .. }) => {
+                    if let Err(e) = self.client.send_state(&self.game.save_state()) {
+                        break e;
+                    }
+                    continue;
+                }
+                Ok(Command::LoadState { data, .. }) => {
+                    // A bad state leaves the game untouched, the agent can tell from the unchanged observation
+                    if let Err(e) = self.game.load_state(&data) {
+                        error!("Failed to load state: {}", e);
+                    }
+                    // Continuing from a loaded state is a different trajectory as far as the recording is concerned
+                    if let Some(recorder) = &mut self.recorder {
+                        recorder.begin_episode(&self.game.renderer);
+                    }
+                    if let Some(replays) = &mut self.replays {
+                        replays.discard();
+                    }
+                    if let Err(e) = self.client.send_obv(
+                        &self.game.renderer,
+                        0.0,
+                        false,
+                        false,
+                        &self.game.info(),
+                    ) {
+                        break e;
+                    }
+                    continue;
+                }
+                Err(e) => break e,
+            };
+
+            // Play the game at 15fps by default to highlight major changes and for better performance
+            // Every command has to be answered by exactly one observation, so dying partway through still ends the step
+            let mut died = false;
+            for _ in 0..self.game.config.frameskip {
+                died = self.game.tick(input);
+                if let Some(replays) = &mut self.replays {
+                    replays.push(input);
+                }
+                if died || self.game.frame >= self.game.config.timeout {
                     break;
                 }
             }
-
-            let died = self.game.tick(input);
-            let timeout = frame >= 60 * 60;
+            let timeout = self.game.frame >= self.game.config.timeout;
 
             // Send the current results to the agent
-            let reward = if died {
-                -1.0
-            } else {
-                // Reward being closer on the x-axis to the enemy
-                1.0 - ((self.game.player.pos.x - self.game.enemy.pos.x)
-                    .abs()
-                    .clamp(0.0, 50.0)
-                    / 50.0)
-                    * 0.5
-            };
-            if died || timeout || input_frame {
-                if self
-                    .client
-                    .send_obv(&self.game.renderer, reward, died || timeout)
-                    .is_err()
-                {
-                    break;
+            let reward = self.game.reward(died);
+            if let Some(eval) = &mut self.eval {
+                eval.episode_return += reward;
+            }
+            if let Some(recorder) = &mut self.recorder {
+                recorder.record(input, reward, died || timeout, &self.game.renderer);
+            }
+            let mut info = self.game.info();
+            if died {
+                info.set("end", "death");
+            } else if timeout {
+                info.set("end", "timeout");
+            }
+            if let Err(e) =
+                self.client
+                    .send_obv(&self.game.renderer, reward, died, timeout && !died, &info)
+            {
+                break e;
+            }
+            if died || timeout {
+                if timeout {
+                    info!(
+                        "Player managed to survive {:.2} seconds! (seed {})\n{}",
+                        self.game.frame as f64 / 60.0,
+                        self.game.seed,
+                        self.game.describe_enemies()
+                    );
                 }
-                if died || timeout {
-                    if timeout {
-                        info!(
-                            "Player managed to survive 1 minute! {:?} {:?}",
-                            self.game.enemy.movement, self.game.enemy.pattern
-                        );
+                self.game = match &mut self.eval {
+                    Some(eval) => {
+                        if eval.finish_episode(&self.game, died) {
+                            if let Some(replays) = &mut self.replays {
+                                replays.finish(died);
+                            }
+                            return;
+                        }
+                        Game::new(self.game.config.clone(), eval.seed)
                     }
-                    self.game = Default::default();
-                    frame = 0;
+                    None => self.game.restart(),
+                };
+                self.game.draw();
+                if let Some(recorder) = &mut self.recorder {
+                    recorder.begin_episode(&self.game.renderer);
                 }
+                if let Some(replays) = &mut self.replays {
+                    replays.finish(died);
+                    replays.begin(self.game.seed, &self.game.config);
+                }
+            }
+        };
+        protocol_error(&mut self.client, e);
+    }
+}
+
+// Nothing to fall back on without a trainer, so just say why and leave
+fn connect(uri: &str, info: &EnvInfo) -> EnvClient {
+    match EnvClient::connect(uri, info) {
+        Ok(client) => client,
+        Err(e) => {
+            error!("Failed to connect to the trainer: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
+// Tells the trainer why the environment is going away, unless the trainer is the one that went away
+fn protocol_error(client: &mut EnvClient, e: ProtocolError) {
+    error!("Stream broken ({}), exiting!", e);
+    if !matches!(e, ProtocolError::Disconnected | ProtocolError::Remote(_)) {
+        let _ = client.send_error(&e.to_string());
+    }
+}
+
+// Options are TOML that only applies to the episodes started from the reset they came with
+fn reset_config(config: &Arc<Config>, options: Option<String>) -> Arc<Config> {
+    match options.map(|x| config.with_overrides(&x)) {
+        Some(Ok(config)) => Arc::new(config),
+        Some(Err(e)) => {
+            error!("Ignoring bad reset options: {}", e);
+            config.clone()
+        }
+        None => config.clone(),
+    }
+}
+
+// Lots of games behind a single connection, stepped in parallel on worker threads
+// Finished games are restarted right away: the observation is the first frame of the next episode, while the reward,
+// flags and info still describe the step that ended the last one
+// Recordings and replays are left to TcpBackend, hundreds of games would write hundreds of files
+pub struct HostBackend {
+    games: Vec<HostedGame>,
+    // Reset options are applied on top of this
+    config: Arc<Config>,
+    client: EnvClient,
+    pool: rayon::ThreadPool,
+}
+
+struct HostedGame {
+    game: Game,
+    encoder: ObvEncoder,
+    // Encoded by the worker that stepped the game
+    obv: Vec<u8>,
+}
+
+impl HostedGame {
+    fn step(&mut self, input: Input) -> Result<(), ProtocolError> {
+        let mut died = false;
+        for _ in 0..self.game.config.frameskip {
+            died = self.game.tick(input);
+            if died || self.game.frame >= self.game.config.timeout {
+                break;
             }
         }
-        error!("Stream broken, exiting!");
+        let timeout = self.game.frame >= self.game.config.timeout;
+
+        let reward = self.game.reward(died);
+        let mut info = self.game.info();
+        if died {
+            info.set("end", "death");
+        } else if timeout {
+            info.set("end", "timeout");
+        }
+        if died || timeout {
+            self.game = self.game.restart();
+            self.game.draw();
+        }
+
+        self.obv.clear();
+        self.encoder.encode(
+            &self.game.renderer,
+            reward,
+            died,
+            timeout && !died,
+            &info,
+            &mut self.obv,
+        )
+    }
+}
+
+impl HostBackend {
+    // threads is the number of workers, None for one per core
+    pub fn new(uri: &str, config: Config, envs: u32, threads: Option<usize>) -> Self {
+        let config = Arc::new(config);
+        let games = (0..envs)
+            .map(|_| Game::new(config.clone(), rand::random()))
+            .collect::<Vec<_>>();
+        let info = EnvInfo {
+            game: "bullettest".to_string(),
+            field_width: games[0].renderer.width() as u32,
+            field_height: games[0].renderer.height() as u32,
+            encodings: ObservationEncoding::RGBA32
+                | ObservationEncoding::PLANES
+                | ObservationEncoding::OBJECTS
+                | ObservationEncoding::DOWNSCALED,
+            inputs: Input::all(),
+            features: EnvFeatures::SNAPSHOTS,
+            env_count: envs,
+        };
+        let client = connect(uri, &info);
+        let encoder = client.encoder();
+        let games = games
+            .into_iter()
+            .map(|game| HostedGame {
+                game,
+                encoder: encoder.clone(),
+                obv: Vec::new(),
+            })
+            .collect();
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.unwrap_or(0))
+            .build()
+            .expect("creating worker threads");
+        info!(
+            "Hosting {} games on {} threads",
+            envs,
+            pool.current_num_threads()
+        );
+
+        HostBackend {
+            games,
+            config,
+            client,
+            pool,
+        }
+    }
+
+    fn send_obv(&mut self, env: u32, info: &Info) -> Result<(), ProtocolError> {
+        self.client.send_obv(
+            &self.games[env as usize].game.renderer,
+            0.0,
+            false,
+            false,
+            info,
+        )
+    }
+
+    fn handle(&mut self, command: Command) -> Result<(), ProtocolError> {
+        match command {
+            Command::Step(inputs) => {
+                let games = &mut self.games;
+                self.pool.install(|| {
+                    games
+                        .par_iter_mut()
+                        .zip(inputs)
+                        .try_for_each(|(game, input)| game.step(input))
+                })?;
+                self.client
+                    .send_obvs(self.games.iter().map(|x| x.obv.as_slice()))
+            }
+            Command::Reset { env, seed, options } => {
+                let config = reset_config(&self.config, options);
+                let game = &mut self.games[env as usize].game;
+                *game = Game::new(config, seed.unwrap_or_else(rand::random));
+                game.draw();
+                let info = game.info();
+                self.send_obv(env, &info)
+            }
+            Command::SaveState { env } => self
+                .client
+                .send_state(&self.games[env as usize].game.save_state()),
+            Command::LoadState { env, data } => {
+                let game = &mut self.games[env as usize].game;
+                // A bad state leaves the game untouched, the agent can tell from the unchanged observation
+                if let Err(e) = game.load_state(&data) {
+                    error!("Failed to load state: {}", e);
+                }
+                let info = game.info();
+                self.send_obv(env, &info)
+            }
+            Command::Close => unreachable!("handled by main_loop"),
+        }
+    }
+}
+
+impl Backend for HostBackend {
+    fn main_loop(&mut self) {
+        let e = loop {
+            match self.client.recv_command() {
+                Ok(Command::Close) => {
+                    info!("Agent closed the environment, exiting!");
+                    return;
+                }
+                Ok(command) => {
+                    if let Err(e) = self.handle(command) {
+                        break e;
+                    }
+                }
+                Err(e) => break e,
+            }
+        };
+        protocol_error(&mut self.client, e);
+    }
+}
+
+// Runs the game headless as fast as possible with random inputs
+pub struct BenchBackend {
+    game: Game,
+    frames: u64,
+    rng: ChaCha8Rng,
+}
+
+impl BenchBackend {
+    pub fn new(config: Config, frames: u64, seed: u64) -> Self {
+        BenchBackend {
+            game: Game::new(Arc::new(config), seed),
+            frames,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+        }
+    }
+}
+
+impl Backend for BenchBackend {
+    fn main_loop(&mut self) {
+        let start = Instant::now();
+        let mut last_update = start;
+        let mut frames_since_update = 0;
+        let mut episodes = 1;
+
+        let mut input = Input::empty();
+        for frame in 0..self.frames {
+            // Inputs are held for as long as an agent would hold them
+            if frame.is_multiple_of(self.game.config.frameskip as u64) {
+                input = Input::from_bits_truncate(self.rng.gen_range(0..32));
+            }
+            if self.game.tick(input) {
+                self.game = Game::new(self.game.config.clone(), self.rng.gen());
+                episodes += 1;
+            }
+            frames_since_update += 1;
+
+            if last_update.elapsed() >= Duration::from_secs(1) {
+                info!(
+                    "{:.0} fps",
+                    frames_since_update as f64 / last_update.elapsed().as_secs_f64()
+                );
+                frames_since_update = 0;
+                last_update = Instant::now();
+            }
+        }
+
+        let elapsed = start.elapsed().as_secs_f64();
+        info!(
+            "Ran {} frames over {} episodes in {:.2} seconds ({:.0} fps, {:.1}x realtime)",
+            self.frames,
+            episodes,
+            elapsed,
+            self.frames as f64 / elapsed,
+            self.frames as f64 / elapsed / 60.0
+        );
+    }
+}
+
+pub struct ReplayBackend {
+    replay: Replay,
+    game: Game,
+    window: Option<Window>,
+}
+
+impl ReplayBackend {
+    // Headless playback runs as fast as possible and only checks that the replay ends the same way it was recorded
+    pub fn new<P: AsRef<Path>>(path: P, headless: bool) -> Self {
+        // Nothing to play without it, so just say why and leave
+        let replay = Replay::load(path.as_ref()).unwrap_or_else(|e| {
+            error!("Failed to load replay {}: {}", path.as_ref().display(), e);
+            std::process::exit(1);
+        });
+        let config = Config::parse(&replay.config).unwrap_or_else(|e| {
+            error!("Replay has an invalid config: {}", e);
+            std::process::exit(1);
+        });
+        ReplayBackend {
+            game: Game::new(Arc::new(config), replay.seed),
+            replay,
+            window: (!headless).then(create_window),
+        }
+    }
+}
+
+impl Backend for ReplayBackend {
+    fn main_loop(&mut self) {
+        info!(
+            "Replaying {} frames ({:.2} seconds) with seed {}",
+            self.replay.inputs.len(),
+            self.replay.inputs.len() as f64 / 60.0,
+            self.replay.seed
+        );
+
+        let mut died = false;
+        for input in &self.replay.inputs {
+            if let Some(window) = &self.window {
+                if !window.is_open() || window.is_key_down(Key::Escape) {
+                    return;
+                }
+            }
+
+            died = self.game.tick(*input);
+
+            if let Some(window) = &mut self.window {
+                window
+                    .update_with_buffer(
+                        &self.game.renderer.buffer,
+                        self.game.renderer.width(),
+                        self.game.renderer.height(),
+                    )
+                    .expect("failed to update window");
+            }
+            if died {
+                break;
+            }
+        }
+
+        if died == self.replay.died && self.game.frame == self.replay.inputs.len() as u64 {
+            info!(
+                "Replay finished as recorded after {:.2} seconds",
+                self.game.frame as f64 / 60.0
+            );
+        } else {
+            error!(
+                "Replay desynced! Recorded {} after {} frames, but {} after {} frames",
+                if self.replay.died {
+                    "dying"
+                } else {
+                    "surviving"
+                },
+                self.replay.inputs.len(),
+                if died { "died" } else { "survived" },
+                self.game.frame
+            );
+        }
+
+        // Keep the last frame up until the window is closed
+        if let Some(window) = &mut self.window {
+            while window.is_open() && !window.is_key_down(Key::Escape) {
+                window.update();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_round_trip() {
+        // Not the defaults, so that the replay has to bring its config along
+        let config = Config {
+            enemy_limit: 5,
+            enemy_spawn_interval: crate::config::Range(20, 40),
+            ..Default::default()
+        };
+        let mut game = Game::new(Arc::new(config.clone()), 7);
+        let mut replay = Replay::new(game.seed, &config);
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        while game.frame < 3000 && !replay.died {
+            let input = Input::from_bits_truncate(rng.gen());
+            replay.inputs.push(input);
+            replay.died = game.tick(input);
+        }
+
+        let path = std::env::temp_dir().join(format!("bullettest-{}.btr", std::process::id()));
+        replay.save(&path).unwrap();
+        let mut backend = ReplayBackend::new(&path, true);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(backend.replay.inputs, replay.inputs);
+        assert_eq!(backend.replay.died, replay.died);
+        assert_eq!(backend.game.config.enemy_limit, 5);
+
+        backend.main_loop();
+        assert_eq!(backend.game.frame, game.frame);
+        assert_eq!(backend.game.save_state(), game.save_state());
+        assert_eq!(backend.game.renderer.buffer, game.renderer.buffer);
+    }
+
+    #[test]
+    fn hosted_games() {
+        use bulletrl_common::{
+            EnvConnection, EnvServer, InfoValue, Observation, ObservationRequest,
+        };
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        // Nothing can kill the player, so episodes only end by running out of time, 10 steps in
+        let config = Config {
+            bullet_limit: 0,
+            timeout: 40,
+            ..Default::default()
+        };
+        let host = std::thread::spawn(move || {
+            HostBackend::new(&port.to_string(), config, 3, Some(2)).main_loop()
+        });
+        let (stream, _) = listener.accept().unwrap();
+        let connection = EnvConnection::handshake(
+            Box::new(stream),
+            ObservationRequest::Objects { max_objects: 1 },
+        )
+        .unwrap();
+        assert_eq!(connection.env_count(), 3);
+        let mut server = EnvServer::from_connections(vec![connection]);
+
+        let player_x = |obv: &Observation| f32::from_le_bytes(obv.data[2..6].try_into().unwrap());
+        let uint = |obv: &Observation, key: &str| match obv.info.get(key) {
+            Some(InfoValue::UInt(x)) => *x,
+            x => panic!("{} is {:?}", key, x),
+        };
+        let start = crate::game::Player::default().pos.x;
+
+        let obvs = server.reset(&[Some(1), Some(2), Some(3)], None).unwrap();
+        assert_eq!(
+            obvs.iter().map(|x| uint(x, "seed")).collect::<Vec<_>>(),
+            [1, 2, 3]
+        );
+        let inputs = [Input::LEFT, Input::RIGHT, Input::empty()];
+        for _ in 0..5 {
+            server.step(&inputs).unwrap();
+        }
+        let obvs = server.step(&inputs).unwrap();
+        let xs = obvs.iter().map(player_x).collect::<Vec<_>>();
+        assert_eq!(xs, [start - 4.0 * 24.0, start + 4.0 * 24.0, start]);
+
+        // Puts the last one 6 steps behind the others
+        let obv = server.reset_one(2, Some(30), None).unwrap();
+        assert_eq!(uint(&obv, "seed"), 30);
+        for _ in 0..3 {
+            server.step(&inputs).unwrap();
+        }
+
+        // The first two run out of time and start over, reporting how the old episode went
+        let obvs = server.step(&inputs).unwrap();
+        for (obv, seed) in obvs[..2].iter().zip([1, 2]) {
+            assert!(obv.truncated && !obv.terminated);
+            assert_eq!(
+                obv.info.get("end"),
+                Some(&InfoValue::Str("timeout".to_string()))
+            );
+            assert_eq!(uint(obv, "seed"), seed);
+            assert_eq!(uint(obv, "frame"), 40);
+            assert_eq!(player_x(obv), start);
+        }
+        assert!(!obvs[2].done());
+        assert_eq!(uint(&obvs[2], "seed"), 30);
+        assert_eq!(uint(&obvs[2], "frame"), 16);
+
+        let obvs = server.step(&inputs).unwrap();
+        for obv in &obvs[..2] {
+            assert!(!obv.done());
+            assert_eq!(uint(obv, "frame"), 4);
+        }
+        assert_ne!(uint(&obvs[0], "seed"), 1);
+        assert_eq!(uint(&obvs[2], "seed"), 30);
+        assert_eq!(uint(&obvs[2], "frame"), 20);
+
+        // Dropping the server closes the connection
+        drop(server);
+        host.join().unwrap();
     }
 }
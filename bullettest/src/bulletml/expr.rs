@@ -0,0 +1,204 @@
+use rand::Rng;
+
+// Arithmetic expressions used everywhere in BulletML, e.g. "$rand * 360 + $1 / 2"
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Num(f32),
+    Rand,
+    Rank,
+    // $1 is Param(0)
+    Param(usize),
+    Neg(Box<Expr>),
+    Bin(Op, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Op {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+}
+
+// Everything an expression can refer to
+pub struct Env<'a, R: Rng + ?Sized> {
+    pub params: &'a [f32],
+    pub rank: f32,
+    pub rng: &'a mut R,
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut parser = Parser {
+            chars: text.chars().filter(|x| !x.is_whitespace()).collect(),
+            pos: 0,
+        };
+        let expr = parser.sum()?;
+        if parser.pos != parser.chars.len() {
+            return Err(format!("unexpected {:?} in {:?}", parser.peek(), text));
+        }
+        Ok(expr)
+    }
+
+    pub fn eval<R: Rng + ?Sized>(&self, env: &mut Env<R>) -> f32 {
+        match self {
+            Expr::Num(x) => *x,
+            Expr::Rand => env.rng.gen(),
+            Expr::Rank => env.rank,
+            // Missing parameters are 0 in most BulletML implementations
+            Expr::Param(i) => env.params.get(*i).copied().unwrap_or(0.0),
+            Expr::Neg(x) => -x.eval(env),
+            Expr::Bin(op, a, b) => {
+                let a = a.eval(env);
+                let b = b.eval(env);
+                match op {
+                    Op::Add => a + b,
+                    Op::Sub => a - b,
+                    Op::Mul => a * b,
+                    Op::Div => a / b,
+                    Op::Rem => a % b,
+                }
+            }
+        }
+    }
+}
+
+// Plain recursive descent, sum -> product -> unary -> atom
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.product()?;
+        while let Some(op) = self.peek().and_then(|x| match x {
+            '+' => Some(Op::Add),
+            '-' => Some(Op::Sub),
+            _ => None,
+        }) {
+            self.pos += 1;
+            lhs = Expr::Bin(op, Box::new(lhs), Box::new(self.product()?));
+        }
+        Ok(lhs)
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        while let Some(op) = self.peek().and_then(|x| match x {
+            '*' => Some(Op::Mul),
+            '/' => Some(Op::Div),
+            '%' => Some(Op::Rem),
+            _ => None,
+        }) {
+            self.pos += 1;
+            lhs = Expr::Bin(op, Box::new(lhs), Box::new(self.unary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some('-') => {
+                self.pos += 1;
+                Ok(Expr::Neg(Box::new(self.unary()?)))
+            }
+            Some('+') => {
+                self.pos += 1;
+                self.unary()
+            }
+            _ => self.atom(),
+        }
+    }
+
+    fn atom(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let expr = self.sum()?;
+                if self.peek() != Some(')') {
+                    return Err("missing closing parenthesis".to_string());
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            Some('$') => {
+                self.pos += 1;
+                let name = self.take_while(|x| x.is_ascii_alphanumeric());
+                match name.as_str() {
+                    "rand" => Ok(Expr::Rand),
+                    "rank" => Ok(Expr::Rank),
+                    _ => match name.parse::<usize>() {
+                        Ok(i) if i >= 1 => Ok(Expr::Param(i - 1)),
+                        _ => Err(format!("unknown variable ${}", name)),
+                    },
+                }
+            }
+            Some(x) if x.is_ascii_digit() || x == '.' => {
+                let num = self.take_while(|x| x.is_ascii_digit() || x == '.');
+                num.parse()
+                    .map(Expr::Num)
+                    .map_err(|_| format!("bad number {:?}", num))
+            }
+            x => Err(format!("unexpected {:?}", x)),
+        }
+    }
+
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> String {
+        let start = self.pos;
+        while self.peek().map(&f).unwrap_or(false) {
+            self.pos += 1;
+        }
+        self.chars[start..self.pos].iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    fn eval(text: &str, params: &[f32]) -> f32 {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        Expr::parse(text).unwrap().eval(&mut Env {
+            params,
+            rank: 0.5,
+            rng: &mut rng,
+        })
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(eval("1 + 2 * 3", &[]), 7.0);
+        assert_eq!(eval("(1 + 2) * 3", &[]), 9.0);
+        assert_eq!(eval("-2 * 3 + 10 % 4", &[]), -4.0);
+        assert_eq!(eval("--2", &[]), 2.0);
+        // Left to right within the same level
+        assert_eq!(eval("8 - 4 - 2", &[]), 2.0);
+        assert_eq!(eval("8 / 4 / 2", &[]), 1.0);
+        assert_eq!(eval("1 - 2 * (3 - 1) / 4", &[]), 0.0);
+    }
+
+    #[test]
+    fn variables() {
+        assert_eq!(eval("$1 * 2 + $2", &[3.0, 1.0]), 7.0);
+        // Missing parameters are 0
+        assert_eq!(eval("$3 + 1", &[3.0]), 1.0);
+        assert_eq!(eval("$rank * 4", &[]), 2.0);
+        let x = eval("$rand * 360", &[]);
+        assert!((0.0..360.0).contains(&x));
+    }
+
+    #[test]
+    fn errors() {
+        for text in ["", "1 +", "(1 + 2", "$0", "$foo", "1..2", "2 * )"] {
+            assert!(Expr::parse(text).is_err(), "{:?} parsed", text);
+        }
+    }
+}
@@ -0,0 +1,441 @@
+// Loader and interpreter for BulletML (http://www.asahi-net.or.jp/~cs8k-cyu/bulletml/index_e.html)
+//
+// Documents are compiled into flat arenas of actions, bullets and fires that refer to each other by index,
+// so that the state of a running pattern is plain data and can be cloned and saved along with the game
+
+use std::{
+    collections::HashMap,
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+use log::{error, info};
+use roxmltree::Node;
+
+use self::expr::Expr;
+
+mod expr;
+mod runner;
+
+pub use runner::{Actor, Spawn};
+
+#[derive(Clone, Copy, Debug)]
+pub enum DirectionKind {
+    Aim,
+    Absolute,
+    Relative,
+    Sequence,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum SpeedKind {
+    Absolute,
+    Relative,
+    Sequence,
+}
+
+#[derive(Clone, Debug)]
+pub struct Direction {
+    pub kind: DirectionKind,
+    pub value: Expr,
+}
+
+#[derive(Clone, Debug)]
+pub struct Speed {
+    pub kind: SpeedKind,
+    pub value: Expr,
+}
+
+// Inline definitions and references both point into the arenas, references just bring their own parameters
+#[derive(Clone, Debug)]
+pub struct Ref {
+    pub index: usize,
+    pub params: Option<Vec<Expr>>,
+}
+
+#[derive(Clone, Debug)]
+pub enum Step {
+    Repeat {
+        times: Expr,
+        action: Ref,
+    },
+    Fire(Ref),
+    ChangeSpeed {
+        speed: Speed,
+        term: Expr,
+    },
+    ChangeDirection {
+        direction: Direction,
+        term: Expr,
+    },
+    Accel {
+        horizontal: Option<Speed>,
+        vertical: Option<Speed>,
+        term: Expr,
+    },
+    Wait(Expr),
+    Vanish,
+    Action(Ref),
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BulletDef {
+    pub direction: Option<Direction>,
+    pub speed: Option<Speed>,
+    pub actions: Vec<Ref>,
+}
+
+#[derive(Clone, Debug)]
+pub struct FireDef {
+    pub direction: Option<Direction>,
+    pub speed: Option<Speed>,
+    pub bullet: Ref,
+}
+
+pub struct Pattern {
+    pub name: String,
+    pub actions: Vec<Vec<Step>>,
+    pub bullets: Vec<BulletDef>,
+    pub fires: Vec<FireDef>,
+    // Every action labeled "top", "top1", "top2", etc, which all run in parallel
+    pub top: Vec<usize>,
+}
+
+impl Pattern {
+    pub fn parse(name: &str, text: &str) -> Result<Self, String> {
+        let doc = roxmltree::Document::parse(text).map_err(|e| e.to_string())?;
+        let root = doc.root_element();
+        if root.tag_name().name() != "bulletml" {
+            return Err("root element isn't <bulletml>".to_string());
+        }
+
+        // bullettest is a vertical shooter, so horizontal documents are just played as if they were vertical
+
+        let mut compiler = Compiler::default();
+
+        // Reserve a slot for every label up front so that references can point forwards
+        for node in root.descendants().filter(|x| x.is_element()) {
+            let Some(label) = node.attribute("label") else {
+                continue;
+            };
+            let label = label.to_string();
+            match node.tag_name().name() {
+                "action" => {
+                    compiler.action_labels.insert(label, compiler.actions.len());
+                    compiler.actions.push(Vec::new());
+                }
+                "bullet" => {
+                    compiler.bullet_labels.insert(label, compiler.bullets.len());
+                    compiler.bullets.push(Default::default());
+                }
+                "fire" => {
+                    compiler.fire_labels.insert(label, compiler.fires.len());
+                    compiler.fires.push(None);
+                }
+                _ => {}
+            }
+        }
+
+        let mut top = Vec::new();
+        for node in root.children().filter(|x| x.is_element()) {
+            match node.tag_name().name() {
+                "action" => {
+                    let index = compiler.action(node)?;
+                    if node
+                        .attribute("label")
+                        .map(|x| x.starts_with("top"))
+                        .unwrap_or(false)
+                    {
+                        top.push(index);
+                    }
+                }
+                "bullet" => {
+                    compiler.bullet(node)?;
+                }
+                "fire" => {
+                    compiler.fire(node)?;
+                }
+                x => return Err(format!("unexpected <{}> in <bulletml>", x)),
+            }
+        }
+        if top.is_empty() {
+            return Err("no top action".to_string());
+        }
+
+        Ok(Pattern {
+            name: name.to_string(),
+            actions: compiler.actions,
+            bullets: compiler.bullets,
+            fires: compiler
+                .fires
+                .into_iter()
+                .enumerate()
+                .map(|(i, x)| x.ok_or_else(|| format!("fire #{} is never defined", i)))
+                .collect::<Result<_, _>>()?,
+            top,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Compiler {
+    actions: Vec<Vec<Step>>,
+    bullets: Vec<BulletDef>,
+    // Only None while a labeled fire hasn't been compiled yet
+    fires: Vec<Option<FireDef>>,
+    action_labels: HashMap<String, usize>,
+    bullet_labels: HashMap<String, usize>,
+    fire_labels: HashMap<String, usize>,
+}
+
+fn elements<'a, 'b>(node: Node<'a, 'b>) -> impl Iterator<Item = Node<'a, 'b>> {
+    node.children().filter(|x| x.is_element())
+}
+
+fn child<'a, 'b>(node: Node<'a, 'b>, name: &str) -> Option<Node<'a, 'b>> {
+    elements(node).find(|x| x.tag_name().name() == name)
+}
+
+fn expr(node: Node) -> Result<Expr, String> {
+    Expr::parse(node.text().unwrap_or(""))
+}
+
+fn required_expr(node: Node, name: &str) -> Result<Expr, String> {
+    let parent = node.tag_name().name();
+    expr(child(node, name).ok_or_else(|| format!("<{}> is missing <{}>", parent, name))?)
+}
+
+fn direction(node: Node) -> Result<Direction, String> {
+    Ok(Direction {
+        kind: match node.attribute("type").unwrap_or("aim") {
+            "aim" => DirectionKind::Aim,
+            "absolute" => DirectionKind::Absolute,
+            "relative" => DirectionKind::Relative,
+            "sequence" => DirectionKind::Sequence,
+            x => return Err(format!("unknown direction type {:?}", x)),
+        },
+        value: expr(node)?,
+    })
+}
+
+fn speed(node: Node) -> Result<Speed, String> {
+    Ok(Speed {
+        kind: match node.attribute("type").unwrap_or("absolute") {
+            "absolute" => SpeedKind::Absolute,
+            "relative" => SpeedKind::Relative,
+            "sequence" => SpeedKind::Sequence,
+            x => return Err(format!("unknown speed type {:?}", x)),
+        },
+        value: expr(node)?,
+    })
+}
+
+fn params(node: Node) -> Result<Vec<Expr>, String> {
+    elements(node)
+        .filter(|x| x.tag_name().name() == "param")
+        .map(expr)
+        .collect()
+}
+
+fn lookup(labels: &HashMap<String, usize>, node: Node) -> Result<usize, String> {
+    let label = node
+        .attribute("label")
+        .ok_or_else(|| format!("<{}> without a label", node.tag_name().name()))?;
+    labels
+        .get(label)
+        .copied()
+        .ok_or_else(|| format!("<{}> to unknown label {:?}", node.tag_name().name(), label))
+}
+
+impl Compiler {
+    // Labeled definitions go into their reserved slot, everything else gets a new one
+    fn slot<T>(arena: &mut Vec<T>, labels: &HashMap<String, usize>, node: Node, empty: T) -> usize {
+        match node.attribute("label").and_then(|x| labels.get(x)) {
+            Some(x) => *x,
+            None => {
+                arena.push(empty);
+                arena.len() - 1
+            }
+        }
+    }
+
+    fn action(&mut self, node: Node) -> Result<usize, String> {
+        let index = Self::slot(&mut self.actions, &self.action_labels, node, Vec::new());
+
+        let mut steps = Vec::new();
+        for node in elements(node) {
+            steps.push(match node.tag_name().name() {
+                "repeat" => Step::Repeat {
+                    times: required_expr(node, "times")?,
+                    action: self.action_or_ref(node)?,
+                },
+                "fire" => Step::Fire(Ref {
+                    index: self.fire(node)?,
+                    params: None,
+                }),
+                "fireRef" => Step::Fire(Ref {
+                    index: lookup(&self.fire_labels, node)?,
+                    params: Some(params(node)?),
+                }),
+                "changeSpeed" => Step::ChangeSpeed {
+                    speed: speed(child(node, "speed").ok_or("<changeSpeed> is missing <speed>")?)?,
+                    term: required_expr(node, "term")?,
+                },
+                "changeDirection" => Step::ChangeDirection {
+                    direction: direction(
+                        child(node, "direction")
+                            .ok_or("<changeDirection> is missing <direction>")?,
+                    )?,
+                    term: required_expr(node, "term")?,
+                },
+                "accel" => Step::Accel {
+                    horizontal: child(node, "horizontal").map(speed).transpose()?,
+                    vertical: child(node, "vertical").map(speed).transpose()?,
+                    term: required_expr(node, "term")?,
+                },
+                "wait" => Step::Wait(expr(node)?),
+                "vanish" => Step::Vanish,
+                "action" => Step::Action(Ref {
+                    index: self.action(node)?,
+                    params: None,
+                }),
+                "actionRef" => Step::Action(Ref {
+                    index: lookup(&self.action_labels, node)?,
+                    params: Some(params(node)?),
+                }),
+                x => return Err(format!("unexpected <{}> in <action>", x)),
+            });
+        }
+
+        self.actions[index] = steps;
+        Ok(index)
+    }
+
+    fn action_or_ref(&mut self, node: Node) -> Result<Ref, String> {
+        if let Some(action) = child(node, "action") {
+            Ok(Ref {
+                index: self.action(action)?,
+                params: None,
+            })
+        } else if let Some(action_ref) = child(node, "actionRef") {
+            Ok(Ref {
+                index: lookup(&self.action_labels, action_ref)?,
+                params: Some(params(action_ref)?),
+            })
+        } else {
+            Err(format!("<{}> without an action", node.tag_name().name()))
+        }
+    }
+
+    fn bullet(&mut self, node: Node) -> Result<usize, String> {
+        let index = Self::slot(
+            &mut self.bullets,
+            &self.bullet_labels,
+            node,
+            Default::default(),
+        );
+
+        let mut bullet = BulletDef {
+            direction: child(node, "direction").map(direction).transpose()?,
+            speed: child(node, "speed").map(speed).transpose()?,
+            actions: Vec::new(),
+        };
+        for node in elements(node) {
+            match node.tag_name().name() {
+                "action" => bullet.actions.push(Ref {
+                    index: self.action(node)?,
+                    params: None,
+                }),
+                "actionRef" => bullet.actions.push(Ref {
+                    index: lookup(&self.action_labels, node)?,
+                    params: Some(params(node)?),
+                }),
+                _ => {}
+            }
+        }
+
+        self.bullets[index] = bullet;
+        Ok(index)
+    }
+
+    fn fire(&mut self, node: Node) -> Result<usize, String> {
+        let index = Self::slot(&mut self.fires, &self.fire_labels, node, None);
+
+        let bullet = if let Some(bullet) = child(node, "bullet") {
+            Ref {
+                index: self.bullet(bullet)?,
+                params: None,
+            }
+        } else if let Some(bullet_ref) = child(node, "bulletRef") {
+            Ref {
+                index: lookup(&self.bullet_labels, bullet_ref)?,
+                params: Some(params(bullet_ref)?),
+            }
+        } else {
+            return Err("<fire> without a bullet".to_string());
+        };
+
+        self.fires[index] = Some(FireDef {
+            direction: child(node, "direction").map(direction).transpose()?,
+            speed: child(node, "speed").map(speed).transpose()?,
+            bullet,
+        });
+        Ok(index)
+    }
+}
+
+// Every BulletML pattern that enemies can pick from
+#[derive(Default)]
+pub struct PatternLibrary {
+    pub patterns: Vec<Pattern>,
+}
+
+impl PatternLibrary {
+    // Loads every .xml file in a directory, skipping the ones that fail to parse
+    pub fn load_dir<P: AsRef<Path>>(dir: P) -> Self {
+        let mut paths = match std::fs::read_dir(dir.as_ref()) {
+            Ok(x) => x
+                .filter_map(|x| x.ok().map(|x| x.path()))
+                .filter(|x| x.extension().map(|x| x == "xml").unwrap_or(false))
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                error!(
+                    "Failed to read BulletML directory {}: {}",
+                    dir.as_ref().display(),
+                    e
+                );
+                Vec::new()
+            }
+        };
+        // Pattern indices end up in saved states, so the order has to be stable
+        paths.sort();
+
+        let mut library = PatternLibrary::default();
+        for path in paths {
+            let name = path.file_stem().unwrap().to_string_lossy().to_string();
+            let pattern = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|x| Pattern::parse(&name, &x));
+            match pattern {
+                Ok(x) => library.patterns.push(x),
+                Err(e) => error!("Failed to load BulletML pattern {}: {}", path.display(), e),
+            }
+        }
+
+        info!("Loaded {} BulletML patterns", library.patterns.len());
+        library
+    }
+
+    // Opt-in through the BULLETTEST_BULLETML environment variable, empty otherwise
+    pub fn shared() -> Arc<PatternLibrary> {
+        static LIBRARY: OnceLock<Arc<PatternLibrary>> = OnceLock::new();
+        LIBRARY
+            .get_or_init(|| {
+                Arc::new(match std::env::var_os("BULLETTEST_BULLETML") {
+                    Some(dir) => PatternLibrary::load_dir(dir),
+                    None => Default::default(),
+                })
+            })
+            .clone()
+    }
+}
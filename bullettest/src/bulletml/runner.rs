@@ -0,0 +1,712 @@
+use std::io::{Error, ErrorKind, Read, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use rand::Rng;
+
+use super::{expr::Env, Direction, DirectionKind, Pattern, Ref, Speed, SpeedKind, Step};
+use bulletrl_common::Vector2;
+
+// Difficulty passed to $rank, patterns are expected to scale from 0.0 to 1.0
+const RANK: f32 = 0.5;
+
+// Guards against patterns like an empty <repeat> with a huge count hanging the game
+const STEP_BUDGET: u32 = 10000;
+
+// A bullet fired by a pattern, which is only given a runner if it has actions of its own
+pub struct Spawn {
+    pub pos: Vector2,
+    pub velocity: Vector2,
+    pub runner: Option<Actor>,
+}
+
+// A gradual change from <changeSpeed>, <changeDirection> or <accel>
+#[derive(Clone, Copy, Default)]
+struct Change {
+    per_frame: f32,
+    frames_left: u32,
+}
+
+impl Change {
+    fn new(from: f32, to: f32, term: u32) -> Self {
+        let term = term.max(1);
+        Change {
+            per_frame: (to - from) / term as f32,
+            frames_left: term,
+        }
+    }
+
+    fn apply(&mut self, value: &mut f32) {
+        if self.frames_left > 0 {
+            *value += self.per_frame;
+            self.frames_left -= 1;
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Frame {
+    action: usize,
+    pc: usize,
+    // Iterations left after the current one, for <repeat>
+    repeat: u32,
+    params: Vec<f32>,
+}
+
+#[derive(Clone)]
+struct Thread {
+    frames: Vec<Frame>,
+    wait: u32,
+}
+
+// Runs the actions of an enemy or a bullet
+// Angles are in degrees, with 0 pointing up and increasing clockwise like in every other BulletML implementation
+#[derive(Clone)]
+pub struct Actor {
+    pub pattern: usize,
+    threads: Vec<Thread>,
+    direction: f32,
+    speed: f32,
+    // Extra velocity from <accel>
+    mx: f32,
+    my: f32,
+    // For "sequence" fires
+    last_direction: f32,
+    last_speed: f32,
+    speed_change: Change,
+    direction_change: Change,
+    mx_change: Change,
+    my_change: Change,
+    pub vanished: bool,
+}
+
+impl Actor {
+    // Runs every top action of a pattern in parallel
+    pub fn new(pattern_index: usize, pattern: &Pattern) -> Self {
+        let threads = pattern
+            .top
+            .iter()
+            .map(|&action| Thread::new(action, Vec::new()))
+            .collect();
+        Actor::with_threads(pattern_index, threads, 180.0, 0.0)
+    }
+
+    fn with_threads(pattern: usize, threads: Vec<Thread>, direction: f32, speed: f32) -> Self {
+        Actor {
+            pattern,
+            threads,
+            direction,
+            speed,
+            mx: 0.0,
+            my: 0.0,
+            last_direction: direction,
+            last_speed: speed,
+            speed_change: Default::default(),
+            direction_change: Default::default(),
+            mx_change: Default::default(),
+            my_change: Default::default(),
+            vanished: false,
+        }
+    }
+
+    pub fn velocity(&self) -> Vector2 {
+        let angle = self.direction.to_radians();
+        Vector2::new(
+            angle.sin() * self.speed + self.mx,
+            -angle.cos() * self.speed + self.my,
+        )
+    }
+
+    pub fn tick<R: Rng + ?Sized>(
+        &mut self,
+        pattern: &Pattern,
+        pos: Vector2,
+        target: Vector2,
+        rng: &mut R,
+        spawns: &mut Vec<Spawn>,
+    ) {
+        let mut threads = std::mem::take(&mut self.threads);
+        for thread in threads.iter_mut() {
+            if thread.wait > 0 {
+                thread.wait -= 1;
+                if thread.wait > 0 {
+                    continue;
+                }
+            }
+            self.run(thread, pattern, pos, target, rng, spawns);
+        }
+        threads.retain(|x| !x.frames.is_empty());
+        self.threads = threads;
+
+        self.speed_change.apply(&mut self.speed);
+        self.direction_change.apply(&mut self.direction);
+        self.mx_change.apply(&mut self.mx);
+        self.my_change.apply(&mut self.my);
+    }
+
+    fn run<R: Rng + ?Sized>(
+        &mut self,
+        thread: &mut Thread,
+        pattern: &Pattern,
+        pos: Vector2,
+        target: Vector2,
+        rng: &mut R,
+        spawns: &mut Vec<Spawn>,
+    ) {
+        for _ in 0..STEP_BUDGET {
+            let Some(frame) = thread.frames.last_mut() else {
+                return;
+            };
+            let step = match pattern.actions.get(frame.action) {
+                Some(steps) if frame.pc < steps.len() => &steps[frame.pc],
+                Some(_) if frame.repeat > 0 => {
+                    frame.repeat -= 1;
+                    frame.pc = 0;
+                    continue;
+                }
+                // Also catches a bad action index from a corrupted state
+                _ => {
+                    thread.frames.pop();
+                    continue;
+                }
+            };
+            frame.pc += 1;
+
+            let params = frame.params.clone();
+            let mut env = Env {
+                params: &params,
+                rank: RANK,
+                rng: &mut *rng,
+            };
+
+            match step {
+                Step::Repeat { times, action } => {
+                    let times = times.eval(&mut env).floor();
+                    if times >= 1.0 {
+                        let mut frame = Frame::new(action, &mut env);
+                        frame.repeat = times as u32 - 1;
+                        thread.frames.push(frame);
+                    }
+                }
+                Step::Fire(fire) => {
+                    let frame = Frame::new(fire, &mut env);
+                    self.fire(pattern, fire.index, frame.params, pos, target, rng, spawns);
+                }
+                Step::ChangeSpeed { speed, term } => {
+                    let term = term.eval(&mut env).max(0.0) as u32;
+                    self.speed_change = match speed.kind {
+                        SpeedKind::Sequence => Change {
+                            per_frame: speed.value.eval(&mut env),
+                            frames_left: term,
+                        },
+                        _ => {
+                            let to = eval_speed(speed, self.speed, self.speed, &mut env);
+                            Change::new(self.speed, to, term)
+                        }
+                    };
+                }
+                Step::ChangeDirection { direction, term } => {
+                    let term = term.eval(&mut env).max(0.0) as u32;
+                    self.direction_change = match direction.kind {
+                        DirectionKind::Sequence => Change {
+                            per_frame: direction.value.eval(&mut env),
+                            frames_left: term,
+                        },
+                        _ => {
+                            let to = eval_direction(
+                                direction,
+                                pos,
+                                target,
+                                self.direction,
+                                self.direction,
+                                &mut env,
+                            );
+                            // Always turn the short way around
+                            let delta = (to - self.direction + 180.0).rem_euclid(360.0) - 180.0;
+                            Change::new(self.direction, self.direction + delta, term)
+                        }
+                    };
+                }
+                Step::Accel {
+                    horizontal,
+                    vertical,
+                    term,
+                } => {
+                    let term = term.eval(&mut env).max(0.0) as u32;
+                    if let Some(x) = horizontal {
+                        self.mx_change = accel(x, self.mx, term, &mut env);
+                    }
+                    if let Some(y) = vertical {
+                        self.my_change = accel(y, self.my, term, &mut env);
+                    }
+                }
+                Step::Wait(frames) => {
+                    let frames = frames.eval(&mut env).max(0.0) as u32;
+                    if frames > 0 {
+                        thread.wait = frames;
+                        return;
+                    }
+                }
+                Step::Vanish => {
+                    self.vanished = true;
+                    thread.frames.clear();
+                    return;
+                }
+                Step::Action(action) => {
+                    let frame = Frame::new(action, &mut env);
+                    thread.frames.push(frame);
+                }
+            }
+        }
+
+        // Ran out of budget, so this thread is most likely stuck
+        thread.frames.clear();
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn fire<R: Rng + ?Sized>(
+        &mut self,
+        pattern: &Pattern,
+        fire: usize,
+        params: Vec<f32>,
+        pos: Vector2,
+        target: Vector2,
+        rng: &mut R,
+        spawns: &mut Vec<Spawn>,
+    ) {
+        let Some(fire) = pattern.fires.get(fire) else {
+            return;
+        };
+        let Some(bullet) = pattern.bullets.get(fire.bullet.index) else {
+            return;
+        };
+
+        // Parameters given to the fire are visible to its direction and speed, the bullet gets its own
+        let mut env = Env {
+            params: &params,
+            rank: RANK,
+            rng: &mut *rng,
+        };
+        let bullet_params = Frame::new(&fire.bullet, &mut env).params;
+
+        let direction = match &fire.direction {
+            Some(x) => eval_direction(
+                x,
+                pos,
+                target,
+                self.direction,
+                self.last_direction,
+                &mut env,
+            ),
+            None => {
+                let mut env = Env {
+                    params: &bullet_params,
+                    rank: RANK,
+                    rng: &mut *rng,
+                };
+                match &bullet.direction {
+                    Some(x) => eval_direction(
+                        x,
+                        pos,
+                        target,
+                        self.direction,
+                        self.last_direction,
+                        &mut env,
+                    ),
+                    None => aim(pos, target),
+                }
+            }
+        };
+
+        let mut env = Env {
+            params: &params,
+            rank: RANK,
+            rng: &mut *rng,
+        };
+        let speed = match &fire.speed {
+            Some(x) => eval_speed(x, self.speed, self.last_speed, &mut env),
+            None => {
+                let mut env = Env {
+                    params: &bullet_params,
+                    rank: RANK,
+                    rng: &mut *rng,
+                };
+                match &bullet.speed {
+                    Some(x) => eval_speed(x, self.speed, self.last_speed, &mut env),
+                    None => 1.0,
+                }
+            }
+        };
+
+        self.last_direction = direction;
+        self.last_speed = speed;
+
+        let mut env = Env {
+            params: &bullet_params,
+            rank: RANK,
+            rng: &mut *rng,
+        };
+        let threads = bullet
+            .actions
+            .iter()
+            .map(|x| {
+                let frame = Frame::new(x, &mut env);
+                Thread::new(frame.action, frame.params)
+            })
+            .collect::<Vec<_>>();
+        let actor = Actor::with_threads(self.pattern, threads, direction, speed);
+
+        spawns.push(Spawn {
+            pos,
+            velocity: actor.velocity(),
+            runner: if actor.threads.is_empty() {
+                None
+            } else {
+                Some(actor)
+            },
+        });
+    }
+
+    pub fn write<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        w.write_u16::<LittleEndian>(self.pattern as u16)?;
+        for x in [
+            self.direction,
+            self.speed,
+            self.mx,
+            self.my,
+            self.last_direction,
+            self.last_speed,
+        ] {
+            w.write_f32::<LittleEndian>(x)?;
+        }
+        for x in [
+            self.speed_change,
+            self.direction_change,
+            self.mx_change,
+            self.my_change,
+        ] {
+            w.write_f32::<LittleEndian>(x.per_frame)?;
+            w.write_u32::<LittleEndian>(x.frames_left)?;
+        }
+        w.write_u8(self.vanished as u8)?;
+
+        w.write_u32::<LittleEndian>(self.threads.len() as u32)?;
+        for thread in &self.threads {
+            w.write_u32::<LittleEndian>(thread.wait)?;
+            w.write_u32::<LittleEndian>(thread.frames.len() as u32)?;
+            for frame in &thread.frames {
+                w.write_u32::<LittleEndian>(frame.action as u32)?;
+                w.write_u32::<LittleEndian>(frame.pc as u32)?;
+                w.write_u32::<LittleEndian>(frame.repeat)?;
+                w.write_u32::<LittleEndian>(frame.params.len() as u32)?;
+                for x in &frame.params {
+                    w.write_f32::<LittleEndian>(*x)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn read<R: Read>(r: &mut R) -> Result<Self, Error> {
+        let mut actor =
+            Actor::with_threads(r.read_u16::<LittleEndian>()? as usize, Vec::new(), 0.0, 0.0);
+        for x in [
+            &mut actor.direction,
+            &mut actor.speed,
+            &mut actor.mx,
+            &mut actor.my,
+            &mut actor.last_direction,
+            &mut actor.last_speed,
+        ] {
+            *x = r.read_f32::<LittleEndian>()?;
+        }
+        for x in [
+            &mut actor.speed_change,
+            &mut actor.direction_change,
+            &mut actor.mx_change,
+            &mut actor.my_change,
+        ] {
+            x.per_frame = r.read_f32::<LittleEndian>()?;
+            x.frames_left = r.read_u32::<LittleEndian>()?;
+        }
+        actor.vanished = match r.read_u8()? {
+            0 => false,
+            1 => true,
+            x => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("bad vanished flag {}", x),
+                ))
+            }
+        };
+
+        for _ in 0..r.read_u32::<LittleEndian>()? {
+            let mut thread = Thread {
+                frames: Vec::new(),
+                wait: r.read_u32::<LittleEndian>()?,
+            };
+            for _ in 0..r.read_u32::<LittleEndian>()? {
+                let mut frame = Frame {
+                    action: r.read_u32::<LittleEndian>()? as usize,
+                    pc: r.read_u32::<LittleEndian>()? as usize,
+                    repeat: r.read_u32::<LittleEndian>()?,
+                    params: Vec::new(),
+                };
+                for _ in 0..r.read_u32::<LittleEndian>()? {
+                    frame.params.push(r.read_f32::<LittleEndian>()?);
+                }
+                thread.frames.push(frame);
+            }
+            actor.threads.push(thread);
+        }
+
+        Ok(actor)
+    }
+}
+
+impl Thread {
+    fn new(action: usize, params: Vec<f32>) -> Self {
+        Thread {
+            frames: vec![Frame {
+                action,
+                pc: 0,
+                repeat: 0,
+                params,
+            }],
+            wait: 0,
+        }
+    }
+}
+
+impl Frame {
+    // References get their parameters evaluated, inline definitions share the caller's
+    fn new<R: Rng + ?Sized>(action: &Ref, env: &mut Env<R>) -> Self {
+        Frame {
+            action: action.index,
+            pc: 0,
+            repeat: 0,
+            params: match &action.params {
+                Some(params) => params.iter().map(|x| x.eval(env)).collect(),
+                None => env.params.to_vec(),
+            },
+        }
+    }
+}
+
+fn aim(pos: Vector2, target: Vector2) -> f32 {
+    (target.x - pos.x).atan2(-(target.y - pos.y)).to_degrees()
+}
+
+fn eval_direction<R: Rng + ?Sized>(
+    direction: &Direction,
+    pos: Vector2,
+    target: Vector2,
+    current: f32,
+    last: f32,
+    env: &mut Env<R>,
+) -> f32 {
+    let value = direction.value.eval(env);
+    match direction.kind {
+        DirectionKind::Aim => aim(pos, target) + value,
+        DirectionKind::Absolute => value,
+        DirectionKind::Relative => current + value,
+        DirectionKind::Sequence => last + value,
+    }
+}
+
+fn eval_speed<R: Rng + ?Sized>(speed: &Speed, current: f32, last: f32, env: &mut Env<R>) -> f32 {
+    let value = speed.value.eval(env);
+    match speed.kind {
+        SpeedKind::Absolute => value,
+        SpeedKind::Relative => current + value,
+        SpeedKind::Sequence => last + value,
+    }
+}
+
+fn accel<R: Rng + ?Sized>(speed: &Speed, current: f32, term: u32, env: &mut Env<R>) -> Change {
+    match speed.kind {
+        SpeedKind::Sequence => Change {
+            per_frame: speed.value.eval(env),
+            frames_left: term,
+        },
+        _ => {
+            let to = eval_speed(speed, current, current, env);
+            Change::new(current, to, term)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    // The target is straight below, so aimed things go down
+    const POS: Vector2 = Vector2 { x: 100.0, y: 100.0 };
+    const TARGET: Vector2 = Vector2 { x: 100.0, y: 200.0 };
+
+    fn pattern(top: &str, rest: &str) -> Pattern {
+        let text = format!(
+            "<bulletml><action label=\"top\">{}</action>{}</bulletml>",
+            top, rest
+        );
+        Pattern::parse("test", &text).unwrap()
+    }
+
+    // Spawns from each tick
+    fn run(pattern: &Pattern, actor: &mut Actor, ticks: usize) -> Vec<Vec<Spawn>> {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        (0..ticks)
+            .map(|_| {
+                let mut spawns = Vec::new();
+                actor.tick(pattern, POS, TARGET, &mut rng, &mut spawns);
+                spawns
+            })
+            .collect()
+    }
+
+    fn close(a: Vector2, b: Vector2) -> bool {
+        a.distance(b) < 1e-4
+    }
+
+    #[test]
+    fn fire() {
+        let pattern = pattern(
+            "<fire><direction type=\"absolute\">90</direction><speed>2</speed><bullet/></fire>
+            <fire><bullet><speed>3</speed></bullet></fire>",
+            "",
+        );
+        let mut actor = Actor::new(0, &pattern);
+        let spawns = run(&pattern, &mut actor, 1).remove(0);
+        assert_eq!(spawns.len(), 2);
+        assert!(close(spawns[0].velocity, Vector2::new(2.0, 0.0)));
+        // Aimed by default
+        assert!(close(spawns[1].velocity, Vector2::new(0.0, 3.0)));
+        assert!(spawns.iter().all(|x| x.runner.is_none() && x.pos == POS));
+    }
+
+    #[test]
+    fn bullet_ref() {
+        let pattern = pattern(
+            "<fire><direction type=\"absolute\">0</direction>
+                <bulletRef label=\"b\"><param>1 + 2</param></bulletRef></fire>",
+            "<bullet label=\"b\"><speed>$1</speed><action><vanish/></action></bullet>",
+        );
+        let mut actor = Actor::new(0, &pattern);
+        let spawns = run(&pattern, &mut actor, 1).remove(0);
+        assert_eq!(spawns.len(), 1);
+        assert!(close(spawns[0].velocity, Vector2::new(0.0, -3.0)));
+        // The bullet has an action, so it runs on its own
+        let mut runner = spawns.into_iter().next().unwrap().runner.unwrap();
+        run(&pattern, &mut runner, 1);
+        assert!(runner.vanished);
+    }
+
+    #[test]
+    fn repeat_and_wait() {
+        let pattern = pattern(
+            "<repeat><times>2 + 1</times><action>
+                <fire><direction type=\"sequence\">90</direction><bullet/></fire>
+                <wait>2</wait>
+            </action></repeat>",
+            "",
+        );
+        let mut actor = Actor::new(0, &pattern);
+        let spawns = run(&pattern, &mut actor, 8);
+        let counts = spawns.iter().map(|x| x.len()).collect::<Vec<_>>();
+        assert_eq!(counts, [1, 0, 1, 0, 1, 0, 0, 0]);
+        // Each one turned another 90 degrees from the last, starting from the actor's own 180
+        let velocities = spawns.iter().flatten().map(|x| x.velocity);
+        for (velocity, expected) in velocities.zip([(-1.0, 0.0), (0.0, -1.0), (1.0, 0.0)]) {
+            assert!(close(velocity, Vector2::new(expected.0, expected.1)));
+        }
+    }
+
+    #[test]
+    fn change_speed() {
+        let pattern = pattern(
+            "<changeSpeed><speed>4</speed><term>4</term></changeSpeed>",
+            "",
+        );
+        let mut actor = Actor::new(0, &pattern);
+        run(&pattern, &mut actor, 2);
+        assert!(close(actor.velocity(), Vector2::new(0.0, 2.0)));
+        run(&pattern, &mut actor, 4);
+        assert!(close(actor.velocity(), Vector2::new(0.0, 4.0)));
+    }
+
+    #[test]
+    fn change_direction() {
+        let pattern = pattern(
+            "<changeSpeed><speed>1</speed><term>1</term></changeSpeed>
+            <changeDirection><direction type=\"absolute\">90</direction><term>3</term></changeDirection>",
+            "",
+        );
+        let mut actor = Actor::new(0, &pattern);
+        run(&pattern, &mut actor, 1);
+        let (sin, cos) = 150f32.to_radians().sin_cos();
+        assert!(close(actor.velocity(), Vector2::new(sin, -cos)));
+        run(&pattern, &mut actor, 4);
+        assert!(close(actor.velocity(), Vector2::new(1.0, 0.0)));
+    }
+
+    #[test]
+    fn accel() {
+        let pattern = pattern(
+            "<accel><horizontal>3</horizontal><vertical type=\"sequence\">-1</vertical><term>3</term></accel>",
+            "",
+        );
+        let mut actor = Actor::new(0, &pattern);
+        run(&pattern, &mut actor, 5);
+        assert!(close(actor.velocity(), Vector2::new(3.0, -3.0)));
+    }
+
+    #[test]
+    fn vanish() {
+        let pattern = pattern("<wait>2</wait><vanish/>", "");
+        let mut actor = Actor::new(0, &pattern);
+        run(&pattern, &mut actor, 2);
+        assert!(!actor.vanished);
+        run(&pattern, &mut actor, 1);
+        assert!(actor.vanished);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        // top2 recurses 300 levels deep and then unwinds slowly, which is way more frames than fit in a byte
+        let pattern = pattern(
+            "<changeSpeed><speed>2</speed><term>10</term></changeSpeed>
+            <repeat><times>5</times><action><fire><bullet/></fire><wait>3</wait></action></repeat>",
+            "<action label=\"top2\"><actionRef label=\"down\"><param>300</param></actionRef></action>
+            <action label=\"down\">
+                <repeat><times>$1 / $1</times><actionRef label=\"down\"><param>$1 - 1</param></actionRef></repeat>
+                <wait>2</wait>
+            </action>",
+        );
+        let mut actor = Actor::new(0, &pattern);
+        run(&pattern, &mut actor, 1);
+        assert!(actor.threads[1].frames.len() > 300);
+
+        let save = |actor: &Actor| {
+            let mut data = Vec::new();
+            actor.write(&mut data).unwrap();
+            data
+        };
+        let data = save(&actor);
+        let mut loaded = Actor::read(&mut data.as_slice()).unwrap();
+        assert_eq!(save(&loaded), data);
+
+        // Both carry on exactly the same way
+        let spawns = run(&pattern, &mut actor, 40);
+        let loaded_spawns = run(&pattern, &mut loaded, 40);
+        assert!(spawns.iter().any(|x| !x.is_empty()));
+        for (a, b) in spawns.iter().zip(&loaded_spawns) {
+            assert_eq!(a.len(), b.len());
+            assert!(a.iter().zip(b).all(|(a, b)| a.velocity == b.velocity));
+        }
+        assert_eq!(save(&actor), save(&loaded));
+
+        assert!(Actor::read(&mut &data[..data.len() - 1]).is_err());
+    }
+}
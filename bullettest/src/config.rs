@@ -0,0 +1,498 @@
+use std::path::Path;
+
+use bulletrl_common::{RenderStyle, FIELD_WIDTH};
+use rand::{distributions::uniform::SampleUniform, Rng};
+use serde::{Deserialize, Serialize};
+
+// Everything that shapes an episode, loaded from TOML
+// Every field is optional and defaults to how bullettest has always played, see bullettest.toml for a full example
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub player_size: i32,
+    pub enemy_size: i32,
+    pub bullet_size: f32,
+    pub enemy_x_range: Range<f32>,
+    pub enemy_y_range: Range<f32>,
+    pub enemy_limit: usize,
+    pub enemy_spawn_interval: Range<u64>,
+    pub enemy_lifetime: Range<u64>,
+    pub bullet_limit: usize,
+    // In frames, only used by the TCP backend
+    pub timeout: u64,
+    pub frameskip: u32,
+    // Blends the edges of bullets into the RGB observation, see Renderer::antialias
+    pub antialias: bool,
+    // Applied on top of the default render style and BULLETRL_RENDER_STYLE, see RenderStyle::with_overrides
+    pub render_style: String,
+    pub movement: MovementConfig,
+    pub pattern: PatternConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            player_size: 5,
+            enemy_size: 25,
+            bullet_size: 5.0,
+            enemy_x_range: Range(
+                (FIELD_WIDTH as f32 / 2.0) - 60.0,
+                (FIELD_WIDTH as f32 / 2.0) + 60.0,
+            ),
+            enemy_y_range: Range(50.0, 150.0),
+            enemy_limit: 3,
+            enemy_spawn_interval: Range(180, 420),
+            enemy_lifetime: Range(600, 1500),
+            bullet_limit: 640,
+            timeout: 60 * 60,
+            frameskip: 4,
+            antialias: false,
+            render_style: String::new(),
+            movement: Default::default(),
+            pattern: Default::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MovementConfig {
+    pub r#static: StaticConfig,
+    pub sine: SineConfig,
+    pub ease_out_expo: EaseOutExpoConfig,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StaticConfig {
+    pub weight: u32,
+}
+
+impl Default for StaticConfig {
+    fn default() -> Self {
+        StaticConfig { weight: 1 }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SineConfig {
+    pub weight: u32,
+    pub speed: Range<f32>,
+    pub range: Range<f32>,
+}
+
+impl Default for SineConfig {
+    fn default() -> Self {
+        SineConfig {
+            weight: 1,
+            speed: Range(0.02, 0.1),
+            range: Range(90.0, 125.0),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EaseOutExpoConfig {
+    pub weight: u32,
+    pub wait: Range<u64>,
+    pub anim_len: Range<u64>,
+}
+
+impl Default for EaseOutExpoConfig {
+    fn default() -> Self {
+        EaseOutExpoConfig {
+            weight: 1,
+            wait: Range(30, 60),
+            anim_len: Range(30, 60),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PatternConfig {
+    pub spiral: SpiralConfig,
+    pub direct: DirectConfig,
+    pub burst: BurstConfig,
+    pub bulletml: BulletMLConfig,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpiralConfig {
+    pub weight: u32,
+    pub bullet_speed: Range<f32>,
+    pub rot_speed: Range<f32>,
+}
+
+impl Default for SpiralConfig {
+    fn default() -> Self {
+        SpiralConfig {
+            weight: 1,
+            bullet_speed: Range(2.0, 5.0),
+            rot_speed: Range(0.1, 1.0),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DirectConfig {
+    pub weight: u32,
+    pub bullet_speed: Range<f32>,
+    pub spread: Range<f32>,
+    pub divisor: Range<u64>,
+}
+
+impl Default for DirectConfig {
+    fn default() -> Self {
+        DirectConfig {
+            weight: 1,
+            bullet_speed: Range(4.0, 6.0),
+            spread: Range(0.0, 2.0),
+            divisor: Range(4, 8),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BurstConfig {
+    pub weight: u32,
+    pub bullet_speed: Range<f32>,
+    pub spread: Range<f32>,
+    pub divisor: Range<u64>,
+    pub amount: Range<u64>,
+}
+
+impl Default for BurstConfig {
+    fn default() -> Self {
+        BurstConfig {
+            weight: 1,
+            bullet_speed: Range(3.0, 4.0),
+            spread: Range(std::f32::consts::FRAC_PI_4, std::f32::consts::FRAC_PI_2),
+            divisor: Range(6, 10),
+            amount: Range(4, 8),
+        }
+    }
+}
+
+// BulletML patterns are picked before the built-in ones, and only if any were loaded
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BulletMLConfig {
+    pub chance: f64,
+}
+
+impl Default for BulletMLConfig {
+    fn default() -> Self {
+        BulletMLConfig { chance: 0.25 }
+    }
+}
+
+// Written as [min, max] in TOML
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Range<T>(pub T, pub T);
+
+impl<T: SampleUniform + PartialOrd + Copy> Range<T> {
+    // Half-open like most of the original constants were, but a range like [2.0, 2.0] still works
+    pub fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> T {
+        if self.0 < self.1 {
+            rng.gen_range(self.0..self.1)
+        } else {
+            self.0
+        }
+    }
+
+    pub fn sample_inclusive<R: Rng + ?Sized>(&self, rng: &mut R) -> T {
+        rng.gen_range(self.0..=self.1)
+    }
+}
+
+// Range bounds that Config::validate can check, rand panics on infinite float ranges
+trait Bound: PartialOrd {
+    fn is_finite(&self) -> bool;
+}
+
+impl Bound for f32 {
+    fn is_finite(&self) -> bool {
+        f32::is_finite(*self)
+    }
+}
+
+impl Bound for u64 {
+    fn is_finite(&self) -> bool {
+        true
+    }
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let text = std::fs::read_to_string(path.as_ref())
+            .map_err(|e| format!("failed to read {}: {}", path.as_ref().display(), e))?;
+        Config::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let config: Config = toml::from_str(text).map_err(|e| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    // Applies the keys in another TOML document on top of this config, e.g. the options sent with a reset
+    pub fn with_overrides(&self, overrides: &str) -> Result<Self, String> {
+        let mut table = toml::Table::try_from(self).map_err(|e| e.to_string())?;
+        merge(
+            &mut table,
+            overrides
+                .parse::<toml::Table>()
+                .map_err(|e| e.to_string())?,
+        );
+        let config: Config = toml::Value::Table(table)
+            .try_into()
+            .map_err(|e: toml::de::Error| e.to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn to_toml(&self) -> String {
+        toml::to_string(self).expect("config should always serialize")
+    }
+
+    // Catches everything that would otherwise panic in the middle of an episode
+    fn validate(&self) -> Result<(), String> {
+        fn range<T: Bound>(name: &str, x: &Range<T>) -> Result<(), String> {
+            if !(x.0.is_finite() && x.1.is_finite()) {
+                Err(format!("{} has to be finite", name))
+            } else if x.0 <= x.1 {
+                Ok(())
+            } else {
+                Err(format!("{} has a minimum above its maximum", name))
+            }
+        }
+
+        // pick draws from 0 to the sum of the weights
+        fn weights(name: &str, x: &[u32]) -> Result<(), String> {
+            let sum = x.iter().map(|&x| x as u64).sum::<u64>();
+            if sum == 0 {
+                Err(format!("at least one {} needs a weight", name))
+            } else if sum > u32::MAX as u64 {
+                Err(format!(
+                    "{} weights can add up to at most {}",
+                    name,
+                    u32::MAX
+                ))
+            } else {
+                Ok(())
+            }
+        }
+
+        range("enemy_x_range", &self.enemy_x_range)?;
+        range("enemy_y_range", &self.enemy_y_range)?;
+        range("enemy_spawn_interval", &self.enemy_spawn_interval)?;
+        range("enemy_lifetime", &self.enemy_lifetime)?;
+        range("movement.sine.speed", &self.movement.sine.speed)?;
+        range("movement.sine.range", &self.movement.sine.range)?;
+        range(
+            "movement.ease_out_expo.wait",
+            &self.movement.ease_out_expo.wait,
+        )?;
+        range(
+            "movement.ease_out_expo.anim_len",
+            &self.movement.ease_out_expo.anim_len,
+        )?;
+        range(
+            "pattern.spiral.bullet_speed",
+            &self.pattern.spiral.bullet_speed,
+        )?;
+        range("pattern.spiral.rot_speed", &self.pattern.spiral.rot_speed)?;
+        range(
+            "pattern.direct.bullet_speed",
+            &self.pattern.direct.bullet_speed,
+        )?;
+        range("pattern.direct.spread", &self.pattern.direct.spread)?;
+        range("pattern.direct.divisor", &self.pattern.direct.divisor)?;
+        range(
+            "pattern.burst.bullet_speed",
+            &self.pattern.burst.bullet_speed,
+        )?;
+        range("pattern.burst.spread", &self.pattern.burst.spread)?;
+        range("pattern.burst.divisor", &self.pattern.burst.divisor)?;
+        range("pattern.burst.amount", &self.pattern.burst.amount)?;
+
+        // Saved states store the enemy count in a byte
+        if self.enemy_limit == 0 || self.enemy_limit > u8::MAX as usize {
+            return Err(format!("enemy_limit has to be between 1 and {}", u8::MAX));
+        }
+        if self.enemy_spawn_interval.0 == 0 {
+            return Err("enemy_spawn_interval has to be at least 1".to_string());
+        }
+        // The eased position is divided by anim_len, and enemies loop over anim_len + wait frames
+        if self.movement.ease_out_expo.anim_len.0 == 0 {
+            return Err("movement.ease_out_expo.anim_len has to be at least 1".to_string());
+        }
+        // Direct spread is sampled from 0 to itself, and 0 means no spread at all
+        for (name, spread) in [
+            ("pattern.direct.spread", &self.pattern.direct.spread),
+            ("pattern.burst.spread", &self.pattern.burst.spread),
+        ] {
+            if !(spread.0 >= 0.0 && spread.1.is_finite()) {
+                return Err(format!("{} has to be finite and at least 0", name));
+            }
+        }
+        if self.pattern.direct.divisor.0 == 0 || self.pattern.burst.divisor.0 == 0 {
+            return Err("pattern divisors have to be at least 1".to_string());
+        }
+        if self.bullet_limit > u16::MAX as usize {
+            return Err(format!("bullet_limit can be at most {}", u16::MAX));
+        }
+        if self.timeout == 0 || self.frameskip == 0 {
+            return Err("timeout and frameskip have to be at least 1".to_string());
+        }
+        // Whether it parses doesn't depend on what it's applied to
+        RenderStyle::default()
+            .with_overrides(&self.render_style)
+            .map_err(|e| format!("render_style: {}", e))?;
+        if !(0.0..=1.0).contains(&self.pattern.bulletml.chance) {
+            return Err("pattern.bulletml.chance has to be between 0 and 1".to_string());
+        }
+        weights("movement", &self.movement.weights())?;
+        weights("pattern", &self.pattern.weights())?;
+
+        Ok(())
+    }
+}
+
+impl MovementConfig {
+    pub fn weights(&self) -> [u32; 3] {
+        [
+            self.r#static.weight,
+            self.sine.weight,
+            self.ease_out_expo.weight,
+        ]
+    }
+}
+
+impl PatternConfig {
+    pub fn weights(&self) -> [u32; 3] {
+        [self.spiral.weight, self.direct.weight, self.burst.weight]
+    }
+}
+
+// Picks an index with probability proportional to its weight
+// With every weight at 1 this draws exactly what gen_range(0..weights.len()) would, so default seeds are unaffected
+pub fn pick<R: Rng + ?Sized>(rng: &mut R, weights: &[u32]) -> usize {
+    let mut x = rng.gen_range(0..weights.iter().sum::<u32>());
+    for (i, weight) in weights.iter().enumerate() {
+        if x < *weight {
+            return i;
+        }
+        x -= weight;
+    }
+    unreachable!()
+}
+
+fn merge(base: &mut toml::Table, overrides: toml::Table) {
+    for (key, value) in overrides {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(value)) => merge(base, value),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_is_the_default() {
+        let config = Config::parse(include_str!("../bullettest.toml")).unwrap();
+        assert_eq!(config.to_toml(), Config::default().to_toml());
+    }
+
+    #[test]
+    fn overrides() {
+        let config = Config::default()
+            .with_overrides("frameskip = 2\n[pattern.spiral]\nweight = 0")
+            .unwrap();
+        assert_eq!(config.frameskip, 2);
+        assert_eq!(config.pattern.spiral.weight, 0);
+        // Only the keys that were given change, even inside a table
+        assert_eq!(config.pattern.spiral.bullet_speed.1, 5.0);
+        assert_eq!(config.pattern.direct.weight, 1);
+        assert_eq!(config.timeout, Config::default().timeout);
+
+        assert!(Config::default().with_overrides("not toml").is_err());
+        assert!(Config::default().with_overrides("unknown_key = 1").is_err());
+        assert!(Config::default()
+            .with_overrides("frameskip = \"fast\"")
+            .is_err());
+        // The result is validated like a loaded config
+        assert!(Config::default().with_overrides("frameskip = 0").is_err());
+    }
+
+    #[test]
+    fn invalid_configs() {
+        for text in [
+            "enemy_x_range = [200.0, 100.0]",
+            "enemy_limit = 0",
+            "enemy_limit = 256",
+            "enemy_spawn_interval = [0, 10]",
+            "bullet_limit = 65536",
+            "timeout = 0",
+            "frameskip = 0",
+            "movement.ease_out_expo.anim_len = [0, 10]",
+            "pattern.direct.spread = [-1.0, 1.0]",
+            "pattern.burst.spread = [-1.0, -0.5]",
+            "pattern.direct.spread = [0.0, inf]",
+            "pattern.direct.spread = [nan, nan]",
+            "pattern.direct.divisor = [0, 4]",
+            "pattern.bulletml.chance = 1.5",
+            "render_style = \"huge\"",
+            "render_style = \"scaled,player=-1\"",
+            "movement.static.weight = 0\nmovement.sine.weight = 0\nmovement.ease_out_expo.weight = 0",
+            "pattern.spiral.weight = 0\npattern.direct.weight = 0\npattern.burst.weight = 0",
+            "pattern.spiral.weight = 4294967295\npattern.direct.weight = 1",
+            "movement.static.weight = 4294967295\nmovement.sine.weight = 4294967295",
+        ] {
+            assert!(Config::parse(text).is_err(), "{:?} was accepted", text);
+        }
+
+        for text in [
+            "enemy_limit = 255",
+            "movement.ease_out_expo.wait = [0, 0]",
+            "movement.ease_out_expo.anim_len = [1, 1]",
+            "pattern.direct.spread = [0.0, 0.0]",
+            "pattern.spiral.weight = 0",
+            "render_style = \"dual,player=3,core=0.25\"",
+            "pattern.spiral.weight = 4294967293",
+        ] {
+            assert!(Config::parse(text).is_ok(), "{:?} was rejected", text);
+        }
+    }
+
+    #[test]
+    fn infinite_ranges() {
+        for key in [
+            "enemy_x_range",
+            "enemy_y_range",
+            "movement.sine.speed",
+            "movement.sine.range",
+            "pattern.spiral.bullet_speed",
+            "pattern.spiral.rot_speed",
+            "pattern.direct.bullet_speed",
+            "pattern.burst.bullet_speed",
+            "pattern.burst.spread",
+        ] {
+            for value in ["[0.0, inf]", "[-inf, 0.0]", "[-inf, inf]", "[nan, 1.0]"] {
+                let text = format!("{} = {}", key, value);
+                assert!(Config::parse(&text).is_err(), "{:?} was accepted", text);
+            }
+        }
+    }
+}
@@ -1,80 +1,339 @@
-use std::ops::RangeInclusive;
+use std::{
+    io::{Error, ErrorKind, Read, Write},
+    sync::Arc,
+};
 
-use crate::util::{self, check_rect_overlap, Vector2};
-use bulletrl_common::{FIELD_HEIGHT, FIELD_WIDTH};
-use log::info;
-use rand::{
-    distributions::Standard,
-    prelude::{Distribution, ThreadRng},
-    Rng,
+use crate::{
+    bulletml::{Actor, PatternLibrary, Spawn},
+    config::{self, Config},
+    util,
 };
+use bulletrl_common::{
+    Body, Info, Object, Plane, RenderMode, RenderStyle, Renderer, Shape, Vector2, FIELD_HEIGHT,
+    FIELD_WIDTH,
+};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::info;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 
-const PLAYER_SIZE: i32 = 5;
-const ENEMY_SIZE: i32 = 25;
-const ENEMY_X_RANGE: RangeInclusive<f32> =
-    (FIELD_WIDTH as f32 / 2.0) - 60.0..=(FIELD_WIDTH as f32 / 2.0) + 60.0;
-const ENEMY_Y_RANGE: RangeInclusive<f32> = 50.0f32..=150.0f32;
-const BULLET_LIMIT: usize = 640;
+// Bumped whenever the layout of a saved state changes
+const STATE_MAGIC: &[u8; 4] = b"BTST";
+const STATE_VERSION: u16 = 4;
 
+// The player's and bullets' hitboxes are very small, so they're drawn larger to actually be visible
+// Can be changed with BULLETRL_RENDER_STYLE and then render_style in the config, see RenderStyle::with_overrides
+const RENDER_STYLE: RenderStyle = RenderStyle {
+    mode: RenderMode::Scaled,
+    scale: [5.0, 3.0, 1.0, 1.0, 1.0],
+    core_intensity: 0.5,
+};
+
+#[derive(Clone)]
 pub struct Game {
     pub renderer: bulletrl_common::Renderer,
     pub player: Player,
-    pub enemy: Enemy, // TODO: multiple enemies
+    pub enemies: Vec<Enemy>,
     pub bullets: Box<[Option<Bullet>]>,
     pub frame: u64,
+    pub next_spawn: u64,
+    pub seed: u64,
+    pub config: Arc<Config>,
+    pub patterns: Arc<PatternLibrary>,
+    rng: ChaCha8Rng,
 }
 
-impl Default for Game {
-    fn default() -> Self {
+impl Game {
+    // Every random decision in an episode comes from this seed, so the same seed, config and inputs always play out the same way
+    pub fn new(config: Arc<Config>, seed: u64) -> Self {
+        let mut rng = ChaCha8Rng::seed_from_u64(seed);
+        let patterns = PatternLibrary::shared();
+        let mut renderer = Renderer::from_env(FIELD_WIDTH, FIELD_HEIGHT);
+        renderer.style = RENDER_STYLE
+            .with_env_overrides()
+            .with_overrides(&config.render_style)
+            .expect("checked by Config::validate");
         Game {
-            renderer: Default::default(),
+            renderer,
             player: Default::default(),
-            enemy: Default::default(),
-            bullets: (vec![None; BULLET_LIMIT]).into_boxed_slice(),
+            enemies: vec![Enemy::new(&mut rng, &config, &patterns)],
+            bullets: (vec![None; config.bullet_limit]).into_boxed_slice(),
             frame: 0,
+            next_spawn: config.enemy_spawn_interval.sample_inclusive(&mut rng),
+            seed,
+            config,
+            patterns,
+            rng,
         }
     }
-}
 
-impl Game {
+    // A fresh episode with the same config and a random seed
+    pub fn restart(&self) -> Self {
+        Game::new(self.config.clone(), rand::random())
+    }
+
     pub fn tick(&mut self, input: bulletrl_common::Input) -> bool {
         self.frame += 1;
+        let mut spawns = Vec::new();
         for x in self.bullets.iter_mut() {
             if let Some(bullet) = x {
-                if bullet.tick() {
+                if bullet.tick(&self.patterns, self.player.pos, &mut self.rng, &mut spawns) {
                     *x = None;
                 }
             }
         }
-        if self.player.tick(input, &mut self.bullets) {
+        for spawn in spawns {
+            spawn_bullet(&mut self.bullets, &self.config, spawn);
+        }
+        if self
+            .player
+            .tick(input, &mut self.bullets, self.config.player_size)
+        {
             info!(
-                "Player got pichu~n'd, lasted {:.2} seconds...\n{:?} {:?}",
+                "Player got pichu~n'd, lasted {:.2} seconds... (seed {})\n{}",
                 self.frame as f64 / 60.0,
-                self.enemy.movement,
-                self.enemy.pattern
+                self.seed,
+                self.describe_enemies()
             );
             return true;
         }
-        self.enemy.tick(&self.player, &mut self.bullets);
+
+        // Enemies come and go over time, but there's always at least one around
+        self.enemies.retain(|x| x.frame < x.lifetime);
+        if self.enemies.is_empty()
+            || (self.frame >= self.next_spawn && self.enemies.len() < self.config.enemy_limit)
+        {
+            self.enemies
+                .push(Enemy::new(&mut self.rng, &self.config, &self.patterns));
+            self.next_spawn = self.frame
+                + self
+                    .config
+                    .enemy_spawn_interval
+                    .sample_inclusive(&mut self.rng);
+        }
+        for enemy in self.enemies.iter_mut() {
+            enemy.tick(
+                &self.player,
+                &mut self.bullets,
+                &self.config,
+                &self.patterns,
+                &mut self.rng,
+            );
+        }
 
         self.draw();
 
         false
     }
 
-    fn draw(&mut self) {
+    pub fn reward(&self, died: bool) -> f32 {
+        if died {
+            -1.0
+        } else {
+            self.distance_reward()
+        }
+    }
+
+    // Reward being closer on the x-axis to the nearest enemy
+    fn distance_reward(&self) -> f32 {
+        let distance = self
+            .enemies
+            .iter()
+            .map(|x| (self.player.pos.x - x.pos.x).abs())
+            .fold(f32::INFINITY, f32::min);
+        1.0 - (distance.clamp(0.0, 50.0) / 50.0) * 0.5
+    }
+
+    // Everything a trainer might want to log about the current step
+    pub fn info(&self) -> Info {
+        let mut info = Info::new();
+        info.set("seed", self.seed)
+            .set("frame", self.frame)
+            .set("bullets", self.bullets.iter().flatten().count())
+            .set("enemies", self.enemies.len())
+            .set(
+                "enemy_movements",
+                self.enemies
+                    .iter()
+                    .map(|x| x.movement.name())
+                    .collect::<Vec<_>>()
+                    .join(","),
+            )
+            .set(
+                "enemy_patterns",
+                self.enemies
+                    .iter()
+                    .map(|x| match x.pattern {
+                        EnemyPattern::BulletML { pattern } => {
+                            format!("bulletml:{}", self.patterns.patterns[pattern].name)
+                        }
+                        _ => x.pattern.name().to_string(),
+                    })
+                    .collect::<Vec<_>>()
+                    .join(","),
+            )
+            .set("reward.distance", self.distance_reward());
+        info
+    }
+
+    pub fn describe_enemies(&self) -> String {
+        self.enemies
+            .iter()
+            .map(|x| match x.pattern {
+                EnemyPattern::BulletML { pattern } => format!(
+                    "{:?} BulletML({})",
+                    x.movement, self.patterns.patterns[pattern].name
+                ),
+                _ => format!("{:?} {:?}", x.movement, x.pattern),
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    // Snapshots everything that affects the simulation, including the RNG, into a compact binary blob
+    // The renderer is left out since it can always be redrawn
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = Vec::new();
+        self.write_state(&mut w)
+            .expect("writing to a Vec can't fail");
+        w
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), Error> {
+        // Parse into a copy so that a bad blob doesn't leave the game half-loaded
+        let mut game = self.clone();
+        game.read_state(&mut &data[..])?;
+        *self = game;
+        self.draw();
+        Ok(())
+    }
+
+    fn write_state<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        w.write_all(STATE_MAGIC)?;
+        w.write_u16::<LittleEndian>(STATE_VERSION)?;
+
+        w.write_u64::<LittleEndian>(self.seed)?;
+        w.write_u64::<LittleEndian>(self.frame)?;
+        w.write_u64::<LittleEndian>(self.next_spawn)?;
+        w.write_all(&self.rng.get_seed())?;
+        w.write_u64::<LittleEndian>(self.rng.get_stream())?;
+        w.write_u128::<LittleEndian>(self.rng.get_word_pos())?;
+
+        self.player.pos.write(w)?;
+        w.write_u8(self.enemies.len() as u8)?;
+        for enemy in &self.enemies {
+            enemy.write(w)?;
+        }
+
+        // Only live bullets are stored, along with their slot so that the pool layout is identical after loading
+        let live = self.bullets.iter().filter(|x| x.is_some()).count();
+        w.write_u16::<LittleEndian>(live as u16)?;
+        for (i, bullet) in self.bullets.iter().enumerate() {
+            if let Some(bullet) = bullet {
+                w.write_u16::<LittleEndian>(i as u16)?;
+                bullet.pos.write(w)?;
+                bullet.size.write(w)?;
+                bullet.velocity.write(w)?;
+                match &bullet.runner {
+                    Some(runner) => {
+                        w.write_u8(1)?;
+                        runner.write(w)?;
+                    }
+                    None => w.write_u8(0)?,
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_state<R: Read>(&mut self, r: &mut R) -> Result<(), Error> {
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        let version = r.read_u16::<LittleEndian>()?;
+        if &magic != STATE_MAGIC || version != STATE_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("not a v{} bullettest state", STATE_VERSION),
+            ));
+        }
+
+        self.seed = r.read_u64::<LittleEndian>()?;
+        self.frame = r.read_u64::<LittleEndian>()?;
+        self.next_spawn = r.read_u64::<LittleEndian>()?;
+        let mut rng_seed = [0u8; 32];
+        r.read_exact(&mut rng_seed)?;
+        self.rng = ChaCha8Rng::from_seed(rng_seed);
+        self.rng.set_stream(r.read_u64::<LittleEndian>()?);
+        self.rng.set_word_pos(r.read_u128::<LittleEndian>()?);
+
+        // BulletML patterns are stored by index, so they have to come from the same library
+        let pattern_count = self.patterns.patterns.len();
+        let check_pattern = |x: usize| {
+            if x < pattern_count {
+                Ok(x)
+            } else {
+                Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("BulletML pattern {} isn't loaded", x),
+                ))
+            }
+        };
+
+        self.player.pos = Vector2::read(r)?;
+        self.enemies.clear();
+        for _ in 0..r.read_u8()? {
+            let enemy = Enemy::read(r)?;
+            if let EnemyPattern::BulletML { pattern } = enemy.pattern {
+                check_pattern(pattern)?;
+            }
+            if let Some(runner) = &enemy.runner {
+                check_pattern(runner.pattern)?;
+            }
+            self.enemies.push(enemy);
+        }
+
+        self.bullets.fill(None);
+        for _ in 0..r.read_u16::<LittleEndian>()? {
+            let slot = self
+                .bullets
+                .get_mut(r.read_u16::<LittleEndian>()? as usize)
+                .ok_or_else(|| Error::new(ErrorKind::InvalidData, "bullet slot out of range"))?;
+            let mut bullet = Bullet {
+                pos: Vector2::read(r)?,
+                size: Vector2::read(r)?,
+                velocity: Vector2::read(r)?,
+                runner: None,
+            };
+            if r.read_u8()? != 0 {
+                let runner = Actor::read(r)?;
+                check_pattern(runner.pattern)?;
+                bullet.runner = Some(Box::new(runner));
+            }
+            *slot = Some(bullet);
+        }
+
+        Ok(())
+    }
+
+    pub fn draw(&mut self) {
         self.renderer.clear();
+        self.renderer.antialias = self.config.antialias;
 
         // Rendered from bottom to top
         // Order is important for visibility, especially at lower resolutions
-        self.player.draw(&mut self.renderer);
+        self.player
+            .draw(&mut self.renderer, self.config.player_size);
         for x in self.bullets.iter_mut().flatten() {
             x.draw(&mut self.renderer);
         }
-        self.enemy.draw(&mut self.renderer);
+        for x in self.enemies.iter_mut() {
+            x.draw(&mut self.renderer, self.config.enemy_size);
+        }
     }
 }
 
+#[derive(Clone)]
 pub struct Player {
     pub pos: Vector2,
 }
@@ -88,7 +347,12 @@ impl Default for Player {
 }
 
 impl Player {
-    pub fn tick(&mut self, input: bulletrl_common::Input, bullets: &mut [Option<Bullet>]) -> bool {
+    pub fn tick(
+        &mut self,
+        input: bulletrl_common::Input,
+        bullets: &mut [Option<Bullet>],
+        size: i32,
+    ) -> bool {
         use bulletrl_common::Input;
 
         // TODO: this kinda sucks
@@ -122,28 +386,22 @@ impl Player {
         self.pos.x = self.pos.x.clamp(0.0, FIELD_WIDTH as f32);
         self.pos.y = self.pos.y.clamp(0.0, FIELD_HEIGHT as f32);
 
-        for x in bullets.iter().flatten() {
-            if check_rect_overlap(
-                self.pos,
-                Vector2::new(PLAYER_SIZE as f32, PLAYER_SIZE as f32),
-                x.pos,
-                x.size,
-            ) {
-                return true;
-            }
-        }
-        false
+        let hitbox = Shape::Aabb {
+            center: self.pos,
+            size: Vector2::new(size as f32, size as f32),
+        };
+        bullets
+            .iter()
+            .flatten()
+            .any(|x| hitbox.intersects(&x.hitbox()))
     }
 
-    pub fn draw(&self, renderer: &mut bulletrl_common::Renderer) {
-        // The hitbox is very small, so the player will be rendered larger to actually be visible
-        renderer.draw_rect(
-            0x00FF0000,
-            self.pos.x as i32,
-            self.pos.y as i32,
-            PLAYER_SIZE * 5,
-            PLAYER_SIZE * 5,
-        );
+    pub fn draw(&self, renderer: &mut bulletrl_common::Renderer, size: i32) {
+        let size = Vector2::new(size as f32, size as f32);
+        renderer.draw_object(Plane::Player, self.pos, size, 0.0, Body::Rect);
+        renderer
+            .objects
+            .push(Object::new(Plane::Player, self.pos, size));
     }
 }
 
@@ -154,26 +412,6 @@ pub enum EnemyMovement {
     EaseOutExpo { wait: u64, anim_len: u64 },
 }
 
-impl Distribution<EnemyMovement> for Standard {
-    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> EnemyMovement {
-        match rng.gen_range(0..3) {
-            0 => EnemyMovement::Static {
-                pos: Vector2::new(rng.gen_range(ENEMY_X_RANGE), rng.gen_range(ENEMY_Y_RANGE)),
-            },
-            1 => EnemyMovement::Sine {
-                speed: rng.gen_range(0.02f32..0.1f32),
-                range: rng.gen_range(90.0..=125.0),
-                height: rng.gen_range(ENEMY_Y_RANGE),
-            },
-            2 => EnemyMovement::EaseOutExpo {
-                wait: rng.gen_range(30..=60),
-                anim_len: rng.gen_range(30..=60),
-            },
-            _ => unreachable!(),
-        }
-    }
-}
-
 #[derive(Clone, Copy, Debug)]
 pub enum EnemyPattern {
     Spiral {
@@ -191,64 +429,260 @@ pub enum EnemyPattern {
         divisor: u64,
         amount: u64,
     },
+    // Index into the PatternLibrary the game was created with
+    BulletML {
+        pattern: usize,
+    },
+}
+
+impl EnemyMovement {
+    // Same as in the config
+    pub fn name(&self) -> &'static str {
+        match self {
+            EnemyMovement::Static { .. } => "static",
+            EnemyMovement::Sine { .. } => "sine",
+            EnemyMovement::EaseOutExpo { .. } => "ease_out_expo",
+        }
+    }
+
+    pub fn sample<R: Rng + ?Sized>(rng: &mut R, config: &Config) -> Self {
+        let movement = &config.movement;
+        match config::pick(rng, &movement.weights()) {
+            0 => EnemyMovement::Static {
+                pos: Vector2::new(
+                    config.enemy_x_range.sample_inclusive(rng),
+                    config.enemy_y_range.sample_inclusive(rng),
+                ),
+            },
+            1 => EnemyMovement::Sine {
+                speed: movement.sine.speed.sample(rng),
+                range: movement.sine.range.sample_inclusive(rng),
+                height: config.enemy_y_range.sample_inclusive(rng),
+            },
+            2 => EnemyMovement::EaseOutExpo {
+                wait: movement.ease_out_expo.wait.sample_inclusive(rng),
+                anim_len: movement.ease_out_expo.anim_len.sample_inclusive(rng),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn write<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        match *self {
+            EnemyMovement::Static { pos } => {
+                w.write_u8(0)?;
+                pos.write(w)
+            }
+            EnemyMovement::Sine {
+                speed,
+                range,
+                height,
+            } => {
+                w.write_u8(1)?;
+                w.write_f32::<LittleEndian>(speed)?;
+                w.write_f32::<LittleEndian>(range)?;
+                w.write_f32::<LittleEndian>(height)
+            }
+            EnemyMovement::EaseOutExpo { wait, anim_len } => {
+                w.write_u8(2)?;
+                w.write_u64::<LittleEndian>(wait)?;
+                w.write_u64::<LittleEndian>(anim_len)
+            }
+        }
+    }
+
+    fn read<R: Read>(r: &mut R) -> Result<Self, Error> {
+        match r.read_u8()? {
+            0 => Ok(EnemyMovement::Static {
+                pos: Vector2::read(r)?,
+            }),
+            1 => Ok(EnemyMovement::Sine {
+                speed: r.read_f32::<LittleEndian>()?,
+                range: r.read_f32::<LittleEndian>()?,
+                height: r.read_f32::<LittleEndian>()?,
+            }),
+            2 => Ok(EnemyMovement::EaseOutExpo {
+                wait: r.read_u64::<LittleEndian>()?,
+                anim_len: r.read_u64::<LittleEndian>()?,
+            }),
+            x => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown enemy movement {}", x),
+            )),
+        }
+    }
 }
 
-impl Distribution<EnemyPattern> for Standard {
-    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> EnemyPattern {
-        match rng.gen_range(0..3) {
+impl EnemyPattern {
+    // Same as in the config
+    pub fn name(&self) -> &'static str {
+        match self {
+            EnemyPattern::Spiral { .. } => "spiral",
+            EnemyPattern::Direct { .. } => "direct",
+            EnemyPattern::Burst { .. } => "burst",
+            EnemyPattern::BulletML { .. } => "bulletml",
+        }
+    }
+
+    pub fn sample<R: Rng + ?Sized>(
+        rng: &mut R,
+        config: &Config,
+        patterns: &PatternLibrary,
+    ) -> Self {
+        let pattern = &config.pattern;
+
+        // Loaded patterns get mixed in with the built-in ones
+        // Nothing extra is drawn from the RNG without any, so seeds play out the same as before
+        if !patterns.patterns.is_empty() && rng.gen_bool(pattern.bulletml.chance) {
+            return EnemyPattern::BulletML {
+                pattern: rng.gen_range(0..patterns.patterns.len()),
+            };
+        }
+
+        match config::pick(rng, &pattern.weights()) {
             0 => EnemyPattern::Spiral {
-                bullet_speed: rng.gen_range(2.0f32..5.0f32),
-                rot_speed: rng.gen_range(0.1f32..1.0f32),
+                bullet_speed: pattern.spiral.bullet_speed.sample(rng),
+                rot_speed: pattern.spiral.rot_speed.sample(rng),
             },
             1 => EnemyPattern::Direct {
-                bullet_speed: rng.gen_range(4.0f32..6.0f32),
-                spread: rng.gen_range(0.0f32..2.0f32),
-                divisor: rng.gen_range(4..=8),
+                bullet_speed: pattern.direct.bullet_speed.sample(rng),
+                spread: pattern.direct.spread.sample(rng),
+                divisor: pattern.direct.divisor.sample_inclusive(rng),
             },
             2 => EnemyPattern::Burst {
                 // This isn't actually very well designed
                 // There are multiple scenarios where you can just sit and do nothing
                 // Oh well
-                bullet_speed: rng.gen_range(3.0f32..4.0f32),
-                spread: rng.gen_range(std::f32::consts::FRAC_PI_4..std::f32::consts::FRAC_PI_2),
-                divisor: rng.gen_range(6..=10),
-                amount: rng.gen_range(4..=8),
+                bullet_speed: pattern.burst.bullet_speed.sample(rng),
+                spread: pattern.burst.spread.sample(rng),
+                divisor: pattern.burst.divisor.sample_inclusive(rng),
+                amount: pattern.burst.amount.sample_inclusive(rng),
             },
             _ => unreachable!(),
         }
     }
+
+    fn write<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        match *self {
+            EnemyPattern::Spiral {
+                bullet_speed,
+                rot_speed,
+            } => {
+                w.write_u8(0)?;
+                w.write_f32::<LittleEndian>(bullet_speed)?;
+                w.write_f32::<LittleEndian>(rot_speed)
+            }
+            EnemyPattern::Direct {
+                bullet_speed,
+                spread,
+                divisor,
+            } => {
+                w.write_u8(1)?;
+                w.write_f32::<LittleEndian>(bullet_speed)?;
+                w.write_f32::<LittleEndian>(spread)?;
+                w.write_u64::<LittleEndian>(divisor)
+            }
+            EnemyPattern::Burst {
+                bullet_speed,
+                spread,
+                divisor,
+                amount,
+            } => {
+                w.write_u8(2)?;
+                w.write_f32::<LittleEndian>(bullet_speed)?;
+                w.write_f32::<LittleEndian>(spread)?;
+                w.write_u64::<LittleEndian>(divisor)?;
+                w.write_u64::<LittleEndian>(amount)
+            }
+            EnemyPattern::BulletML { pattern } => {
+                w.write_u8(3)?;
+                w.write_u16::<LittleEndian>(pattern as u16)
+            }
+        }
+    }
+
+    fn read<R: Read>(r: &mut R) -> Result<Self, Error> {
+        match r.read_u8()? {
+            0 => Ok(EnemyPattern::Spiral {
+                bullet_speed: r.read_f32::<LittleEndian>()?,
+                rot_speed: r.read_f32::<LittleEndian>()?,
+            }),
+            1 => Ok(EnemyPattern::Direct {
+                bullet_speed: r.read_f32::<LittleEndian>()?,
+                spread: r.read_f32::<LittleEndian>()?,
+                divisor: r.read_u64::<LittleEndian>()?,
+            }),
+            2 => Ok(EnemyPattern::Burst {
+                bullet_speed: r.read_f32::<LittleEndian>()?,
+                spread: r.read_f32::<LittleEndian>()?,
+                divisor: r.read_u64::<LittleEndian>()?,
+                amount: r.read_u64::<LittleEndian>()?,
+            }),
+            3 => Ok(EnemyPattern::BulletML {
+                pattern: r.read_u16::<LittleEndian>()? as usize,
+            }),
+            x => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown enemy pattern {}", x),
+            )),
+        }
+    }
 }
 
+#[derive(Clone)]
 pub struct Enemy {
     pub pos: Vector2,
     pub target_pos: Vector2, // for EnemyMovement::EaseOutExpo
     pub last_pos: Vector2,   // for EnemyMovement::EaseOutExpo
     pub movement: EnemyMovement,
     pub pattern: EnemyPattern,
+    pub runner: Option<Actor>, // for EnemyPattern::BulletML
     pub frame: u64,
-    rng: ThreadRng,
+    pub lifetime: u64,
 }
 
-impl Default for Enemy {
-    fn default() -> Self {
-        let mut rng = rand::thread_rng();
+impl Enemy {
+    pub fn new<R: Rng + ?Sized>(rng: &mut R, config: &Config, patterns: &PatternLibrary) -> Self {
+        let target_pos = Vector2::new(
+            config.enemy_x_range.sample_inclusive(rng),
+            config.enemy_y_range.sample_inclusive(rng),
+        );
+        let last_pos = Vector2::new(
+            config.enemy_x_range.sample_inclusive(rng),
+            config.enemy_y_range.sample_inclusive(rng),
+        );
+        let movement = EnemyMovement::sample(rng, config);
+        let pattern = EnemyPattern::sample(rng, config, patterns);
+        let runner = match pattern {
+            EnemyPattern::BulletML { pattern } => {
+                Some(Actor::new(pattern, &patterns.patterns[pattern]))
+            }
+            _ => None,
+        };
+
         Enemy {
             pos: Vector2::new(0.0, 0.0),
-            target_pos: Vector2::new(rng.gen_range(ENEMY_X_RANGE), rng.gen_range(ENEMY_Y_RANGE)),
-            last_pos: Vector2::new(rng.gen_range(ENEMY_X_RANGE), rng.gen_range(ENEMY_Y_RANGE)),
-            movement: rng.gen(),
-            pattern: rng.gen(),
+            target_pos,
+            last_pos,
+            movement,
+            pattern,
+            runner,
             frame: 0,
-            rng,
+            lifetime: config.enemy_lifetime.sample_inclusive(rng),
         }
     }
-}
 
-impl Enemy {
-    pub fn tick(&mut self, player: &Player, bullets: &mut [Option<Bullet>]) {
+    pub fn tick<R: Rng + ?Sized>(
+        &mut self,
+        player: &Player,
+        bullets: &mut [Option<Bullet>],
+        config: &Config,
+        patterns: &PatternLibrary,
+        rng: &mut R,
+    ) {
         self.frame += 1;
 
-        //self.pos.x = (FIELD_WIDTH as f32 / 2.0) + (self.frame as f64 / 25.0).sin() as f32 * 125.0;
+        //self.pos.x = (FIELD_WIDTH as f32 / 2.0) + (self.frame as f64 / 25.0).sin() * 125.0;
         self.pos = match self.movement {
             EnemyMovement::Static { pos } => pos,
             EnemyMovement::Sine {
@@ -256,7 +690,7 @@ impl Enemy {
                 range,
                 height,
             } => Vector2::new(
-                (FIELD_WIDTH as f32 / 2.0) + (self.frame as f32 * speed).sin() as f32 * range,
+                (FIELD_WIDTH as f32 / 2.0) + (self.frame as f32 * speed).sin() * range,
                 height,
             ),
             EnemyMovement::EaseOutExpo { wait, anim_len } => {
@@ -265,8 +699,8 @@ impl Enemy {
                 if progress == 0 {
                     self.last_pos = self.target_pos;
                     self.target_pos = Vector2::new(
-                        self.rng.gen_range(ENEMY_X_RANGE),
-                        self.rng.gen_range(ENEMY_Y_RANGE),
+                        config.enemy_x_range.sample_inclusive(rng),
+                        config.enemy_y_range.sample_inclusive(rng),
                     );
                 }
 
@@ -285,10 +719,8 @@ impl Enemy {
                 let angle = self.frame as f32 * rot_speed;
                 self.shoot(
                     bullets,
-                    Vector2::new(
-                        bullet_speed * angle.cos() as f32,
-                        -bullet_speed * angle.sin() as f32,
-                    ),
+                    config,
+                    Vector2::new(bullet_speed * angle.cos(), -bullet_speed * angle.sin()),
                 );
             }
             EnemyPattern::Direct {
@@ -296,11 +728,11 @@ impl Enemy {
                 spread,
                 divisor,
             } => {
-                if self.frame % divisor == 0 {
+                if self.frame.is_multiple_of(divisor) {
                     let offset = if spread == 0.0 {
                         0.0
                     } else {
-                        self.rng.gen_range(0.0f32..spread) - spread / 2.0
+                        rng.gen_range(0.0f32..spread) - spread / 2.0
                     };
                     // https://stackoverflow.com/a/27481611
                     let delta_y = self.pos.y - player.pos.y;
@@ -308,10 +740,8 @@ impl Enemy {
                     let angle = delta_y.atan2(delta_x) + offset;
                     self.shoot(
                         bullets,
-                        Vector2::new(
-                            bullet_speed * angle.cos() as f32,
-                            -bullet_speed * angle.sin() as f32,
-                        ),
+                        config,
+                        Vector2::new(bullet_speed * angle.cos(), -bullet_speed * angle.sin()),
                     );
                 }
             }
@@ -321,7 +751,7 @@ impl Enemy {
                 divisor,
                 amount,
             } => {
-                if self.frame % divisor == 0 {
+                if self.frame.is_multiple_of(divisor) {
                     // https://stackoverflow.com/a/27481611
                     let delta_y = self.pos.y - player.pos.y;
                     let delta_x = player.pos.x - self.pos.x;
@@ -334,51 +764,113 @@ impl Enemy {
                         let angle = base_angle + offset;
                         self.shoot(
                             bullets,
-                            Vector2::new(
-                                bullet_speed * angle.cos() as f32,
-                                -bullet_speed * angle.sin() as f32,
-                            ),
+                            config,
+                            Vector2::new(bullet_speed * angle.cos(), -bullet_speed * angle.sin()),
                         );
                     }
                 }
             }
+            EnemyPattern::BulletML { pattern } => {
+                if let Some(runner) = &mut self.runner {
+                    let mut spawns = Vec::new();
+                    runner.tick(
+                        &patterns.patterns[pattern],
+                        self.pos,
+                        player.pos,
+                        rng,
+                        &mut spawns,
+                    );
+                    for spawn in spawns {
+                        spawn_bullet(bullets, config, spawn);
+                    }
+                }
+            }
         };
     }
 
-    fn shoot(&self, bullets: &mut [Option<Bullet>], velocity: Vector2) {
-        // TODO: properly handle no free bullet slots!
-        for x in bullets.iter_mut() {
-            if x.is_none() {
-                *x = Some(Bullet {
-                    pos: self.pos,
-                    size: Vector2::new(5.0, 5.0),
-                    velocity,
-                });
-                return;
+    fn write<W: Write>(&self, w: &mut W) -> Result<(), Error> {
+        self.pos.write(w)?;
+        self.target_pos.write(w)?;
+        self.last_pos.write(w)?;
+        self.movement.write(w)?;
+        self.pattern.write(w)?;
+        match &self.runner {
+            Some(runner) => {
+                w.write_u8(1)?;
+                runner.write(w)?;
             }
+            None => w.write_u8(0)?,
         }
+        w.write_u64::<LittleEndian>(self.frame)?;
+        w.write_u64::<LittleEndian>(self.lifetime)
     }
 
-    pub fn draw(&mut self, renderer: &mut bulletrl_common::Renderer) {
-        renderer.draw_rect(
-            0x0000FF00,
-            self.pos.x as i32,
-            self.pos.y as i32,
-            ENEMY_SIZE,
-            ENEMY_SIZE,
+    fn read<R: Read>(r: &mut R) -> Result<Self, Error> {
+        Ok(Enemy {
+            pos: Vector2::read(r)?,
+            target_pos: Vector2::read(r)?,
+            last_pos: Vector2::read(r)?,
+            movement: EnemyMovement::read(r)?,
+            pattern: EnemyPattern::read(r)?,
+            runner: match r.read_u8()? {
+                0 => None,
+                _ => Some(Actor::read(r)?),
+            },
+            frame: r.read_u64::<LittleEndian>()?,
+            lifetime: r.read_u64::<LittleEndian>()?,
+        })
+    }
+
+    fn shoot(&self, bullets: &mut [Option<Bullet>], config: &Config, velocity: Vector2) {
+        spawn_bullet(
+            bullets,
+            config,
+            Spawn {
+                pos: self.pos,
+                velocity,
+                runner: None,
+            },
         );
     }
+
+    pub fn draw(&mut self, renderer: &mut bulletrl_common::Renderer, size: i32) {
+        let size = Vector2::new(size as f32, size as f32);
+        renderer.draw_object(Plane::Enemies, self.pos, size, 0.0, Body::Rect);
+        renderer
+            .objects
+            .push(Object::new(Plane::Enemies, self.pos, size));
+    }
 }
 
-#[derive(Clone, Copy)]
+fn spawn_bullet(bullets: &mut [Option<Bullet>], config: &Config, spawn: Spawn) {
+    // TODO: properly handle no free bullet slots!
+    if let Some(x) = bullets.iter_mut().find(|x| x.is_none()) {
+        *x = Some(Bullet {
+            pos: spawn.pos,
+            size: Vector2::new(config.bullet_size, config.bullet_size),
+            velocity: spawn.velocity,
+            runner: spawn.runner.map(Box::new),
+        });
+    }
+}
+
+#[derive(Clone)]
 pub struct Bullet {
     pub pos: Vector2,
     pub size: Vector2,
     pub velocity: Vector2,
+    // Only for BulletML bullets with actions of their own
+    pub runner: Option<Box<Actor>>,
 }
 
 impl Bullet {
-    pub fn tick(&mut self) -> bool {
+    pub fn tick<R: Rng + ?Sized>(
+        &mut self,
+        patterns: &PatternLibrary,
+        target: Vector2,
+        rng: &mut R,
+        spawns: &mut Vec<Spawn>,
+    ) -> bool {
         if self.pos.x < 0.0 - self.size.x
             || self.pos.x > FIELD_WIDTH as f32 + self.size.x
             || self.pos.y < 0.0 - self.size.y
@@ -387,19 +879,202 @@ impl Bullet {
             return true;
         }
 
+        if let Some(runner) = &mut self.runner {
+            runner.tick(
+                &patterns.patterns[runner.pattern],
+                self.pos,
+                target,
+                rng,
+                spawns,
+            );
+            if runner.vanished {
+                return true;
+            }
+            self.velocity = runner.velocity();
+        }
+
         self.pos += self.velocity;
 
         false
     }
 
+    pub fn hitbox(&self) -> Shape {
+        Shape::Aabb {
+            center: self.pos,
+            size: self.size,
+        }
+    }
+
     pub fn draw(&mut self, renderer: &mut bulletrl_common::Renderer) {
-        // Bullets are also drawn very large compared to their hitboxes, so they'll be scaled here too
-        renderer.draw_rect(
-            0x000000FF,
-            self.pos.x as i32,
-            self.pos.y as i32,
-            self.size.x as i32 * 3,
-            self.size.y as i32 * 3,
+        renderer.draw_object(Plane::EnemyBullets, self.pos, self.size, 0.0, Body::Ellipse);
+        renderer.objects.push(
+            Object::new(Plane::EnemyBullets, self.pos, self.size).with_velocity(self.velocity),
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bulletrl_common::Input;
+
+    fn game(seed: u64) -> Game {
+        Game::new(Arc::new(Config::default()), seed)
+    }
+
+    // Some wandering around that's the same every time
+    fn input(frame: usize) -> Input {
+        const INPUTS: [Input; 6] = [
+            Input::LEFT,
+            Input::UP,
+            Input::RIGHT.union(Input::FOCUS),
+            Input::DOWN,
+            Input::RIGHT,
+            Input::empty(),
+        ];
+        INPUTS[(frame / 7) % INPUTS.len()]
+    }
+
+    // The frame and every live bullet's slot and position after a tick
+    type Step = (Box<[u32]>, Vec<(usize, Vector2)>);
+
+    fn run(game: &mut Game, ticks: usize) -> Vec<Step> {
+        (0..ticks)
+            .map(|_| {
+                game.tick(input(game.frame as usize));
+                let bullets = game
+                    .bullets
+                    .iter()
+                    .enumerate()
+                    .filter_map(|(i, x)| x.as_ref().map(|x| (i, x.pos)))
+                    .collect();
+                (game.renderer.buffer.clone(), bullets)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn seeds() {
+        let (mut a, mut b) = (game(1), game(1));
+        assert_eq!(a.describe_enemies(), b.describe_enemies());
+        assert!(run(&mut a, 600) == run(&mut b, 600));
+        assert_eq!(a.describe_enemies(), b.describe_enemies());
+
+        let mut c = game(2);
+        assert_ne!(c.describe_enemies(), game(1).describe_enemies());
+        let mut a = game(1);
+        assert!(run(&mut a, 600) != run(&mut c, 600));
+    }
+
+    #[test]
+    fn save_and_load() {
+        let mut game = game(1);
+        run(&mut game, 200);
+        assert!(game.bullets.iter().any(|x| x.is_some()));
+        let state = game.save_state();
+
+        let before = run(&mut game, 200);
+        game.load_state(&state).unwrap();
+        assert_eq!(game.frame, 200);
+        assert_eq!(run(&mut game, 200), before);
+
+        // Loading into a different game works just as well
+        let mut other = self::game(2);
+        other.load_state(&state).unwrap();
+        assert_eq!(other.seed, 1);
+        assert_eq!(run(&mut other, 200), before);
+    }
+
+    #[test]
+    fn bad_states() {
+        let mut game = game(1);
+        run(&mut game, 100);
+        let state = game.save_state();
+        let buffer = game.renderer.buffer.clone();
+
+        let mut wrong_version = state.clone();
+        wrong_version[4..6].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+        let mut bad = vec![
+            Vec::new(),
+            state[..3].to_vec(),
+            state[..state.len() / 2].to_vec(),
+            state[..state.len() - 1].to_vec(),
+            wrong_version,
+        ];
+
+        // A bullet past the end of a smaller pool
+        let mut other = self::game(1);
+        other.bullets[600] = Some(Bullet {
+            pos: Vector2::new(10.0, 10.0),
+            size: Vector2::new(5.0, 5.0),
+            velocity: Vector2::new(0.0, 1.0),
+            runner: None,
+        });
+        bad.push(other.save_state());
+        let mut game = Game {
+            bullets: vec![None; 600].into_boxed_slice(),
+            config: Arc::new(Config {
+                bullet_limit: 600,
+                ..Default::default()
+            }),
+            ..game
+        };
+        let state = game.save_state();
+
+        for data in bad {
+            assert!(game.load_state(&data).is_err());
+            // Still exactly where it was
+            assert_eq!(game.save_state(), state);
+            assert_eq!(game.renderer.buffer, buffer);
+        }
+    }
+
+    // Frames on which a new enemy showed up, checking that there's never more than enemy_limit or a stale one
+    fn spawn_frames(config: Config, ticks: u64) -> Vec<u64> {
+        let mut game = Game::new(Arc::new(config), 3);
+        let mut spawns = Vec::new();
+        for _ in 0..ticks {
+            assert!(!game.tick(Input::empty()));
+            assert!((1..=game.config.enemy_limit).contains(&game.enemies.len()));
+            assert!(game.enemies.iter().all(|x| x.frame <= x.lifetime));
+            spawns.extend(
+                game.enemies
+                    .iter()
+                    .filter(|x| x.frame == 1)
+                    .map(|_| game.frame),
+            );
+        }
+        spawns
+    }
+
+    #[test]
+    fn enemies_come_and_go() {
+        // Nothing can kill the player
+        let config = Config {
+            bullet_limit: 0,
+            enemy_limit: 3,
+            enemy_spawn_interval: config::Range(20, 40),
+            enemy_lifetime: config::Range(100, 100),
+            ..Default::default()
+        };
+        let spawns = spawn_frames(config.clone(), 1000);
+        // The first enemy is there from the start, and the rest come at least an interval apart
+        assert_eq!(spawns[0], 1);
+        assert!((21..=41).contains(&spawns[1]));
+        assert!(spawns.windows(2).all(|x| x[1] - x[0] >= 20));
+        // With a full field, the next one has to wait for the oldest to leave
+        assert!(spawns.windows(4).all(|x| x[3] - x[0] >= 100));
+        assert!(spawns.windows(4).any(|x| x[3] - x[0] == 100));
+        assert!(spawns.len() > 20);
+
+        // An empty field gets a new enemy right away, no matter the interval
+        let config = Config {
+            enemy_limit: 1,
+            enemy_spawn_interval: config::Range(1000, 1000),
+            enemy_lifetime: config::Range(50, 50),
+            ..config
+        };
+        let spawns = spawn_frames(config, 300);
+        assert_eq!(spawns, [1, 51, 101, 151, 201, 251]);
+    }
+}
@@ -1,21 +1,146 @@
-use backend::{Backend, MinifbBackend, TcpBackend};
+use std::path::PathBuf;
+
+use backend::{Backend, BenchBackend, HostBackend, MinifbBackend, ReplayBackend, TcpBackend};
+use clap::{Parser, Subcommand};
+use config::Config;
 
 mod backend;
+mod bulletml;
+mod config;
 mod game;
+mod replay;
 mod util;
 
+#[derive(Parser)]
+#[command(about = "Barebones bulletrl environment")]
+struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    // Replays bring their own config and ignore this
+    #[arg(long, global = true, help = "TOML config file, see bullettest.toml")]
+    config: Option<PathBuf>,
+
+    #[arg(long, global = true, help = "Overrides frameskip from the config")]
+    frameskip: Option<u32>,
+
+    #[arg(
+        long,
+        global = true,
+        help = "off, error, warn, info, debug or trace [default: RUST_LOG or info]"
+    )]
+    log_level: Option<log::LevelFilter>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    #[command(about = "Play with the keyboard (default)")]
+    Play {
+        #[arg(long, help = "Seed of the first episode [default: random]")]
+        seed: Option<u64>,
+    },
+    #[command(about = "Connect to a trainer")]
+    Serve {
+        #[command(flatten)]
+        connection: Connection,
+    },
+    #[command(
+        about = "Connect to a trainer with many games on one connection, which restart on their own"
+    )]
+    Host {
+        #[command(flatten)]
+        connection: Connection,
+        #[arg(long, default_value_t = 16, value_parser = clap::value_parser!(u32).range(1..))]
+        envs: u32,
+        #[arg(long, help = "Worker threads [default: one per core]")]
+        threads: Option<usize>,
+    },
+    #[command(about = "Play back a replay")]
+    Replay {
+        file: PathBuf,
+        #[arg(long, help = "Only check that it ends the same way it was recorded")]
+        headless: bool,
+    },
+    #[command(about = "Measure simulation speed with random inputs")]
+    Bench {
+        #[arg(long, default_value_t = 60 * 60 * 10)]
+        frames: u64,
+        #[arg(long, default_value_t = 0)]
+        seed: u64,
+    },
+    #[command(
+        about = "Connect to a trainer for a fixed set of episodes, then report how they went"
+    )]
+    Eval {
+        #[command(flatten)]
+        connection: Connection,
+        #[arg(long, default_value_t = 10)]
+        episodes: u32,
+        #[arg(
+            long,
+            default_value_t = 0,
+            help = "Seed of the first episode, the rest count up from it"
+        )]
+        seed: u64,
+    },
+}
+
+#[derive(clap::Args)]
+#[group(required = true, multiple = false)]
+struct Connection {
+    #[arg(long, help = "Port of a trainer on 127.0.0.1")]
+    port: Option<u16>,
+    #[arg(
+        long,
+        help = "tcp://host:port, unix:///path/to/socket or shm:///path/to/file?control=<uri>"
+    )]
+    connect: Option<String>,
+}
+
+impl Connection {
+    fn uri(&self) -> String {
+        match (&self.connect, self.port) {
+            (Some(uri), _) => uri.clone(),
+            (None, Some(port)) => port.to_string(),
+            (None, None) => unreachable!("clap requires one of them"),
+        }
+    }
+}
+
 fn main() {
-    env_logger::init_from_env(
+    let args = Args::parse();
+
+    let mut logger = env_logger::Builder::from_env(
         env_logger::Env::default().filter_or(env_logger::DEFAULT_FILTER_ENV, "info"),
     );
+    if let Some(level) = args.log_level {
+        logger.filter_level(level);
+    }
+    logger.init();
+
+    let mut config = match &args.config {
+        Some(path) => Config::load(path).expect("failed to load config"),
+        None => Config::default(),
+    };
+    if let Some(frameskip) = args.frameskip {
+        config.frameskip = frameskip.max(1);
+    }
 
-    let args: Vec<String> = std::env::args().collect();
-    let mut backend: Box<dyn Backend> = if args.len() > 1 {
-        Box::new(TcpBackend::new(
-            args[1].parse().expect("failed to parse port"),
-        ))
-    } else {
-        Box::new(MinifbBackend::default())
+    let mut backend: Box<dyn Backend> = match args.command.unwrap_or(Command::Play { seed: None }) {
+        Command::Play { seed } => Box::new(MinifbBackend::new(config, seed)),
+        Command::Serve { connection } => Box::new(TcpBackend::new(&connection.uri(), config)),
+        Command::Host {
+            connection,
+            envs,
+            threads,
+        } => Box::new(HostBackend::new(&connection.uri(), config, envs, threads)),
+        Command::Replay { file, headless } => Box::new(ReplayBackend::new(file, headless)),
+        Command::Bench { frames, seed } => Box::new(BenchBackend::new(config, frames, seed)),
+        Command::Eval {
+            connection,
+            episodes,
+            seed,
+        } => Box::new(TcpBackend::eval(&connection.uri(), config, episodes, seed)),
     };
     backend.main_loop();
 }
@@ -0,0 +1,196 @@
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter, Error, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use bulletrl_common::Input;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use log::{error, info};
+
+use crate::config::Config;
+
+// Replays are just the episode seed, config and every input, since Game::tick is deterministic
+const REPLAY_MAGIC: &[u8; 4] = b"BTRP";
+const REPLAY_VERSION: u16 = 2;
+
+// Caps on the lengths in a replay, so that a corrupt file is an error instead of a huge allocation
+const MAX_CONFIG_LEN: usize = 1 << 20;
+// A day at 60 fps
+const MAX_INPUTS: usize = 60 * 60 * 60 * 24;
+
+pub struct Replay {
+    pub seed: u64,
+    // As TOML, v1 replays predate configs and always used the defaults
+    pub config: String,
+    pub inputs: Vec<Input>,
+    // Whether the player died on the last input, so playback can check that it ends the same way
+    pub died: bool,
+}
+
+impl Replay {
+    pub fn new(seed: u64, config: &Config) -> Self {
+        Replay {
+            seed,
+            config: config.to_toml(),
+            inputs: Vec::new(),
+            died: false,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let mut r = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 4];
+        r.read_exact(&mut magic)?;
+        let version = r.read_u16::<LittleEndian>()?;
+        if &magic != REPLAY_MAGIC || version == 0 || version > REPLAY_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("not a v1-v{} bullettest replay", REPLAY_VERSION),
+            ));
+        }
+
+        let seed = r.read_u64::<LittleEndian>()?;
+        let mut config = String::new();
+        if version >= 2 {
+            let mut buf = vec![0u8; read_len(&mut r, MAX_CONFIG_LEN, "config")?];
+            r.read_exact(&mut buf)?;
+            config = String::from_utf8(buf)
+                .map_err(|_| Error::new(ErrorKind::InvalidData, "replay config isn't UTF-8"))?;
+        }
+        let died = r.read_u8()? != 0;
+        let mut inputs = vec![0u8; read_len(&mut r, MAX_INPUTS, "input count")?];
+        r.read_exact(&mut inputs)?;
+
+        Ok(Replay {
+            seed,
+            config,
+            inputs: inputs.into_iter().map(Input::from_bits_truncate).collect(),
+            died,
+        })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        // Anything load would reject isn't worth writing
+        check_len(self.config.len(), MAX_CONFIG_LEN, "config")?;
+        check_len(self.inputs.len(), MAX_INPUTS, "input count")?;
+
+        let mut w = BufWriter::new(File::create(path)?);
+
+        w.write_all(REPLAY_MAGIC)?;
+        w.write_u16::<LittleEndian>(REPLAY_VERSION)?;
+        w.write_u64::<LittleEndian>(self.seed)?;
+        w.write_u32::<LittleEndian>(self.config.len() as u32)?;
+        w.write_all(self.config.as_bytes())?;
+        w.write_u8(self.died as u8)?;
+        w.write_u32::<LittleEndian>(self.inputs.len() as u32)?;
+        for input in &self.inputs {
+            w.write_u8(input.bits())?;
+        }
+
+        w.flush()
+    }
+}
+
+fn read_len<R: Read>(r: &mut R, max: usize, what: &str) -> Result<usize, Error> {
+    let len = r.read_u32::<LittleEndian>()? as usize;
+    check_len(len, max, what)?;
+    Ok(len)
+}
+
+fn check_len(len: usize, max: usize, what: &str) -> Result<(), Error> {
+    if len > max {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("replay {} is {}, but the limit is {}", what, len, max),
+        ));
+    }
+    Ok(())
+}
+
+// Saves a replay of every episode to a directory
+// Opt-in through the BULLETTEST_REPLAYS environment variable, the same way as bulletrl_common::Recorder
+pub struct ReplayRecorder {
+    dir: PathBuf,
+    replay: Option<Replay>,
+}
+
+impl ReplayRecorder {
+    pub fn from_env() -> Option<Self> {
+        let dir = PathBuf::from(std::env::var_os("BULLETTEST_REPLAYS")?);
+        if let Err(e) = std::fs::create_dir_all(&dir) {
+            error!("Failed to create replay directory {}: {}", dir.display(), e);
+            return None;
+        }
+
+        info!("Saving replays to {}", dir.display());
+        Some(ReplayRecorder { dir, replay: None })
+    }
+
+    // Saves the current episode if it wasn't finished yet
+    pub fn begin(&mut self, seed: u64, config: &Config) {
+        self.finish(false);
+        self.replay = Some(Replay::new(seed, config));
+    }
+
+    pub fn push(&mut self, input: Input) {
+        if let Some(replay) = &mut self.replay {
+            replay.inputs.push(input);
+        }
+    }
+
+    // The episode can't be reproduced from its seed anymore, e.g. after loading a state
+    pub fn discard(&mut self) {
+        self.replay = None;
+    }
+
+    pub fn finish(&mut self, died: bool) {
+        if let Some(mut replay) = self.replay.take() {
+            if replay.inputs.is_empty() {
+                return;
+            }
+            replay.died = died;
+
+            let path = self.dir.join(format!(
+                "{:016x}_{:.1}s.btr",
+                replay.seed,
+                replay.inputs.len() as f64 / 60.0
+            ));
+            match replay.save(&path) {
+                Ok(()) => info!("Saved replay to {}", path.display()),
+                Err(e) => error!("Failed to save replay to {}: {}", path.display(), e),
+            }
+        }
+    }
+}
+
+impl Drop for ReplayRecorder {
+    fn drop(&mut self) {
+        self.finish(false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oversized_replays() {
+        let path =
+            std::env::temp_dir().join(format!("bullettest-{}-oversized.btr", std::process::id()));
+        let mut replay = Replay::new(0, &Config::default());
+        replay.inputs = vec![Input::empty(); MAX_INPUTS + 1];
+        assert!(replay.save(&path).is_err());
+
+        // A config claiming to be 4 GB long
+        let mut data = REPLAY_MAGIC.to_vec();
+        data.extend_from_slice(&REPLAY_VERSION.to_le_bytes());
+        data.extend_from_slice(&0u64.to_le_bytes());
+        data.extend_from_slice(&u32::MAX.to_le_bytes());
+        std::fs::write(&path, data).unwrap();
+        let result = Replay::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(result.err().unwrap().kind(), ErrorKind::InvalidData);
+    }
+}
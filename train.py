@@ -1,9 +1,9 @@
 import stable_baselines3
-from bulletrl_env import BulletTestEnv, Touhou6Env
+from bulletrl_env import BulletTestEnv, BulletTestVecEnv, Touhou6Env
 from stable_baselines3.common.vec_env import DummyVecEnv
 from stable_baselines3 import PPO
 from stable_baselines3.ppo import CnnPolicy
-from stable_baselines3.common.vec_env import VecFrameStack
+from stable_baselines3.common.vec_env import VecFrameStack, VecMonitor
 from stable_baselines3.common.monitor import Monitor
 
 # Here for testing, taken from openai-gym source
@@ -59,6 +59,7 @@ def get_wrapped_env(env):
 
 if __name__ == "__main__":
     #env = VecFrameStack(DummyVecEnv([get_wrapped_env(BulletTestEnv) for _ in range(32)]), 2)
+    #env = VecFrameStack(VecMonitor(BulletTestVecEnv(32)), 2)
     env = VecFrameStack(DummyVecEnv([get_wrapped_env(Touhou6Env) for _ in range(8)]), 2)
     # env = Touhou6Env()
     model = PPO(